@@ -0,0 +1,85 @@
+//! Times the interpreter loop on call-heavy, loop-heavy, local-heavy and
+//! global-heavy scripts, compiled as is and with optimizations, whose
+//! superinstructions should speed up the loops:
+//!
+//!     cargo bench --bench dispatch
+
+use rlox::{CompileOptions, Vm};
+use std::io;
+use std::time::{Duration, Instant};
+
+const FIB: &str = "
+fun fib(n) {
+  if (n < 2) return n;
+  return fib(n - 1) + fib(n - 2);
+}
+print fib(27);
+";
+
+const LOOP: &str = "
+var sum = 0;
+for (var i = 0; i < 3000000; i = i + 1) {
+  if (i % 3 == 0) sum = sum + i;
+}
+print sum;
+";
+
+const LOCALS: &str = "
+fun count(limit) {
+  var sum = 0;
+  for (var i = 0; i < limit; i = i + 1) {
+    if (i % 3 != 0) sum = sum + 1;
+  }
+  return sum;
+}
+print count(3000000);
+";
+
+const GLOBALS: &str = "
+var i = 0;
+var total = 0;
+while (i < 3000000) {
+  total = total + i;
+  i = i + 1;
+}
+print total;
+";
+
+const RUNS: u32 = 5;
+
+fn time_run(source: &str, optimize: bool) -> Duration {
+    let options = CompileOptions {
+        optimize,
+        ..Default::default()
+    };
+    (0..RUNS)
+        .map(|_| {
+            let mut vm = Vm::with_output(io::sink());
+            let chunk = rlox::compile_with(source, &mut vm, options).expect("Failed to compile");
+            let start = Instant::now();
+            vm.interpret(chunk).expect("Failed to run");
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    let scripts = [
+        ("fib", FIB),
+        ("loop", LOOP),
+        ("locals", LOCALS),
+        ("globals", GLOBALS),
+    ];
+    for (name, source) in scripts {
+        let plain = time_run(source, false);
+        let optimized = time_run(source, true);
+        println!(
+            "{:>7}: {:>8.2?}  -O: {:>8.2?} ({:+.1}%)",
+            name,
+            plain,
+            optimized,
+            100.0 * (optimized.as_secs_f64() / plain.as_secs_f64() - 1.0)
+        );
+    }
+}
@@ -0,0 +1,48 @@
+//! Times scanning multi-megabyte sources, to check that the scanner stays
+//! linear in the size of its input:
+//!
+//!     cargo bench --bench scanner
+//!
+//! Each doubling of the source should roughly double the time taken.
+
+use std::time::{Duration, Instant};
+
+const SNIPPET: &str = r#"
+class Point {
+  init(x, y) { this.x = x; this.y = y; }
+  norm() { return (this.x ** 2 + this.y ** 2) ** 0.5; }
+}
+// Strings may hold any UTF-8: é, ß, →
+var p = Point(3.25, 4);
+for (var i = 0; i < 10; i = i + 1) { print "point → " + str(p.norm() * i); }
+"#;
+
+const RUNS: u32 = 5;
+
+fn source_of_size(bytes: usize) -> String {
+    SNIPPET.repeat(bytes / SNIPPET.len() + 1)
+}
+
+fn time_scan(source: &str) -> Duration {
+    (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            // Scans every token, which compiling would also do
+            assert!(!rlox::is_incomplete(source));
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    for megabytes in [1, 2, 4, 8] {
+        let source = source_of_size(megabytes * 1024 * 1024);
+        let elapsed = time_scan(&source);
+        let throughput = source.len() as f64 / elapsed.as_secs_f64() / (1024.0 * 1024.0);
+        println!(
+            "{:>2} MB: {:>8.2?} ({:.0} MB/s)",
+            megabytes, elapsed, throughput
+        );
+    }
+}
@@ -0,0 +1,87 @@
+//! Compares the enum value representation with NaN boxing on the work the
+//! VM does most with values: reading numbers and copying values around.
+//!
+//!     cargo bench --features nan-boxing --bench values
+
+use rlox::{NanBoxed, Value};
+use std::hint::black_box;
+use std::mem;
+use std::time::{Duration, Instant};
+
+const COUNT: usize = 1_000_000;
+const RUNS: u32 = 10;
+
+fn best_of(mut run: impl FnMut()) -> Duration {
+    (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            run();
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn report(name: &str, enum_time: Duration, boxed_time: Duration) {
+    println!(
+        "{:<16} enum {:>9.2?}, boxed {:>9.2?}",
+        name, enum_time, boxed_time
+    );
+}
+
+fn sample(i: usize) -> Value {
+    match i % 4 {
+        0 => Value::nil(),
+        1 => Value::bool(i.is_multiple_of(3)),
+        2 => Value::string("shared".to_string()),
+        _ => Value::number(i as f64),
+    }
+}
+
+fn main() {
+    println!(
+        "size: enum {} bytes, boxed {} bytes",
+        mem::size_of::<Value>(),
+        mem::size_of::<NanBoxed>()
+    );
+
+    let numbers: Vec<Value> = (0..COUNT).map(|i| Value::number(i as f64)).collect();
+    let boxed_numbers: Vec<NanBoxed> = numbers.iter().cloned().map(NanBoxed::from).collect();
+    let sum_enum = best_of(|| {
+        let sum: f64 = numbers
+            .iter()
+            .map(|value| match value {
+                Value::Number(n) => *n,
+                _ => 0.0,
+            })
+            .sum();
+        black_box(sum);
+    });
+    let sum_boxed = best_of(|| {
+        let sum: f64 = boxed_numbers
+            .iter()
+            .map(|value| value.as_number().unwrap_or(0.0))
+            .sum();
+        black_box(sum);
+    });
+    report("sum numbers", sum_enum, sum_boxed);
+
+    let mixed: Vec<Value> = (0..COUNT).map(sample).collect();
+    let boxed_mixed: Vec<NanBoxed> = mixed.iter().cloned().map(NanBoxed::from).collect();
+    let copy_enum = best_of(|| drop(black_box(mixed.clone())));
+    let copy_boxed = best_of(|| drop(black_box(boxed_mixed.clone())));
+    report("copy values", copy_enum, copy_boxed);
+
+    let truthy_enum = best_of(|| {
+        black_box(mixed.iter().filter(|value| !value.is_falsey()).count());
+    });
+    let truthy_boxed = best_of(|| {
+        black_box(
+            boxed_mixed
+                .iter()
+                .filter(|value| !value.is_falsey())
+                .count(),
+        );
+    });
+    report("test truthiness", truthy_enum, truthy_boxed);
+}
@@ -0,0 +1,19 @@
+//! Compiles arbitrary source and runs whatever compiles.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rlox::{Feature, VmOptions};
+
+fuzz_target!(|source: &str| {
+    // The budget keeps infinite loops from hanging the fuzzer
+    let mut vm = VmOptions::new()
+        .instruction_budget(10_000)
+        .disable(Feature::Io)
+        .build();
+    vm.set_output(std::io::sink());
+
+    if let Ok(chunk) = rlox::compile(source, &mut vm) {
+        let _ = vm.interpret(chunk);
+    }
+});
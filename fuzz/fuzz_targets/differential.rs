@@ -0,0 +1,36 @@
+//! Runs a program generated from the fuzzer's input through the rlox binary
+//! and the reference interpreter named by `LOX_REFERENCE`, and fails when
+//! their output, first diagnostic line or exit code differ. The binary is
+//! taken from `RLOX`, or `target/release/rlox` by default.
+
+#![no_main]
+
+#[path = "../../tests/differential/harness.rs"]
+mod harness;
+
+use harness::{Generator, Rng};
+use libfuzzer_sys::fuzz_target;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{self, Command};
+
+fuzz_target!(|seed: u64| {
+    let reference = env::var("LOX_REFERENCE")
+        .expect("Set LOX_REFERENCE to the reference interpreter to compare against.");
+    let rlox =
+        env::var_os("RLOX").map_or_else(|| PathBuf::from("target/release/rlox"), PathBuf::from);
+    let compat = env::var_os("LOX_REFERENCE_COMPAT").is_some();
+
+    let source = Generator::new(Rng::new(seed)).program();
+    let path = env::temp_dir().join(format!("rlox-fuzz-{}.lox", process::id()));
+    fs::write(&path, &source).unwrap();
+
+    let actual = harness::run(&mut harness::rlox_command(&rlox, compat), &path);
+    let expected = harness::run(&mut Command::new(&reference), &path);
+    assert_eq!(
+        actual, expected,
+        "rlox and the reference differ on:\n{}",
+        source
+    );
+});
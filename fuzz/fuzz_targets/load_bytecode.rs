@@ -0,0 +1,18 @@
+//! Loads arbitrary bytes as a bytecode file and runs whatever loads.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rlox::{Feature, VmOptions};
+
+fuzz_target!(|bytes: &[u8]| {
+    let mut vm = VmOptions::new()
+        .instruction_budget(10_000)
+        .disable(Feature::Io)
+        .build();
+    vm.set_output(std::io::sink());
+
+    if let Ok(chunk) = vm.load_chunk(bytes) {
+        let _ = vm.interpret(chunk);
+    }
+});
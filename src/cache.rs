@@ -0,0 +1,63 @@
+//! On-disk cache of compiled scripts. Entries are keyed by a hash of the
+//! source, the compiler settings, the interpreter version and the bytecode
+//! format, so edited scripts and upgraded interpreters simply miss the cache.
+
+use rlox::{Chunk, FORMAT_VERSION, Vm};
+use std::path::PathBuf;
+use std::{env, fs, io};
+
+fn cache_dir() -> Option<PathBuf> {
+    if let Some(dir) = env::var_os("RLOX_CACHE_DIR") {
+        return Some(PathBuf::from(dir));
+    }
+    if let Some(dir) = env::var_os("XDG_CACHE_HOME") {
+        return Some(PathBuf::from(dir).join("rlox"));
+    }
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache").join("rlox"))
+}
+
+fn entry_path(source: &str, optimize: bool) -> Option<PathBuf> {
+    // 64-bit FNV-1a, which unlike std's hashers is stable across builds
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let version = env!("CARGO_PKG_VERSION").as_bytes();
+    let format = FORMAT_VERSION.to_le_bytes();
+    let settings = [optimize as u8];
+    let key = version.iter().chain(&format).chain(&settings);
+    for byte in key.chain(source.as_bytes()) {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    Some(cache_dir()?.join(format!("{:016x}.rloxc", hash)))
+}
+
+pub fn load(source: &str, optimize: bool, vm: &mut Vm) -> Option<Chunk> {
+    let bytes = fs::read(entry_path(source, optimize)?).ok()?;
+    vm.load_chunk(&bytes).ok()
+}
+
+pub fn store(source: &str, optimize: bool, chunk: &Chunk) {
+    // Caching is best-effort: if the entry can't be written we just compile
+    // the script again next time.
+    let Some(path) = entry_path(source, optimize) else {
+        return;
+    };
+    let Some(dir) = path.parent() else {
+        return;
+    };
+
+    let temp = path.with_extension(format!("tmp{}", std::process::id()));
+    let written = fs::create_dir_all(dir)
+        .and_then(|_| fs::write(&temp, chunk.serialize()))
+        .and_then(|_| fs::rename(&temp, &path));
+    if written.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+}
+
+pub fn clear() -> io::Result<()> {
+    match cache_dir() {
+        Some(dir) if dir.exists() => fs::remove_dir_all(dir),
+        _ => Ok(()),
+    }
+}
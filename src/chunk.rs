@@ -1,9 +1,26 @@
+use crate::compiler::MAX_NESTING;
 pub(crate) use crate::value::Value;
+use crate::value::{Function, LoxString, Slot};
+use crate::vm::VM;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+const MAGIC: &[u8; 4] = b"RLXC";
+pub const FORMAT_VERSION: u16 = 16;
+
+const TAG_NIL: u8 = 0;
+const TAG_BOOL: u8 = 1;
+const TAG_NUMBER: u8 = 2;
+const TAG_STRING: u8 = 3;
+const TAG_FUNCTION: u8 = 4;
 
 #[repr(u8)]
 #[derive(Debug, Clone, Copy)]
+#[allow(clippy::enum_variant_names)]
 pub enum OpCode {
     OpConstant,
+    OpConstantLong,
     OpNil,
     OpTrue,
     OpFalse,
@@ -15,6 +32,8 @@ pub enum OpCode {
     OpSubtract,
     OpMultiply,
     OpDivide,
+    OpModulo,
+    OpPower,
     OpNot,
     OpNegate,
     OpPrint,
@@ -23,76 +42,624 @@ pub enum OpCode {
     OpSetGlobal,
     OpGetLocal,
     OpSetLocal,
+    OpGetLocalLong,
+    OpSetLocalLong,
+    OpGetProperty,
+    OpSetProperty,
     OpJumpIfFalse,
     OpJump,
     OpLoop,
+    OpCall,
+    OpInvoke,
     OpReturn,
+    OpClass,
+    OpMethod,
+    OpBuildList,
+    OpIndexGet,
+    OpIndexSet,
+    OpBuildMap,
+    OpTry,
+    OpEndTry,
+    OpThrow,
+    OpAssert,
+    OpImport,
+    OpJumpLong,
+    OpJumpIfFalseLong,
+    OpLoopLong,
+    OpTryLong,
+    // Superinstructions the optimizer fuses from common sequences
+    OpAddConstant,
+    OpLessLocals,
+    OpNotEqual,
+}
+
+impl OpCode {
+    /// Every opcode, indexed by its byte.
+    const ALL: [OpCode; 51] = [
+        OpCode::OpConstant,
+        OpCode::OpConstantLong,
+        OpCode::OpNil,
+        OpCode::OpTrue,
+        OpCode::OpFalse,
+        OpCode::OpPop,
+        OpCode::OpEqual,
+        OpCode::OpGreater,
+        OpCode::OpLess,
+        OpCode::OpAdd,
+        OpCode::OpSubtract,
+        OpCode::OpMultiply,
+        OpCode::OpDivide,
+        OpCode::OpModulo,
+        OpCode::OpPower,
+        OpCode::OpNot,
+        OpCode::OpNegate,
+        OpCode::OpPrint,
+        OpCode::OpDefineGlobal,
+        OpCode::OpGetGlobal,
+        OpCode::OpSetGlobal,
+        OpCode::OpGetLocal,
+        OpCode::OpSetLocal,
+        OpCode::OpGetLocalLong,
+        OpCode::OpSetLocalLong,
+        OpCode::OpGetProperty,
+        OpCode::OpSetProperty,
+        OpCode::OpJumpIfFalse,
+        OpCode::OpJump,
+        OpCode::OpLoop,
+        OpCode::OpCall,
+        OpCode::OpInvoke,
+        OpCode::OpReturn,
+        OpCode::OpClass,
+        OpCode::OpMethod,
+        OpCode::OpBuildList,
+        OpCode::OpIndexGet,
+        OpCode::OpIndexSet,
+        OpCode::OpBuildMap,
+        OpCode::OpTry,
+        OpCode::OpEndTry,
+        OpCode::OpThrow,
+        OpCode::OpAssert,
+        OpCode::OpImport,
+        OpCode::OpJumpLong,
+        OpCode::OpJumpIfFalseLong,
+        OpCode::OpLoopLong,
+        OpCode::OpTryLong,
+        OpCode::OpAddConstant,
+        OpCode::OpLessLocals,
+        OpCode::OpNotEqual,
+    ];
+
+    /// The form of a forward jump instruction with a four-byte offset.
+    pub(crate) fn long_jump(self) -> OpCode {
+        match self {
+            OpCode::OpJump => OpCode::OpJumpLong,
+            OpCode::OpJumpIfFalse => OpCode::OpJumpIfFalseLong,
+            OpCode::OpTry => OpCode::OpTryLong,
+            other => other,
+        }
+    }
+
+    pub(crate) fn is_long_jump(self) -> bool {
+        matches!(
+            self,
+            OpCode::OpJumpLong | OpCode::OpJumpIfFalseLong | OpCode::OpLoopLong | OpCode::OpTryLong
+        )
+    }
+}
+
+impl TryFrom<u8> for OpCode {
+    type Error = u8;
+
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        OpCode::ALL.get(byte as usize).copied().ok_or(byte)
+    }
+}
+
+/// Source position of the token an instruction was compiled from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
 }
 
 #[derive(Debug)]
 pub struct Chunk {
     pub code: Vec<u8>,
-    pub lines: Vec<usize>,
-    constants: Vec<Value>,
+    pub spans: Vec<Span>,
+    constants: Vec<Slot>,
+    // Index of each number and string constant, so literals and identifiers
+    // that appear repeatedly share one entry
+    constant_indices: HashMap<ConstantKey, usize>,
+    globals: Vec<Global>,
+    // Index in `globals` of each slot, so a variable used repeatedly gets
+    // one entry
+    global_indices: HashMap<usize, usize>,
+}
+
+/// A global variable used by a chunk, whose instructions refer to it by its
+/// index in the chunk. The slot locates its value in the VM the chunk was
+/// compiled or loaded into.
+#[derive(Debug)]
+pub struct Global {
+    pub name: Rc<LoxString>,
+    pub slot: usize,
+}
+
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    // Compared by bits so 0 and -0 stay distinct
+    Number(u64),
+    String(Rc<LoxString>),
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Chunk {
     pub fn new() -> Self {
         Chunk {
             code: Vec::new(),
-            lines: Vec::new(),
+            spans: Vec::new(),
             constants: Vec::new(),
+            constant_indices: HashMap::new(),
+            globals: Vec::new(),
+            global_indices: HashMap::new(),
         }
     }
 
-    pub fn write(&mut self, opcode: OpCode, line: usize) {
+    pub fn write(&mut self, opcode: OpCode, span: Span) {
         self.code.push(opcode as u8);
-        self.lines.push(line);
+        self.spans.push(span);
     }
 
-    pub fn write_byte(&mut self, byte: u8, line: usize) {
+    pub fn write_byte(&mut self, byte: u8, span: Span) {
         self.code.push(byte);
-        self.lines.push(line);
+        self.spans.push(span);
     }
 
+    /// Adds `value` to the constant pool, unless an identical number or
+    /// string is already there, and returns its index.
     pub fn add_constant(&mut self, value: Value) -> usize {
-        self.constants.push(value);
+        let key = match &value {
+            Value::Number(number) => Some(ConstantKey::Number(number.to_bits())),
+            Value::String(string) => Some(ConstantKey::String(Rc::clone(string))),
+            _ => None,
+        };
+        if let Some(key) = key {
+            if let Some(&index) = self.constant_indices.get(&key) {
+                return index;
+            }
+            self.constant_indices.insert(key, self.constants.len());
+        }
+
+        self.constants.push(value.into_slot());
         self.constants.len() - 1
     }
 
-    pub fn get_constant(&self, index: usize) -> Value {
-        self.constants[index].clone()
+    pub fn get_constant(&self, index: usize) -> &Slot {
+        &self.constants[index]
     }
 
-    pub fn emit_jump(&mut self, instruction: OpCode, line: usize) -> usize {
-        self.write(instruction, line);
+    pub fn constants(&self) -> &[Slot] {
+        &self.constants
+    }
+
+    /// Adds the global variable `name`, which lives in `slot`, unless the
+    /// chunk already uses it, and returns its index.
+    pub fn add_global(&mut self, name: Rc<LoxString>, slot: usize) -> usize {
+        if let Some(&index) = self.global_indices.get(&slot) {
+            return index;
+        }
+        self.global_indices.insert(slot, self.globals.len());
+        self.globals.push(Global { name, slot });
+        self.globals.len() - 1
+    }
+
+    pub fn get_global(&self, index: usize) -> &Global {
+        &self.globals[index]
+    }
+
+    pub fn globals(&self) -> &[Global] {
+        &self.globals
+    }
+
+    /// Emits a forward jump whose offset is filled in by `patch_jump`, and
+    /// returns the offset of its operand.
+    pub fn emit_jump(&mut self, instruction: OpCode, span: Span) -> usize {
+        self.write(instruction, span);
         // Emit placeholder bytes for the jump offset
-        self.write_byte(0xff, line);
-        self.write_byte(0xff, line);
-        self.code.len() - 2
+        let width = if instruction.is_long_jump() { 4 } else { 2 };
+        for _ in 0..width {
+            self.write_byte(0xff, span);
+        }
+        self.code.len() - width
+    }
+
+    /// Points the jump whose operand is at `offset` to the end of the code.
+    /// Fails with a compile error message if it's too far for the operand.
+    pub fn patch_jump(&mut self, offset: usize) -> Result<(), &'static str> {
+        let long = OpCode::try_from(self.code[offset - 1]).is_ok_and(OpCode::is_long_jump);
+        let (width, max) = if long {
+            (4, u32::MAX)
+        } else {
+            (2, u16::MAX as u32)
+        };
+        // Adjust for the bytecode for the jump offset itself
+        let jump = self.code.len() - offset - width;
+
+        if jump > max as usize {
+            return Err("Too much code to jump over.");
+        }
+
+        let bytes = (jump as u32).to_be_bytes();
+        self.code[offset..offset + width].copy_from_slice(&bytes[4 - width..]);
+        Ok(())
+    }
+
+    /// Emits a jump back to `loop_start`, with a four-byte offset if two
+    /// aren't enough.
+    pub fn emit_loop(&mut self, loop_start: usize, span: Span) -> Result<(), &'static str> {
+        // +3 for OpLoop and its operand
+        let offset = self.code.len() - loop_start + 3;
+        if offset <= u16::MAX as usize {
+            self.write(OpCode::OpLoop, span);
+            for byte in (offset as u16).to_be_bytes() {
+                self.write_byte(byte, span);
+            }
+            return Ok(());
+        }
+
+        let offset = offset + 2;
+        if offset > u32::MAX as usize {
+            return Err("Loop body too large.");
+        }
+        self.write(OpCode::OpLoopLong, span);
+        for byte in (offset as u32).to_be_bytes() {
+            self.write_byte(byte, span);
+        }
+        Ok(())
+    }
+
+    /// Encodes the chunk as a self-describing byte buffer: a magic header and
+    /// format version, followed by the code, source spans, constant pool and
+    /// the names of the globals it uses.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        self.write_to(&mut bytes);
+        bytes
+    }
+
+    fn write_to(&self, bytes: &mut Vec<u8>) {
+        write_u32(bytes, self.code.len());
+        bytes.extend_from_slice(&self.code);
+        for span in self.spans.iter() {
+            write_u32(bytes, span.line);
+            write_u32(bytes, span.column);
+        }
+
+        write_u32(bytes, self.constants.len());
+        for constant in self.constants.iter() {
+            match &constant.to_value() {
+                Value::Nil => bytes.push(TAG_NIL),
+                Value::Bool(b) => {
+                    bytes.push(TAG_BOOL);
+                    bytes.push(*b as u8);
+                }
+                Value::Number(n) => {
+                    bytes.push(TAG_NUMBER);
+                    bytes.extend_from_slice(&n.to_le_bytes());
+                }
+                Value::String(s) => {
+                    bytes.push(TAG_STRING);
+                    write_string(bytes, s);
+                }
+                Value::Function(function) => {
+                    bytes.push(TAG_FUNCTION);
+                    write_u32(bytes, function.arity);
+                    write_string(bytes, function.name.as_deref().unwrap_or(""));
+                    function.chunk.write_to(bytes);
+                }
+                Value::Class(_)
+                | Value::Instance(_)
+                | Value::BoundMethod(_)
+                | Value::Native(_)
+                | Value::List(_)
+                | Value::Map(_) => {
+                    unreachable!("only functions are compiled into constants")
+                }
+            }
+        }
+
+        // Slots are only meaningful in this VM, so the loading VM assigns
+        // its own
+        write_u32(bytes, self.globals.len());
+        for global in self.globals.iter() {
+            write_string(bytes, &global.name);
+        }
+    }
+
+    /// Decodes a chunk written by `serialize` for running on `vm`, interning
+    /// its strings and resolving its globals there. The code is verified, so
+    /// a corrupt file fails to load rather than misbehaving when it runs.
+    pub fn deserialize(bytes: &[u8], vm: &mut VM) -> Result<Chunk, LoadError> {
+        let mut reader = Reader { bytes, offset: 0 };
+
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(LoadError::BadMagic);
+        }
+        let version = u16::from_le_bytes(reader.take(2)?.try_into().unwrap());
+        if version != FORMAT_VERSION {
+            return Err(LoadError::VersionMismatch(version));
+        }
+
+        let chunk = Chunk::read_from(&mut reader, vm, 0)?;
+        if reader.offset != bytes.len() {
+            return Err(LoadError::Malformed);
+        }
+        crate::verify::verify(&chunk, 0, vm.global_count()).map_err(LoadError::Invalid)?;
+        Ok(chunk)
     }
 
-    pub fn patch_jump(&mut self, offset: usize) {
-        // -2 to adjust for the bytecode for the jump offset itself
-        let jump = self.code.len() - offset - 2;
+    fn read_from(reader: &mut Reader, vm: &mut VM, depth: usize) -> Result<Chunk, LoadError> {
+        // Functions can't be nested deeper than the compiler allows
+        if depth > MAX_NESTING {
+            return Err(LoadError::Malformed);
+        }
+
+        let mut chunk = Chunk::new();
+        let code_len = reader.read_u32()?;
+        chunk.code = reader.take(code_len)?.to_vec();
+        for _ in 0..code_len {
+            let line = reader.read_u32()?;
+            let column = reader.read_u32()?;
+            chunk.spans.push(Span { line, column });
+        }
 
-        if jump > u16::MAX as usize {
-            panic!("Too much code to jump over.");
+        let constant_count = reader.read_u32()?;
+        for _ in 0..constant_count {
+            let constant = match reader.take(1)?[0] {
+                TAG_NIL => Value::nil(),
+                TAG_BOOL => Value::bool(reader.take(1)?[0] != 0),
+                TAG_NUMBER => {
+                    Value::number(f64::from_le_bytes(reader.take(8)?.try_into().unwrap()))
+                }
+                TAG_STRING => Value::String(vm.intern_string(reader.read_string()?)),
+                TAG_FUNCTION => {
+                    let arity = reader.read_u32()?;
+                    let name = reader.read_string()?;
+                    let mut function = Function::new(Some(name).filter(|n| !n.is_empty()));
+                    function.arity = arity;
+                    function.chunk = Chunk::read_from(reader, vm, depth + 1)?;
+                    Value::function(Rc::new(function))
+                }
+                _ => return Err(LoadError::Malformed),
+            };
+            chunk.constants.push(constant.into_slot());
         }
 
-        self.code[offset] = ((jump >> 8) & 0xff) as u8;
-        self.code[offset + 1] = (jump & 0xff) as u8;
+        let global_count = reader.read_u32()?;
+        for _ in 0..global_count {
+            let name = vm.intern_string(reader.read_string()?);
+            let slot = vm.global_slot(Rc::clone(&name));
+            chunk.add_global(name, slot);
+        }
+
+        Ok(chunk)
     }
+}
 
-    pub fn emit_loop(&mut self, loop_start: usize, line: usize) {
-        self.write(OpCode::OpLoop, line);
+#[derive(Debug, PartialEq)]
+pub enum LoadError {
+    BadMagic,
+    VersionMismatch(u16),
+    Malformed,
+    /// The file decodes, but its code would misbehave if it ran
+    Invalid(String),
+}
 
-        let offset = self.code.len() - loop_start + 2;
-        if offset > u16::MAX as usize {
-            panic!("Loop body too large.");
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::BadMagic => write!(f, "Not an rlox bytecode file."),
+            LoadError::VersionMismatch(version) => write!(
+                f,
+                "Bytecode format version {} is not supported (expected {}).",
+                version, FORMAT_VERSION
+            ),
+            LoadError::Malformed => write!(f, "Malformed bytecode file."),
+            LoadError::Invalid(reason) => write!(f, "Invalid bytecode: {}.", reason),
         }
+    }
+}
 
-        self.write_byte(((offset >> 8) & 0xff) as u8, line);
-        self.write_byte((offset & 0xff) as u8, line);
+fn write_u32(bytes: &mut Vec<u8>, value: usize) {
+    bytes.extend_from_slice(&(value as u32).to_le_bytes());
+}
+
+fn write_string(bytes: &mut Vec<u8>, string: &str) {
+    write_u32(bytes, string.len());
+    bytes.extend_from_slice(string.as_bytes());
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], LoadError> {
+        let end = self.offset.checked_add(len).ok_or(LoadError::Malformed)?;
+        let slice = self
+            .bytes
+            .get(self.offset..end)
+            .ok_or(LoadError::Malformed)?;
+        self.offset = end;
+        Ok(slice)
+    }
+
+    fn read_u32(&mut self) -> Result<usize, LoadError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+    }
+
+    fn read_string(&mut self) -> Result<String, LoadError> {
+        let len = self.read_u32()?;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| LoadError::Malformed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::CompileOptions;
+    use crate::program_gen;
+    use crate::verify::verify;
+    use crate::vm::VmOptions;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_opcodes_round_trip() {
+        for byte in 0..=u8::MAX {
+            match OpCode::try_from(byte) {
+                Ok(opcode) => assert_eq!(opcode as u8, byte),
+                Err(_) => assert!(byte as usize >= OpCode::ALL.len()),
+            }
+        }
+        assert_eq!(OpCode::ALL.len(), OpCode::OpNotEqual as usize + 1);
+    }
+
+    #[test]
+    fn test_constants_are_deduplicated() {
+        let mut chunk = Chunk::new();
+
+        let one = chunk.add_constant(Value::number(1.0));
+        let name = chunk.add_constant(Value::string("name".to_string()));
+        assert_eq!(chunk.add_constant(Value::number(1.0)), one);
+        assert_eq!(chunk.add_constant(Value::string("name".to_string())), name);
+
+        let zero = chunk.add_constant(Value::number(0.0));
+        assert_ne!(chunk.add_constant(Value::number(-0.0)), zero);
+        assert_eq!(chunk.constants().len(), 4);
+    }
+
+    #[test]
+    fn test_long_constants() {
+        let mut vm = VM::new();
+        let mut source = String::new();
+        for i in 0..300 {
+            source.push_str(&format!("print {};\n", i));
+        }
+        let chunk = crate::compiler::compile(&source, &mut vm).unwrap();
+
+        assert!(chunk.code.contains(&(OpCode::OpConstantLong as u8)));
+        assert!(verify(&chunk, 0, vm.global_count()).is_ok());
+    }
+
+    #[test]
+    fn test_serialize_round_trip() {
+        let mut vm = VM::new();
+        let chunk =
+            crate::compiler::compile("var a = \"hi\"; print a == nil or 1.5;", &mut vm).unwrap();
+
+        let bytes = chunk.serialize();
+        let loaded = Chunk::deserialize(&bytes, &mut vm).unwrap();
+
+        assert_eq!(loaded.code, chunk.code);
+        assert_eq!(loaded.spans, chunk.spans);
+        assert_eq!(loaded.constants, chunk.constants);
+        assert_eq!(loaded.globals[0].slot, chunk.globals[0].slot);
+
+        // Another VM resolves the globals to its own slots
+        let mut other = VM::new();
+        let loaded = Chunk::deserialize(&bytes, &mut other).unwrap();
+        assert_eq!(loaded.globals[0].name.as_str(), "a");
+        assert_eq!(
+            loaded.globals[0].slot,
+            other.global_slot(Rc::clone(&loaded.globals[0].name))
+        );
+    }
+
+    #[test]
+    fn test_deserialize_rejects_bad_input() {
+        let bytes = Chunk::new().serialize();
+        let mut vm = VM::new();
+
+        assert_eq!(
+            Chunk::deserialize(b"RL", &mut vm).unwrap_err(),
+            LoadError::Malformed
+        );
+        assert_eq!(
+            Chunk::deserialize(b"#!/usr/bin/env rlox", &mut vm).unwrap_err(),
+            LoadError::BadMagic
+        );
+
+        let mut stale = bytes.clone();
+        stale[4] = FORMAT_VERSION as u8 + 1;
+        assert_eq!(
+            Chunk::deserialize(&stale, &mut vm).unwrap_err(),
+            LoadError::VersionMismatch(FORMAT_VERSION + 1)
+        );
+
+        assert_eq!(
+            Chunk::deserialize(&bytes[..bytes.len() - 1], &mut vm).unwrap_err(),
+            LoadError::Malformed
+        );
+    }
+
+    #[test]
+    fn test_deserialize_verifies_code() {
+        let mut vm = VM::new();
+        let chunk = crate::compiler::compile("print 1;", &mut vm).unwrap();
+        let mut bytes = chunk.serialize();
+
+        // The code follows the header and its length. OpIndexSet needs three
+        // values where OpPrint needs one.
+        let print = 2;
+        assert!(matches!(
+            OpCode::try_from(chunk.code[print]),
+            Ok(OpCode::OpPrint)
+        ));
+        bytes[MAGIC.len() + 2 + 4 + print] = OpCode::OpIndexSet as u8;
+        assert_eq!(
+            Chunk::deserialize(&bytes, &mut vm).unwrap_err(),
+            LoadError::Invalid("stack underflow at 2".to_string())
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn test_compiler_output_verifies(source in program_gen::program(), optimize: bool) {
+            let mut vm = VM::new();
+            let options = CompileOptions { optimize, ..Default::default() };
+            let chunk = crate::compiler::compile_with(&source, &mut vm, options);
+
+            prop_assert!(chunk.is_ok(), "failed to compile:\n{}", source);
+            let result = verify(&chunk.unwrap(), 0, vm.global_count());
+            prop_assert!(result.is_ok(), "{:?} in:\n{}", result, source);
+        }
+
+        #[test]
+        fn test_corrupt_bytecode_never_panics(
+            source in program_gen::program(),
+            corruptions in prop::collection::vec((any::<prop::sample::Index>(), any::<u8>()), 1..4),
+        ) {
+            let mut vm = VmOptions::new().instruction_budget(10_000).build();
+            vm.set_output(std::io::sink());
+            vm.set_sandboxed(true);
+            let mut bytes = crate::compiler::compile(&source, &mut vm).unwrap().serialize();
+            for (index, byte) in corruptions {
+                let len = bytes.len();
+                bytes[index.index(len)] = byte;
+            }
+
+            if let Ok(chunk) = Chunk::deserialize(&bytes, &mut vm) {
+                let _ = vm.interpret(chunk);
+            }
+        }
     }
 }
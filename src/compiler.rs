@@ -1,6 +1,10 @@
-use crate::chunk::{Chunk, OpCode, Value};
+use crate::chunk::{Chunk, OpCode, Span, Value};
+use crate::error::{CompileError, Location};
 use crate::scanner::{Scanner, Token, TokenType, init_scanner};
-use crate::vm::VM;
+use crate::value::Function;
+use crate::vm::{Feature, VM, feature_disabled};
+use std::mem;
+use std::rc::Rc;
 
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
 enum Precedence {
@@ -11,8 +15,9 @@ enum Precedence {
     Equality,   // == !=
     Comparison, // < > <= >=
     Term,       // + -
-    Factor,     // * /
+    Factor,     // * / %
     Unary,      // ! -
+    Power,      // **
     Call,       // . ()
     Primary,
 }
@@ -28,7 +33,8 @@ impl Precedence {
             Precedence::Comparison => Precedence::Term,
             Precedence::Term => Precedence::Factor,
             Precedence::Factor => Precedence::Unary,
-            Precedence::Unary => Precedence::Call,
+            Precedence::Unary => Precedence::Power,
+            Precedence::Power => Precedence::Call,
             Precedence::Call => Precedence::Primary,
             Precedence::Primary => Precedence::Primary,
         }
@@ -39,8 +45,11 @@ struct Parser<'a> {
     scanner: Scanner<'a>,
     current: Token<'a>,
     previous: Token<'a>,
-    had_error: bool,
+    errors: Vec<CompileError>,
     panic_mode: bool,
+    // Set after an error the parser can't recover from, when the rest of
+    // the source is skipped
+    gave_up: bool,
 }
 
 impl<'a> Parser<'a> {
@@ -49,14 +58,19 @@ impl<'a> Parser<'a> {
             token_type: TokenType::Eof,
             lexeme: "",
             line: 0,
+            end_line: 0,
+            column: 0,
+            start: 0,
+            end: 0,
         };
 
         Parser {
             scanner,
             current: dummy_token,
             previous: dummy_token,
-            had_error: false,
+            errors: Vec::new(),
             panic_mode: false,
+            gave_up: false,
         }
     }
 
@@ -91,23 +105,34 @@ impl<'a> Parser<'a> {
     }
 
     fn error_at(&mut self, token: Token, message: &str) {
-        if self.panic_mode {
+        if self.panic_mode || self.gave_up {
             return;
         }
         self.panic_mode = true;
 
-        eprint!("[line {}] Error", token.line);
+        let location = match token.token_type {
+            TokenType::Eof => Location::End,
+            TokenType::Error => Location::Scanner,
+            _ => Location::Token(token.lexeme.to_string()),
+        };
 
-        if token.token_type == TokenType::Eof {
-            eprint!(" at end");
-        } else if token.token_type == TokenType::Error {
-            // Nothing
-        } else {
-            eprint!(" at '{}'", token.lexeme);
-        }
+        self.errors.push(CompileError {
+            line: token.line as usize,
+            end_line: token.end_line as usize,
+            column: token.column,
+            location,
+            message: message.to_string(),
+        });
+    }
 
-        eprintln!(": {}", message);
-        self.had_error = true;
+    /// Reports an error and skips the rest of the source, leaving the
+    /// constructs being parsed to end without reporting more errors.
+    fn give_up(&mut self, message: &str) {
+        self.error(message);
+        self.gave_up = true;
+        while self.current.token_type != TokenType::Eof {
+            self.advance();
+        }
     }
 
     fn check(&self, token_type: TokenType) -> bool {
@@ -123,20 +148,107 @@ impl<'a> Parser<'a> {
     }
 }
 
-const MAX_LOCALS: usize = 256;
+// Slots past the first 256 are addressed with the wide local instructions
+const MAX_LOCALS: usize = u16::MAX as usize + 1;
+
+// Blocks, statements and expressions nested in one another, each of which
+// the compiler recurses into
+pub(crate) const MAX_NESTING: usize = 256;
 
 struct Local<'a> {
     name: &'a str,
     depth: i32,
 }
 
-struct Compiler<'a> {
-    parser: Parser<'a>,
-    chunk: Chunk,
-    vm: &'a mut VM,
+/// The innermost loop being compiled, which `break` and `continue` jump out
+/// of or back to the start of.
+struct Loop {
+    start: usize,
+    scope_depth: i32,
+    // Number of try blocks enclosing the loop, as jumping out of the ones
+    // inside it must remove their handlers
+    try_depth: usize,
+    // Jumps to patch to the end of the loop once it's compiled
+    break_jumps: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FunctionType {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+/// Compilation state of a single function. Nested function declarations
+/// start a new state and keep the one they interrupted in `enclosing`.
+struct FunctionState<'a> {
+    enclosing: Option<Box<FunctionState<'a>>>,
+    function: Function,
+    function_type: FunctionType,
     locals: Vec<Local<'a>>,
     local_count: usize,
     scope_depth: i32,
+    loops: Vec<Loop>,
+    // Number of try blocks enclosing the code being compiled
+    try_depth: usize,
+    // Offsets of the instructions emitted that push a constant, which
+    // constant folding may combine
+    constant_starts: Vec<usize>,
+    // Offsets of the instructions emitted other than jumps, which the
+    // optimizer may fuse into superinstructions
+    instruction_starts: Vec<usize>,
+    // Offset of the latest jump target. Instructions before it can't be
+    // folded with the ones after, as some paths only run the latter.
+    jump_target: usize,
+}
+
+impl<'a> FunctionState<'a> {
+    fn new(function_type: FunctionType, name: Option<String>) -> Self {
+        let mut locals = Vec::new();
+        // Slot zero holds the function being called, or the receiver in
+        // methods where it's accessible as `this`
+        let receiver = match function_type {
+            FunctionType::Method | FunctionType::Initializer => "this",
+            FunctionType::Function | FunctionType::Script => "",
+        };
+        locals.push(Local {
+            name: receiver,
+            depth: 0,
+        });
+
+        FunctionState {
+            enclosing: None,
+            function: Function::new(name),
+            function_type,
+            locals,
+            local_count: 1,
+            scope_depth: 0,
+            loops: Vec::new(),
+            try_depth: 0,
+            constant_starts: Vec::new(),
+            instruction_starts: Vec::new(),
+            jump_target: 0,
+        }
+    }
+}
+
+struct Compiler<'a> {
+    source: &'a str,
+    parser: Parser<'a>,
+    vm: &'a mut VM,
+    current: FunctionState<'a>,
+    // Number of class declarations enclosing the code being compiled
+    class_depth: usize,
+    // Blocks, statements and expressions enclosing the code being compiled
+    nesting: usize,
+    // Whether forward jumps get four-byte offsets, which is only needed
+    // when a jump turned out to be too far for two
+    long_jumps: bool,
+    jump_too_far: bool,
+    options: CompileOptions,
+    // Path of the module being compiled, if it's imported
+    module: Option<Rc<str>>,
 }
 
 impl<'a> Compiler<'a> {
@@ -145,33 +257,49 @@ impl<'a> Compiler<'a> {
         let parser = Parser::new(scanner);
 
         Compiler {
+            source,
             parser,
-            chunk: Chunk::new(),
             vm,
-            locals: Vec::with_capacity(MAX_LOCALS),
-            local_count: 0,
-            scope_depth: 0,
+            current: FunctionState::new(FunctionType::Script, None),
+            class_depth: 0,
+            nesting: 0,
+            long_jumps: false,
+            jump_too_far: false,
+            options: CompileOptions::default(),
+            module: None,
         }
     }
 
-    fn compile(mut self) -> Option<Chunk> {
+    fn compile(mut self) -> Result<Chunk, Vec<CompileError>> {
         self.parser.advance();
 
         while !self.parser.check(TokenType::Eof) {
             self.declaration();
         }
 
-        self.end_compiler();
+        let function = self.end_compiler();
 
-        if self.parser.had_error {
-            None
-        } else {
-            Some(self.chunk)
+        if !self.parser.errors.is_empty() {
+            return Err(self.parser.errors);
+        }
+        if self.jump_too_far {
+            // Offsets are filled in after the code they jump over is
+            // compiled, too late to make room for longer ones, so start over
+            let mut compiler = Compiler::new(self.source, self.vm);
+            compiler.options = self.options;
+            compiler.module = self.module;
+            compiler.long_jumps = true;
+            return compiler.compile();
         }
+        Ok(function.chunk)
     }
 
     fn declaration(&mut self) {
-        if self.parser.match_token(TokenType::Var) {
+        if self.parser.match_token(TokenType::Class) {
+            self.class_declaration();
+        } else if self.parser.match_token(TokenType::Fun) {
+            self.fun_declaration();
+        } else if self.parser.match_token(TokenType::Var) {
             self.var_declaration();
         } else {
             self.statement();
@@ -182,6 +310,94 @@ impl<'a> Compiler<'a> {
         }
     }
 
+    fn class_declaration(&mut self) {
+        self.check_feature(Feature::Classes, "class");
+        self.parser.consume(TokenType::Identifier, "Expect class name.");
+        let class_name = self.parser.previous.lexeme;
+        let name_constant = self.identifier_constant(class_name);
+        self.declare_variable();
+        let global = if self.current.scope_depth > 0 {
+            0
+        } else {
+            self.global_index(class_name)
+        };
+
+        self.emit_bytes(OpCode::OpClass, name_constant);
+        self.define_variable(global);
+        self.class_depth += 1;
+
+        // Keep the class on the stack while its methods are attached
+        self.named_variable(class_name, false);
+        self.parser
+            .consume(TokenType::LeftBrace, "Expect '{' before class body.");
+        while !self.parser.check(TokenType::RightBrace) && !self.parser.check(TokenType::Eof) {
+            self.method();
+        }
+        self.parser
+            .consume(TokenType::RightBrace, "Expect '}' after class body.");
+        self.emit_byte(OpCode::OpPop);
+
+        self.class_depth -= 1;
+    }
+
+    fn method(&mut self) {
+        self.parser.consume(TokenType::Identifier, "Expect method name.");
+        let constant = self.identifier_constant(self.parser.previous.lexeme);
+
+        let function_type = if self.parser.previous.lexeme == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(function_type);
+        self.emit_bytes(OpCode::OpMethod, constant);
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        // A function may refer to itself, so it's usable before its body
+        self.mark_initialized();
+        self.function(FunctionType::Function);
+        self.define_variable(global);
+    }
+
+    fn function(&mut self, function_type: FunctionType) {
+        let name = self.parser.previous.lexeme.to_string();
+        let enclosing = mem::replace(
+            &mut self.current,
+            FunctionState::new(function_type, Some(name)),
+        );
+        self.current.enclosing = Some(Box::new(enclosing));
+        self.begin_scope();
+
+        self.parser
+            .consume(TokenType::LeftParen, "Expect '(' after function name.");
+        if !self.parser.check(TokenType::RightParen) {
+            loop {
+                self.current.function.arity += 1;
+                if self.current.function.arity > 255 {
+                    self.parser
+                        .error_at_current("Can't have more than 255 parameters.");
+                }
+                let constant = self.parse_variable("Expect parameter name.");
+                self.define_variable(constant);
+
+                if !self.parser.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.parser
+            .consume(TokenType::RightParen, "Expect ')' after parameters.");
+        self.parser
+            .consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        self.block();
+
+        // No end_scope: the frame's slots are discarded on return
+        let function = self.end_compiler();
+        self.emit_constant(Value::function(Rc::new(function)));
+    }
+
     fn var_declaration(&mut self) {
         let global = self.parse_variable("Expect variable name.");
 
@@ -198,26 +414,52 @@ impl<'a> Compiler<'a> {
     }
 
     fn add_local(&mut self, name: &'a str) {
-        if self.local_count == MAX_LOCALS {
+        if self.current.local_count == MAX_LOCALS {
             self.parser.error("Too many local variables in function.");
             return;
         }
 
-        self.locals.push(Local { name, depth: -1 });
-        self.local_count += 1;
+        self.current.locals.push(Local { name, depth: -1 });
+        self.current.local_count += 1;
     }
 
     fn mark_initialized(&mut self) {
-        if let Some(local) = self.locals.last_mut() {
-            local.depth = self.scope_depth;
+        if self.current.scope_depth == 0 {
+            return;
+        }
+
+        if let Some(local) = self.current.locals.last_mut() {
+            local.depth = self.current.scope_depth;
         }
     }
 
     fn statement(&mut self) {
+        if !self.nest() {
+            return;
+        }
+
         if self.parser.match_token(TokenType::Print) {
             self.print_statement();
+        } else if self.parser.match_token(TokenType::For) {
+            self.for_statement();
         } else if self.parser.match_token(TokenType::If) {
             self.if_statement();
+        } else if self.parser.match_token(TokenType::Return) {
+            self.return_statement();
+        } else if self.parser.match_token(TokenType::While) {
+            self.while_statement();
+        } else if self.parser.match_token(TokenType::Break) {
+            self.break_statement();
+        } else if self.parser.match_token(TokenType::Continue) {
+            self.continue_statement();
+        } else if self.parser.match_token(TokenType::Try) {
+            self.try_statement();
+        } else if self.parser.match_token(TokenType::Throw) {
+            self.throw_statement();
+        } else if self.parser.match_token(TokenType::Assert) {
+            self.assert_statement();
+        } else if self.parser.match_token(TokenType::Import) {
+            self.import_statement();
         } else if self.parser.match_token(TokenType::LeftBrace) {
             self.begin_scope();
             self.block();
@@ -225,18 +467,37 @@ impl<'a> Compiler<'a> {
         } else {
             self.expression_statement();
         }
+        self.nesting -= 1;
+    }
+
+    /// Enters a nested construct, unless that would nest them too deeply to
+    /// compile without overflowing the stack. Code nested that deeply is
+    /// most likely generated, so the compiler doesn't try to recover.
+    fn nest(&mut self) -> bool {
+        if self.nesting == MAX_NESTING {
+            self.parser.give_up("Code is nested too deeply.");
+            return false;
+        }
+        self.nesting += 1;
+        true
     }
 
     fn block(&mut self) {
+        if !self.nest() {
+            return;
+        }
+
         while !self.parser.check(TokenType::RightBrace) && !self.parser.check(TokenType::Eof) {
             self.declaration();
         }
 
         self.parser
             .consume(TokenType::RightBrace, "Expect '}' after block.");
+        self.nesting -= 1;
     }
 
     fn print_statement(&mut self) {
+        self.check_feature(Feature::Print, "print");
         self.expression();
         self.parser
             .consume(TokenType::Semicolon, "Expect ';' after value.");
@@ -266,25 +527,274 @@ impl<'a> Compiler<'a> {
         self.patch_jump(else_jump);
     }
 
+    fn return_statement(&mut self) {
+        if self.current.function_type == FunctionType::Script {
+            self.parser.error("Can't return from top-level code.");
+        }
+
+        if self.parser.match_token(TokenType::Semicolon) {
+            self.emit_return();
+        } else {
+            if self.current.function_type == FunctionType::Initializer {
+                self.parser
+                    .error("Can't return a value from an initializer.");
+            }
+
+            self.expression();
+            self.parser
+                .consume(TokenType::Semicolon, "Expect ';' after return value.");
+            self.emit_byte(OpCode::OpReturn);
+        }
+    }
+
+    fn while_statement(&mut self) {
+        let loop_start = self.current.function.chunk.code.len();
+        self.parser
+            .consume(TokenType::LeftParen, "Expect '(' after 'while'.");
+        self.expression();
+        self.parser
+            .consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let exit_jump = self.emit_jump(OpCode::OpJumpIfFalse);
+        self.emit_byte(OpCode::OpPop);
+        self.loop_body(loop_start);
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(OpCode::OpPop);
+        self.end_loop();
+    }
+
+    fn for_statement(&mut self) {
+        // The loop variable is scoped to the loop
+        self.begin_scope();
+        self.parser
+            .consume(TokenType::LeftParen, "Expect '(' after 'for'.");
+
+        if self.parser.match_token(TokenType::Semicolon) {
+            // No initializer
+        } else if self.parser.match_token(TokenType::Var) {
+            self.var_declaration();
+        } else {
+            self.expression_statement();
+        }
+
+        let mut loop_start = self.current.function.chunk.code.len();
+
+        let mut exit_jump = None;
+        if !self.parser.match_token(TokenType::Semicolon) {
+            self.expression();
+            self.parser
+                .consume(TokenType::Semicolon, "Expect ';' after loop condition.");
+
+            // Jump out of the loop if the condition is false
+            exit_jump = Some(self.emit_jump(OpCode::OpJumpIfFalse));
+            self.emit_byte(OpCode::OpPop); // Pop condition value
+        }
+
+        if !self.parser.match_token(TokenType::RightParen) {
+            // The increment runs after the body, so jump over it for now and
+            // loop back to it from the end of the body
+            let body_jump = self.emit_jump(OpCode::OpJump);
+            let increment_start = self.current.function.chunk.code.len();
+            self.expression();
+            self.emit_byte(OpCode::OpPop);
+            self.parser
+                .consume(TokenType::RightParen, "Expect ')' after for clauses.");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.loop_body(loop_start);
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_byte(OpCode::OpPop); // Pop condition value
+        }
+
+        self.end_loop();
+        self.end_scope();
+    }
+
+    /// Compiles the body of a loop that `continue` restarts at `start`.
+    fn loop_body(&mut self, start: usize) {
+        self.current.loops.push(Loop {
+            start,
+            scope_depth: self.current.scope_depth,
+            try_depth: self.current.try_depth,
+            break_jumps: Vec::new(),
+        });
+        self.statement();
+    }
+
+    /// Points the loop's `break` statements at the code emitted next.
+    fn end_loop(&mut self) {
+        let innermost = self.current.loops.pop().unwrap();
+        for jump in innermost.break_jumps {
+            self.patch_jump(jump);
+        }
+    }
+
+    fn break_statement(&mut self) {
+        let Some(innermost) = self.current.loops.last() else {
+            self.parser.error("Can't use 'break' outside of a loop.");
+            return;
+        };
+        let (scope_depth, try_depth) = (innermost.scope_depth, innermost.try_depth);
+        self.parser
+            .consume(TokenType::Semicolon, "Expect ';' after 'break'.");
+
+        self.discard_locals(scope_depth);
+        self.end_tries(try_depth);
+        let jump = self.emit_jump(OpCode::OpJump);
+        self.current.loops.last_mut().unwrap().break_jumps.push(jump);
+    }
+
+    fn continue_statement(&mut self) {
+        let Some(innermost) = self.current.loops.last() else {
+            self.parser.error("Can't use 'continue' outside of a loop.");
+            return;
+        };
+        let (start, scope_depth, try_depth) =
+            (innermost.start, innermost.scope_depth, innermost.try_depth);
+        self.parser
+            .consume(TokenType::Semicolon, "Expect ';' after 'continue'.");
+
+        self.discard_locals(scope_depth);
+        self.end_tries(try_depth);
+        self.emit_loop(start);
+    }
+
+    /// Pops the locals declared deeper than `depth` off the stack, leaving
+    /// them in scope for the code that follows the jump out of their block.
+    fn discard_locals(&mut self, depth: i32) {
+        for i in (0..self.current.local_count).rev() {
+            if self.current.locals[i].depth <= depth {
+                break;
+            }
+            self.emit_byte(OpCode::OpPop);
+        }
+    }
+
+    /// Removes the handlers of the try blocks nested deeper than `depth`.
+    fn end_tries(&mut self, depth: usize) {
+        for _ in depth..self.current.try_depth {
+            self.emit_byte(OpCode::OpEndTry);
+        }
+    }
+
+    fn try_statement(&mut self) {
+        let handler_jump = self.emit_jump(OpCode::OpTry);
+        self.parser
+            .consume(TokenType::LeftBrace, "Expect '{' after 'try'.");
+        self.current.try_depth += 1;
+        self.begin_scope();
+        self.block();
+        self.end_scope();
+        self.current.try_depth -= 1;
+        self.emit_byte(OpCode::OpEndTry);
+        let exit_jump = self.emit_jump(OpCode::OpJump);
+
+        // The handler resumes here with the exception on the stack, which
+        // becomes the catch block's variable
+        self.patch_jump(handler_jump);
+        self.parser
+            .consume(TokenType::Catch, "Expect 'catch' after try block.");
+        self.parser
+            .consume(TokenType::LeftParen, "Expect '(' after 'catch'.");
+        self.parser
+            .consume(TokenType::Identifier, "Expect exception variable name.");
+        self.begin_scope();
+        self.add_local(self.parser.previous.lexeme);
+        self.mark_initialized();
+        self.parser
+            .consume(TokenType::RightParen, "Expect ')' after exception variable.");
+        self.parser
+            .consume(TokenType::LeftBrace, "Expect '{' before catch body.");
+        self.block();
+        self.end_scope();
+
+        self.patch_jump(exit_jump);
+    }
+
+    fn throw_statement(&mut self) {
+        let span = self.span();
+        self.expression();
+        self.parser
+            .consume(TokenType::Semicolon, "Expect ';' after thrown value.");
+        self.emit_byte_at(OpCode::OpThrow, span);
+    }
+
+    fn assert_statement(&mut self) {
+        let span = self.span();
+        self.expression();
+        let has_message = self.parser.match_token(TokenType::Comma);
+        if has_message {
+            self.expression();
+        }
+        self.parser
+            .consume(TokenType::Semicolon, "Expect ';' after assertion.");
+        self.emit_byte_at(OpCode::OpAssert, span);
+        self.current.function.chunk.write_byte(has_message as u8, span);
+    }
+
+    fn import_statement(&mut self) {
+        self.check_feature(Feature::Io, "import");
+        let span = self.span();
+        self.parser
+            .consume(TokenType::String, "Expect module path after 'import'.");
+        let lexeme = self.parser.previous.lexeme;
+        let path = self.identifier_constant(&lexeme[1..lexeme.len() - 1]);
+        self.parser
+            .consume(TokenType::Semicolon, "Expect ';' after module path.");
+        self.emit_byte_at(OpCode::OpImport, span);
+        self.current.function.chunk.write_byte(path, span);
+        // Discard the module's return value
+        self.emit_byte(OpCode::OpPop);
+    }
+
     fn expression_statement(&mut self) {
         self.expression();
+
+        if self.options.repl
+            && self.current.function_type == FunctionType::Script
+            && self.current.scope_depth == 0
+        {
+            // The semicolon is optional after the last expression typed
+            if !self.parser.check(TokenType::Eof) {
+                self.parser
+                    .consume(TokenType::Semicolon, "Expect ';' after expression.");
+            }
+            // Without print, there's nothing to show the value with
+            if self.vm.is_enabled(Feature::Print) {
+                self.emit_byte(OpCode::OpPrint);
+            } else {
+                self.emit_byte(OpCode::OpPop);
+            }
+            return;
+        }
+
         self.parser
             .consume(TokenType::Semicolon, "Expect ';' after expression.");
         self.emit_byte(OpCode::OpPop);
     }
 
     fn begin_scope(&mut self) {
-        self.scope_depth += 1;
+        self.current.scope_depth += 1;
     }
 
     fn end_scope(&mut self) {
-        self.scope_depth -= 1;
+        self.current.scope_depth -= 1;
 
-        while self.local_count > 0
-            && self.locals[self.local_count - 1].depth > self.scope_depth
+        while self.current.local_count > 0
+            && self.current.locals[self.current.local_count - 1].depth > self.current.scope_depth
         {
             self.emit_byte(OpCode::OpPop);
-            self.local_count -= 1;
+            self.current.locals.pop();
+            self.current.local_count -= 1;
         }
     }
 
@@ -304,7 +814,13 @@ impl<'a> Compiler<'a> {
                 | TokenType::If
                 | TokenType::While
                 | TokenType::Print
-                | TokenType::Return => return,
+                | TokenType::Return
+                | TokenType::Break
+                | TokenType::Continue
+                | TokenType::Try
+                | TokenType::Throw
+                | TokenType::Assert
+                | TokenType::Import => return,
                 _ => {}
             }
 
@@ -316,36 +832,64 @@ impl<'a> Compiler<'a> {
         self.parse_precedence(Precedence::Assignment);
     }
 
-    fn number(&mut self) {
-        let value: f64 = self.parser.previous.lexeme.parse().unwrap();
-        self.emit_constant(Value::number(value));
+    fn number(&mut self, _can_assign: bool) {
+        match self.parser.previous.lexeme.parse() {
+            Ok(value) => self.emit_value(Value::number(value)),
+            Err(_) => self.parser.error("Invalid number literal."),
+        }
     }
 
-    fn string(&mut self) {
+    fn string(&mut self, _can_assign: bool) {
         let lexeme = self.parser.previous.lexeme;
         let string_value = lexeme[1..lexeme.len()-1].to_string();
         let interned = self.vm.intern_string(string_value);
-        self.emit_constant(Value::string(interned));
+        self.emit_value(Value::String(interned));
     }
 
-    fn variable(&mut self) {
-        self.named_variable(self.parser.previous.lexeme, true);
+    fn variable(&mut self, can_assign: bool) {
+        self.named_variable(self.parser.previous.lexeme, can_assign);
     }
 
-    fn resolve_local(&mut self, name: &str) -> Option<u8> {
-        for i in (0..self.local_count).rev() {
-            let local = &self.locals[i];
+    fn this(&mut self, _can_assign: bool) {
+        if self.class_depth == 0 {
+            self.parser.error("Can't use 'this' outside of a class.");
+            return;
+        }
+
+        // `this` is a read-only local in slot zero
+        self.variable(false);
+    }
+
+    fn resolve_local(&mut self, name: &str) -> Option<usize> {
+        for i in (0..self.current.local_count).rev() {
+            let local = &self.current.locals[i];
             if local.name == name {
                 if local.depth == -1 {
                     self.parser
                         .error("Can't read local variable in its own initializer.");
                 }
-                return Some(i as u8);
+                return Some(i);
             }
         }
         None
     }
 
+    /// Whether `name` is a local of a function enclosing the current one.
+    /// Functions can't capture those, as there are no closures.
+    fn is_enclosing_local(&self, name: &str) -> bool {
+        let mut state = self.current.enclosing.as_deref();
+        while let Some(function) = state {
+            if function.locals[..function.local_count]
+                .iter()
+                .any(|local| local.name == name)
+            {
+                return true;
+            }
+            state = function.enclosing.as_deref();
+        }
+        false
+    }
+
     fn named_variable(&mut self, name: &str, can_assign: bool) {
         let get_op;
         let set_op;
@@ -353,81 +897,211 @@ impl<'a> Compiler<'a> {
 
         if let Some(local_idx) = self.resolve_local(name) {
             arg = local_idx;
-            get_op = OpCode::OpGetLocal;
-            set_op = OpCode::OpSetLocal;
+            if local_idx <= u8::MAX as usize {
+                get_op = OpCode::OpGetLocal;
+                set_op = OpCode::OpSetLocal;
+            } else {
+                get_op = OpCode::OpGetLocalLong;
+                set_op = OpCode::OpSetLocalLong;
+            }
         } else {
-            arg = self.identifier_constant(name);
+            if self.is_enclosing_local(name) {
+                self.parser.error(&format!(
+                    "Can't capture local variable '{}' from an enclosing function.",
+                    name
+                ));
+            }
+            arg = self.global_index(name) as usize;
             get_op = OpCode::OpGetGlobal;
             set_op = OpCode::OpSetGlobal;
         }
 
-        if can_assign && self.parser.match_token(TokenType::Equal) {
+        let op = if can_assign && self.parser.match_token(TokenType::Equal) {
             self.expression();
-            self.emit_bytes(set_op, arg);
+            set_op
+        } else {
+            get_op
+        };
+
+        self.emit_byte(op);
+        // Global operands are always two bytes
+        if matches!(get_op, OpCode::OpGetLocal) {
+            self.emit_operand(arg as u8);
         } else {
-            self.emit_bytes(get_op, arg);
+            self.emit_operand((arg >> 8) as u8);
+            self.emit_operand(arg as u8);
         }
     }
 
-    fn literal(&mut self) {
+    fn literal(&mut self, _can_assign: bool) {
         match self.parser.previous.token_type {
-            TokenType::False => self.emit_byte(OpCode::OpFalse),
-            TokenType::True => self.emit_byte(OpCode::OpTrue),
-            TokenType::Nil => self.emit_byte(OpCode::OpNil),
+            TokenType::False => self.emit_value(Value::bool(false)),
+            TokenType::True => self.emit_value(Value::bool(true)),
+            TokenType::Nil => self.emit_value(Value::nil()),
             _ => unreachable!(),
         }
     }
 
-    fn grouping(&mut self) {
+    fn grouping(&mut self, _can_assign: bool) {
         self.expression();
         self.parser
             .consume(TokenType::RightParen, "Expect ')' after expression.");
     }
 
-    fn unary(&mut self) {
+    fn unary(&mut self, _can_assign: bool) {
         let operator_type = self.parser.previous.token_type;
+        let span = self.span();
 
         // Compile the operand
         self.parse_precedence(Precedence::Unary);
 
         // Emit the operator instruction
         match operator_type {
-            TokenType::Bang => self.emit_byte(OpCode::OpNot),
-            TokenType::Minus => self.emit_byte(OpCode::OpNegate),
+            TokenType::Bang => self.emit_operator(OpCode::OpNot, span),
+            TokenType::Minus => self.emit_operator(OpCode::OpNegate, span),
             _ => unreachable!(),
         }
     }
 
-    fn binary(&mut self) {
+    fn call(&mut self, _can_assign: bool) {
+        let arg_count = self.argument_list();
+        self.emit_bytes(OpCode::OpCall, arg_count);
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        self.parser
+            .consume(TokenType::Identifier, "Expect property name after '.'.");
+        let name = self.identifier_constant(self.parser.previous.lexeme);
+
+        if can_assign && self.parser.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_bytes(OpCode::OpSetProperty, name);
+        } else if self.parser.match_token(TokenType::LeftParen) {
+            // Calling a method directly skips creating a bound method
+            let arg_count = self.argument_list();
+            self.emit_bytes(OpCode::OpInvoke, name);
+            self.emit_operand(arg_count);
+        } else {
+            self.emit_bytes(OpCode::OpGetProperty, name);
+        }
+    }
+
+    fn list(&mut self, _can_assign: bool) {
+        let mut element_count: usize = 0;
+        if !self.parser.check(TokenType::RightBracket) {
+            loop {
+                self.expression();
+                if element_count == 255 {
+                    self.parser
+                        .error("Can't have more than 255 elements in a list literal.");
+                }
+                element_count += 1;
+
+                if !self.parser.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.parser
+            .consume(TokenType::RightBracket, "Expect ']' after list elements.");
+        self.emit_bytes(OpCode::OpBuildList, element_count as u8);
+    }
+
+    fn map(&mut self, _can_assign: bool) {
+        let mut entry_count: usize = 0;
+        if !self.parser.check(TokenType::RightBrace) {
+            loop {
+                self.expression();
+                self.parser
+                    .consume(TokenType::Colon, "Expect ':' after map key.");
+                self.expression();
+                if entry_count == 255 {
+                    self.parser
+                        .error("Can't have more than 255 entries in a map literal.");
+                }
+                entry_count += 1;
+
+                if !self.parser.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.parser
+            .consume(TokenType::RightBrace, "Expect '}' after map entries.");
+        self.emit_bytes(OpCode::OpBuildMap, entry_count as u8);
+    }
+
+    fn index(&mut self, can_assign: bool) {
+        self.expression();
+        self.parser
+            .consume(TokenType::RightBracket, "Expect ']' after index.");
+
+        if can_assign && self.parser.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_byte(OpCode::OpIndexSet);
+        } else {
+            self.emit_byte(OpCode::OpIndexGet);
+        }
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count: usize = 0;
+        if !self.parser.check(TokenType::RightParen) {
+            loop {
+                self.expression();
+                if arg_count == 255 {
+                    self.parser.error("Can't have more than 255 arguments.");
+                }
+                arg_count += 1;
+
+                if !self.parser.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.parser
+            .consume(TokenType::RightParen, "Expect ')' after arguments.");
+        arg_count as u8
+    }
+
+    fn binary(&mut self, _can_assign: bool) {
         let operator_type = self.parser.previous.token_type;
+        let span = self.span();
         let rule = self.get_rule(operator_type);
-        self.parse_precedence(rule.precedence.next());
+        if operator_type == TokenType::StarStar {
+            // Exponentiation is right-associative
+            self.parse_precedence(rule.precedence);
+        } else {
+            self.parse_precedence(rule.precedence.next());
+        }
 
         match operator_type {
             TokenType::BangEqual => {
-                self.emit_byte(OpCode::OpEqual);
-                self.emit_byte(OpCode::OpNot);
+                self.emit_operator(OpCode::OpEqual, span);
+                self.emit_operator(OpCode::OpNot, span);
             }
-            TokenType::EqualEqual => self.emit_byte(OpCode::OpEqual),
-            TokenType::Greater => self.emit_byte(OpCode::OpGreater),
+            TokenType::EqualEqual => self.emit_operator(OpCode::OpEqual, span),
+            TokenType::Greater => self.emit_operator(OpCode::OpGreater, span),
             TokenType::GreaterEqual => {
-                self.emit_byte(OpCode::OpLess);
-                self.emit_byte(OpCode::OpNot);
+                self.emit_operator(OpCode::OpLess, span);
+                self.emit_operator(OpCode::OpNot, span);
             }
-            TokenType::Less => self.emit_byte(OpCode::OpLess),
+            TokenType::Less => self.emit_operator(OpCode::OpLess, span),
             TokenType::LessEqual => {
-                self.emit_byte(OpCode::OpGreater);
-                self.emit_byte(OpCode::OpNot);
+                self.emit_operator(OpCode::OpGreater, span);
+                self.emit_operator(OpCode::OpNot, span);
             }
-            TokenType::Plus => self.emit_byte(OpCode::OpAdd),
-            TokenType::Minus => self.emit_byte(OpCode::OpSubtract),
-            TokenType::Star => self.emit_byte(OpCode::OpMultiply),
-            TokenType::Slash => self.emit_byte(OpCode::OpDivide),
+            TokenType::Plus => self.emit_operator(OpCode::OpAdd, span),
+            TokenType::Minus => self.emit_operator(OpCode::OpSubtract, span),
+            TokenType::Star => self.emit_operator(OpCode::OpMultiply, span),
+            TokenType::Slash => self.emit_operator(OpCode::OpDivide, span),
+            TokenType::Percent => self.emit_operator(OpCode::OpModulo, span),
+            TokenType::StarStar => self.emit_operator(OpCode::OpPower, span),
             _ => unreachable!(),
         }
     }
 
-    fn and(&mut self) {
+    fn and(&mut self, _can_assign: bool) {
         // Left operand is already on the stack
         // If it's false, skip the right operand
         let end_jump = self.emit_jump(OpCode::OpJumpIfFalse);
@@ -439,7 +1113,7 @@ impl<'a> Compiler<'a> {
         self.patch_jump(end_jump);
     }
 
-    fn or(&mut self) {
+    fn or(&mut self, _can_assign: bool) {
         // Left operand is already on the stack
         // If it's false, we need to evaluate the right operand
         let else_jump = self.emit_jump(OpCode::OpJumpIfFalse);
@@ -454,35 +1128,53 @@ impl<'a> Compiler<'a> {
     }
 
     fn parse_precedence(&mut self, precedence: Precedence) {
+        if !self.nest() {
+            return;
+        }
+
         self.parser.advance();
         let prefix_rule = self.get_rule(self.parser.previous.token_type).prefix;
 
+        // Only a prefix expression parsed at the lowest precedence may be the
+        // target of an assignment, so `a + b = c` is rejected
+        let can_assign = precedence <= Precedence::Assignment;
         match prefix_rule {
             None => {
                 self.parser.error("Expect expression.");
+                self.nesting -= 1;
                 return;
             }
-            Some(prefix_fn) => prefix_fn(self),
+            Some(prefix_fn) => prefix_fn(self, can_assign),
         }
 
         while precedence <= self.get_rule(self.parser.current.token_type).precedence {
             self.parser.advance();
             let infix_rule = self.get_rule(self.parser.previous.token_type).infix;
             if let Some(infix_fn) = infix_rule {
-                infix_fn(self);
+                infix_fn(self, can_assign);
             }
         }
 
-        if self.parser.check(TokenType::Equal) {
+        if can_assign && self.parser.match_token(TokenType::Equal) {
             self.parser.error("Invalid assignment target.");
         }
+        self.nesting -= 1;
     }
 
     fn get_rule(&self, token_type: TokenType) -> ParseRule<'a> {
         match token_type {
-            TokenType::LeftParen => {
-                ParseRule::new(Some(Compiler::grouping), None, Precedence::None)
-            }
+            TokenType::LeftParen => ParseRule::new(
+                Some(Compiler::grouping),
+                Some(Compiler::call),
+                Precedence::Call,
+            ),
+            TokenType::LeftBracket => ParseRule::new(
+                Some(Compiler::list),
+                Some(Compiler::index),
+                Precedence::Call,
+            ),
+            TokenType::LeftBrace => ParseRule::new(Some(Compiler::map), None, Precedence::None),
+            TokenType::Dot => ParseRule::new(None, Some(Compiler::dot), Precedence::Call),
             TokenType::Minus => ParseRule::new(
                 Some(Compiler::unary),
                 Some(Compiler::binary),
@@ -491,6 +1183,8 @@ impl<'a> Compiler<'a> {
             TokenType::Plus => ParseRule::new(None, Some(Compiler::binary), Precedence::Term),
             TokenType::Slash => ParseRule::new(None, Some(Compiler::binary), Precedence::Factor),
             TokenType::Star => ParseRule::new(None, Some(Compiler::binary), Precedence::Factor),
+            TokenType::Percent => ParseRule::new(None, Some(Compiler::binary), Precedence::Factor),
+            TokenType::StarStar => ParseRule::new(None, Some(Compiler::binary), Precedence::Power),
             TokenType::Bang => ParseRule::new(Some(Compiler::unary), None, Precedence::None),
             TokenType::BangEqual => {
                 ParseRule::new(None, Some(Compiler::binary), Precedence::Equality)
@@ -516,53 +1210,96 @@ impl<'a> Compiler<'a> {
             TokenType::False => ParseRule::new(Some(Compiler::literal), None, Precedence::None),
             TokenType::True => ParseRule::new(Some(Compiler::literal), None, Precedence::None),
             TokenType::Nil => ParseRule::new(Some(Compiler::literal), None, Precedence::None),
+            TokenType::This => ParseRule::new(Some(Compiler::this), None, Precedence::None),
             TokenType::And => ParseRule::new(None, Some(Compiler::and), Precedence::And),
             TokenType::Or => ParseRule::new(None, Some(Compiler::or), Precedence::Or),
             _ => ParseRule::new(None, None, Precedence::None),
         }
     }
 
+    fn span(&self) -> Span {
+        span_of(&self.parser.previous)
+    }
+
     fn emit_byte(&mut self, opcode: OpCode) {
-        let line = self.parser.previous.line as usize;
-        self.chunk.write(opcode, line);
+        self.emit_byte_at(opcode, self.span());
+    }
+
+    // Operators are attributed to the operator token rather than to the end
+    // of their last operand, so runtime errors point at the operator
+    fn emit_byte_at(&mut self, opcode: OpCode, span: Span) {
+        let start = self.current.function.chunk.code.len();
+        self.current.instruction_starts.push(start);
+        self.current.function.chunk.write(opcode, span);
     }
 
     fn emit_bytes(&mut self, byte1: OpCode, byte2: u8) {
         self.emit_byte(byte1);
-        let line = self.parser.previous.line as usize;
-        self.chunk.write_byte(byte2, line);
+        self.emit_operand(byte2);
+    }
+
+    fn emit_operand(&mut self, byte: u8) {
+        let span = self.span();
+        self.current.function.chunk.write_byte(byte, span);
     }
 
     fn emit_jump(&mut self, instruction: OpCode) -> usize {
-        let line = self.parser.previous.line as usize;
-        self.chunk.emit_jump(instruction, line)
+        let instruction = if self.long_jumps {
+            instruction.long_jump()
+        } else {
+            instruction
+        };
+        let span = self.span();
+        self.current.function.chunk.emit_jump(instruction, span)
     }
 
     fn patch_jump(&mut self, offset: usize) {
-        self.chunk.patch_jump(offset);
+        if let Err(message) = self.current.function.chunk.patch_jump(offset) {
+            if self.long_jumps {
+                self.parser.error(message);
+            } else {
+                self.jump_too_far = true;
+            }
+        }
+        self.current.jump_target = self.current.function.chunk.code.len();
     }
 
-    fn parse_variable(&mut self, error_message: &str) -> u8 {
+    fn emit_loop(&mut self, loop_start: usize) {
+        let span = self.span();
+        if let Err(message) = self.current.function.chunk.emit_loop(loop_start, span) {
+            self.parser.error(message);
+        }
+    }
+
+    /// Reports an error at the token just parsed, `name`, if it uses a
+    /// feature the VM has disabled.
+    fn check_feature(&mut self, feature: Feature, name: &str) {
+        if !self.vm.is_enabled(feature) {
+            self.parser.error(&feature_disabled(name));
+        }
+    }
+
+    fn parse_variable(&mut self, error_message: &str) -> u16 {
         self.parser.consume(TokenType::Identifier, error_message);
 
         self.declare_variable();
-        if self.scope_depth > 0 {
+        if self.current.scope_depth > 0 {
             return 0;
         }
 
-        self.identifier_constant(self.parser.previous.lexeme)
+        self.global_index(self.parser.previous.lexeme)
     }
 
     fn declare_variable(&mut self) {
-        if self.scope_depth == 0 {
+        if self.current.scope_depth == 0 {
             return;
         }
 
         let name = self.parser.previous.lexeme;
 
-        for i in (0..self.local_count).rev() {
-            let local = &self.locals[i];
-            if local.depth != -1 && local.depth < self.scope_depth {
+        for i in (0..self.current.local_count).rev() {
+            let local = &self.current.locals[i];
+            if local.depth != -1 && local.depth < self.current.scope_depth {
                 break;
             }
 
@@ -577,29 +1314,245 @@ impl<'a> Compiler<'a> {
 
     fn identifier_constant(&mut self, name: &str) -> u8 {
         let interned = self.vm.intern_string(name.to_string());
-        self.chunk.add_constant(Value::string(interned)) as u8
+        let constant = self.current.function.chunk.add_constant(Value::String(interned));
+        // Only OpConstant has a long form, so names must fit in a byte
+        if constant > u8::MAX as usize {
+            self.parser.error("Too many constants in one chunk.");
+            return 0;
+        }
+        constant as u8
     }
 
-    fn define_variable(&mut self, global: u8) {
-        if self.scope_depth > 0 {
+    /// Returns the index in the current chunk of the global variable
+    /// `name`, resolving it to its slot in the VM.
+    fn global_index(&mut self, name: &str) -> u16 {
+        let interned = self.vm.intern_string(name.to_string());
+        let slot = self.vm.global_slot(Rc::clone(&interned));
+        let index = self.current.function.chunk.add_global(interned, slot);
+        if index > u16::MAX as usize {
+            self.parser.error("Too many global variables in one chunk.");
+            return 0;
+        }
+        index as u16
+    }
+
+    fn define_variable(&mut self, global: u16) {
+        if self.current.scope_depth > 0 {
             self.mark_initialized();
             return;
         }
 
-        self.emit_bytes(OpCode::OpDefineGlobal, global);
+        self.emit_byte(OpCode::OpDefineGlobal);
+        self.emit_operand((global >> 8) as u8);
+        self.emit_operand(global as u8);
     }
 
     fn emit_constant(&mut self, value: Value) {
-        let constant = self.chunk.add_constant(value);
-        self.emit_bytes(OpCode::OpConstant, constant as u8);
+        let constant = self.current.function.chunk.add_constant(value);
+        if constant <= u8::MAX as usize {
+            self.emit_bytes(OpCode::OpConstant, constant as u8);
+        } else if constant < 1 << 24 {
+            self.emit_byte(OpCode::OpConstantLong);
+            self.emit_operand((constant >> 16) as u8);
+            self.emit_operand((constant >> 8) as u8);
+            self.emit_operand(constant as u8);
+        } else {
+            self.parser.error("Too many constants in one chunk.");
+        }
+    }
+
+    /// Emits the instruction that pushes `value`, remembering it for constant
+    /// folding.
+    fn emit_value(&mut self, value: Value) {
+        let start = self.current.function.chunk.code.len();
+        match value {
+            Value::Nil => self.emit_byte(OpCode::OpNil),
+            Value::Bool(true) => self.emit_byte(OpCode::OpTrue),
+            Value::Bool(false) => self.emit_byte(OpCode::OpFalse),
+            value => self.emit_constant(value),
+        }
+        self.current.constant_starts.push(start);
+    }
+
+    /// Emits an arithmetic, comparison or logical operator, or with
+    /// optimizations enabled, replaces it and its constant operands with the
+    /// result, or fuses it with the instructions before it.
+    fn emit_operator(&mut self, opcode: OpCode, span: Span) {
+        if !(self.options.optimize && (self.fold(opcode) || self.fuse(opcode, span))) {
+            self.emit_byte_at(opcode, span);
+        }
+    }
+
+    /// Drops the code from `offset` on, to be replaced.
+    fn truncate_code(&mut self, offset: usize) {
+        let state = &mut self.current;
+        state.function.chunk.code.truncate(offset);
+        state.function.chunk.spans.truncate(offset);
+        state.constant_starts.retain(|&start| start < offset);
+        state.instruction_starts.retain(|&start| start < offset);
     }
 
-    fn end_compiler(&mut self) {
+    /// Replaces `opcode` and the instructions just before it with a
+    /// superinstruction doing the same, if they're a sequence that has one:
+    /// OpConstant and OpAdd, two OpGetLocals and OpLess, or OpEqual and
+    /// OpNot. The superinstruction takes the operands of the ones it
+    /// replaces, in order.
+    fn fuse(&mut self, opcode: OpCode, span: Span) -> bool {
+        let (pattern, fused): (&[OpCode], OpCode) = match opcode {
+            OpCode::OpAdd => (&[OpCode::OpConstant], OpCode::OpAddConstant),
+            OpCode::OpLess => (&[OpCode::OpGetLocal, OpCode::OpGetLocal], OpCode::OpLessLocals),
+            OpCode::OpNot => (&[OpCode::OpEqual], OpCode::OpNotEqual),
+            _ => return false,
+        };
+        let starts = &self.current.instruction_starts;
+        if starts.len() < pattern.len() {
+            return false;
+        }
+        let starts = &starts[starts.len() - pattern.len()..];
+        let first = starts[0];
+        if first < self.current.jump_target {
+            return false;
+        }
+
+        // The instructions must be the last ones emitted, one after the other
+        let code = &self.current.function.chunk.code;
+        let mut operands = Vec::new();
+        let mut offset = first;
+        for (&start, &expected) in starts.iter().zip(pattern) {
+            if start != offset || code[start] != expected as u8 {
+                return false;
+            }
+            let length = match expected {
+                OpCode::OpEqual => 1,
+                _ => 2,
+            };
+            operands.extend_from_slice(&code[start + 1..start + length]);
+            offset += length;
+        }
+        if offset != code.len() {
+            return false;
+        }
+
+        self.truncate_code(first);
+        self.emit_byte_at(fused, span);
+        for operand in operands {
+            self.current.function.chunk.write_byte(operand, span);
+        }
+        true
+    }
+
+    fn fold(&mut self, opcode: OpCode) -> bool {
+        let arity = match opcode {
+            OpCode::OpNot | OpCode::OpNegate => 1,
+            _ => 2,
+        };
+        let starts = &self.current.constant_starts;
+        if starts.len() < arity {
+            return false;
+        }
+        let operands = &starts[starts.len() - arity..];
+        let first = operands[0];
+        if first < self.current.jump_target {
+            return false;
+        }
+
+        // The operands must be the last instructions emitted, one after the
+        // other
+        let chunk = &self.current.function.chunk;
+        let mut values = Vec::with_capacity(arity);
+        let mut offset = first;
+        for &start in operands {
+            if start != offset {
+                return false;
+            }
+            let (value, length) = constant_at(chunk, start);
+            values.push(value);
+            offset += length;
+        }
+        if offset != chunk.code.len() {
+            return false;
+        }
+
+        let result = match (opcode, values.as_slice()) {
+            (OpCode::OpNot, [a]) => Value::bool(a.is_falsey()),
+            (OpCode::OpNegate, [Value::Number(a)]) => Value::number(-a),
+            (OpCode::OpEqual, [a, b]) => Value::bool(a == b),
+            (OpCode::OpGreater, [Value::Number(a), Value::Number(b)]) => Value::bool(a > b),
+            (OpCode::OpLess, [Value::Number(a), Value::Number(b)]) => Value::bool(a < b),
+            (OpCode::OpAdd, [Value::Number(a), Value::Number(b)]) => Value::number(a + b),
+            (OpCode::OpAdd, [Value::String(a), Value::String(b)]) => {
+                Value::String(self.vm.intern_string(format!("{}{}", a, b)))
+            }
+            (OpCode::OpSubtract, [Value::Number(a), Value::Number(b)]) => Value::number(a - b),
+            (OpCode::OpMultiply, [Value::Number(a), Value::Number(b)]) => Value::number(a * b),
+            (OpCode::OpDivide, [Value::Number(a), Value::Number(b)]) => Value::number(a / b),
+            (OpCode::OpModulo, [Value::Number(a), Value::Number(b)]) => Value::number(a % b),
+            (OpCode::OpPower, [Value::Number(a), Value::Number(b)]) => Value::number(a.powf(*b)),
+            // Leave operations that fail to report the error at runtime
+            _ => return false,
+        };
+
+        self.truncate_code(first);
+        self.emit_value(result);
+        true
+    }
+
+    fn emit_return(&mut self) {
+        // Initializers always return the instance in slot zero
+        if self.current.function_type == FunctionType::Initializer {
+            self.emit_bytes(OpCode::OpGetLocal, 0);
+        } else {
+            self.emit_byte(OpCode::OpNil);
+        }
         self.emit_byte(OpCode::OpReturn);
     }
+
+    /// Finishes the current function and returns it, resuming compilation of
+    /// the enclosing function if there is one.
+    fn end_compiler(&mut self) -> Function {
+        self.emit_return();
+
+        let state = match self.current.enclosing.take() {
+            Some(enclosing) => mem::replace(&mut self.current, *enclosing),
+            None => mem::replace(
+                &mut self.current,
+                FunctionState::new(FunctionType::Script, None),
+            ),
+        };
+        let mut function = state.function;
+        function.module = self.module.clone();
+        function
+    }
+}
+
+/// Returns the value pushed by the constant instruction at `offset`, and the
+/// instruction's length.
+fn constant_at(chunk: &Chunk, offset: usize) -> (Value, usize) {
+    match chunk.code[offset] {
+        x if x == OpCode::OpNil as u8 => (Value::nil(), 1),
+        x if x == OpCode::OpTrue as u8 => (Value::bool(true), 1),
+        x if x == OpCode::OpFalse as u8 => (Value::bool(false), 1),
+        x if x == OpCode::OpConstant as u8 => {
+            (chunk.get_constant(chunk.code[offset + 1] as usize).to_value(), 2)
+        }
+        x if x == OpCode::OpConstantLong as u8 => {
+            let index = (chunk.code[offset + 1] as usize) << 16
+                | (chunk.code[offset + 2] as usize) << 8
+                | chunk.code[offset + 3] as usize;
+            (chunk.get_constant(index).to_value(), 4)
+        }
+        _ => unreachable!("not a constant instruction"),
+    }
+}
+
+fn span_of(token: &Token) -> Span {
+    Span {
+        line: token.line as usize,
+        column: token.column,
+    }
 }
 
-type ParseFn<'a> = fn(&mut Compiler<'a>);
+type ParseFn<'a> = fn(&mut Compiler<'a>, bool);
 
 struct ParseRule<'a> {
     prefix: Option<ParseFn<'a>>,
@@ -621,7 +1574,302 @@ impl<'a> ParseRule<'a> {
     }
 }
 
-pub fn compile(source: &str, vm: &mut VM) -> Option<Chunk> {
-    let compiler = Compiler::new(source, vm);
+/// Settings for [`compile_with`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CompileOptions {
+    /// Compile a line typed at the REPL: top-level expression statements
+    /// print their value, and the final one may omit its semicolon.
+    pub repl: bool,
+    /// Evaluate operators whose operands are constants at compile time, and
+    /// fuse common instruction sequences into superinstructions.
+    pub optimize: bool,
+}
+
+pub fn compile(source: &str, vm: &mut VM) -> Result<Chunk, Vec<CompileError>> {
+    compile_with(source, vm, CompileOptions::default())
+}
+
+pub fn compile_with(
+    source: &str,
+    vm: &mut VM,
+    options: CompileOptions,
+) -> Result<Chunk, Vec<CompileError>> {
+    let mut compiler = Compiler::new(source, vm);
+    compiler.options = options;
+    compiler.compile()
+}
+
+/// Compiles a module for `import`, recording `path` in its functions so
+/// errors can say where they happened.
+pub(crate) fn compile_module(
+    source: &str,
+    vm: &mut VM,
+    path: &str,
+) -> Result<Chunk, Vec<CompileError>> {
+    let mut compiler = Compiler::new(source, vm);
+    compiler.module = Some(path.into());
     compiler.compile()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile_code(source: &str, optimize: bool) -> Vec<u8> {
+        let mut vm = VM::new();
+        let options = CompileOptions {
+            optimize,
+            ..Default::default()
+        };
+        compile_with(source, &mut vm, options).expect("Failed to compile").code
+    }
+
+    #[test]
+    fn test_constant_folding() {
+        let mut vm = VM::new();
+        let options = CompileOptions {
+            optimize: true,
+            ..Default::default()
+        };
+        let chunk = compile_with("print -2 * 3 + 4;", &mut vm, options).unwrap();
+        assert_eq!(chunk.code.len(), 5);
+        assert_eq!(chunk.code[0], OpCode::OpConstant as u8);
+        assert_eq!(chunk.get_constant(chunk.code[1] as usize).to_value(), Value::number(-2.0));
+
+        let print_true = vec![
+            OpCode::OpTrue as u8,
+            OpCode::OpPrint as u8,
+            OpCode::OpNil as u8,
+            OpCode::OpReturn as u8,
+        ];
+        assert_eq!(compile_code("print \"a\" + \"b\" == \"ab\";", true), print_true);
+        assert_eq!(compile_code("print !(1 >= 2);", true), print_true);
+    }
+
+    #[test]
+    fn test_constant_folding_leaves_other_code() {
+        let unchanged = [
+            // Type errors are left to be reported at runtime
+            "print 1 * \"a\";",
+            "print -nil;",
+            "var a; print a * 2 * 3;",
+            // The jump out of `and` lands on the 1, which must stay separate
+            "print (false and 2) == 1;",
+        ];
+        for source in unchanged {
+            assert_eq!(compile_code(source, true), compile_code(source, false), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_superinstructions() {
+        let mut vm = VM::new();
+        let options = CompileOptions {
+            optimize: true,
+            ..Default::default()
+        };
+        let chunk = compile_with("fun f(a, b) { return a < b or a != b + 1; }", &mut vm, options)
+            .unwrap();
+        let Value::Function(f) = &chunk.get_constant(0).to_value() else {
+            panic!("Expected a function");
+        };
+        assert_eq!(
+            f.chunk.code,
+            vec![
+                OpCode::OpLessLocals as u8,
+                1,
+                2,
+                OpCode::OpJumpIfFalse as u8,
+                0,
+                3,
+                OpCode::OpJump as u8,
+                0,
+                8,
+                OpCode::OpPop as u8,
+                OpCode::OpGetLocal as u8,
+                1,
+                OpCode::OpGetLocal as u8,
+                2,
+                OpCode::OpAddConstant as u8,
+                0,
+                OpCode::OpNotEqual as u8,
+                OpCode::OpReturn as u8,
+                OpCode::OpNil as u8,
+                OpCode::OpReturn as u8,
+            ]
+        );
+
+        // Adding mismatched constants isn't folded, but is still fused
+        assert_eq!(
+            compile_code("print 1 + \"a\";", true),
+            vec![
+                OpCode::OpConstant as u8,
+                0,
+                OpCode::OpAddConstant as u8,
+                1,
+                OpCode::OpPrint as u8,
+                OpCode::OpNil as u8,
+                OpCode::OpReturn as u8,
+            ]
+        );
+
+        // A jump landing between the instructions keeps them apart
+        for source in ["print !(nil and 1 == 1);", "print 1 + (nil and 2);"] {
+            let code = compile_code(source, true);
+            assert!(
+                !code.contains(&(OpCode::OpNotEqual as u8))
+                    && !code.contains(&(OpCode::OpAddConstant as u8)),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_too_many_identifiers() {
+        // Globals have two-byte operands
+        let mut source = String::new();
+        for i in 0..300 {
+            source.push_str(&format!("var v{} = {};", i, i));
+        }
+        source.push_str("print v299;");
+        let mut vm = VM::new();
+        assert!(compile(&source, &mut vm).is_ok());
+
+        // Property names are constants, which only have one
+        let mut source = "fun f(o) {".to_string();
+        for i in 0..300 {
+            source.push_str(&format!("o.p{};", i));
+        }
+        source.push('}');
+        let errors = compile(&source, &mut vm).unwrap_err();
+        assert_eq!(errors[0].message, "Too many constants in one chunk.");
+        assert_eq!(errors[0].location, Location::Token("p256".to_string()));
+    }
+
+    #[test]
+    fn test_deep_nesting() {
+        let mut vm = VM::new();
+        let nested = |open: &str, inner: &str, close: &str, depth| {
+            format!("{}{}{}", open.repeat(depth), inner, close.repeat(depth))
+        };
+
+        for source in [
+            format!("print {};", nested("-", "1", "", 100)),
+            format!("print {};", nested("(", "1", ")", 200)),
+            nested("{", "", "}", 100),
+        ] {
+            assert!(compile(&source, &mut vm).is_ok(), "{}", source);
+        }
+
+        for source in [
+            format!("print {};", nested("(", "1", ")", 100_000)),
+            format!("print {};", nested("-", "1", "", 100_000)),
+            format!("print {};", nested("[", "", "]", 100_000)),
+            nested("{", "", "}", 100_000),
+            nested("if (true) ", "print 1;", "", 100_000),
+            nested("fun f() {", "", "}", 100_000),
+        ] {
+            let errors = compile(&source, &mut vm).unwrap_err();
+            assert_eq!(errors[0].message, "Code is nested too deeply.");
+        }
+    }
+
+    #[test]
+    fn test_reports_every_error() {
+        let mut vm = VM::new();
+        let source = "var = 1;\n\
+                      print 1 +;\n\
+                      fun f( { return; }\n\
+                      var ok = 1;\n\
+                      { var y = ; }\n\
+                      print @;\n";
+        let errors = compile(source, &mut vm).unwrap_err();
+        let lines: Vec<usize> = errors.iter().map(|error| error.line).collect();
+        assert_eq!(lines, vec![1, 2, 3, 5, 6]);
+        assert_eq!(errors[4].message, "Unexpected character.");
+    }
+
+    #[test]
+    fn test_errors_report_the_line_a_token_ends_on() {
+        let mut vm = VM::new();
+        let errors = compile("print 1;\nprint \"a\nb\nc", &mut vm).unwrap_err();
+        assert_eq!((errors[0].line, errors[0].end_line), (2, 4));
+        assert_eq!(errors[0].to_string(), "[line 4] Error: Unterminated string.");
+        assert!(errors[0].render("print 1;\nprint \"a\nb\nc").contains("2 | print \"a"));
+    }
+
+    #[test]
+    fn test_enclosing_locals_cant_be_captured() {
+        let mut vm = VM::new();
+        let source = "var x = 1;
+                      fun outer(a) {
+                        var x = 2;
+                        fun inner() { var b = a; return x; }
+                        return inner();
+                      }
+                      class A { m() { fun f() { return this; } } }";
+        let errors = compile(source, &mut vm).unwrap_err();
+        let names: Vec<_> = errors.iter().map(|error| error.location.clone()).collect();
+        assert_eq!(
+            names,
+            ["a", "x", "this"].map(|name| Location::Token(name.to_string()))
+        );
+        assert_eq!(
+            errors[1].message,
+            "Can't capture local variable 'x' from an enclosing function."
+        );
+
+        // Globals, and the nested function's own locals, are fine
+        let source = "var x = 1;
+                      fun outer() { fun inner(y) { var z = y; return x + z; } return inner(2); }
+                      { var x = 3; }";
+        assert!(compile(source, &mut vm).is_ok());
+    }
+
+    #[test]
+    fn test_long_jumps() {
+        // Each increment is 10 bytes of code, so the bodies are over 64KB
+        let body = "n = n + 1;".repeat(7_000);
+        let source = format!(
+            "var n = 0; var i = 0;
+             if (n == 0) {{ {body} }} else {{ {body} }}
+             while (i < 2) {{ i = i + 1; {body} }}
+             try {{ {body} throw 1; }} catch (e) {{ n = n + e; }}
+             var ok = n > 0 and n < 0 or true;"
+        );
+        let mut vm = VM::new();
+        let chunk = compile(&source, &mut vm).unwrap();
+        let (result, stats) = vm.interpret_with_stats(chunk);
+        assert!(result.is_ok());
+        assert_eq!(vm.get_global("n"), Some(Value::number(28_001.0)));
+        assert_eq!(vm.get_global("ok"), Some(Value::bool(true)));
+
+        let ran = |opcode: OpCode| stats.opcode_counts[opcode as usize] > 0;
+        assert!(ran(OpCode::OpJumpLong));
+        assert!(ran(OpCode::OpJumpIfFalseLong));
+        assert!(ran(OpCode::OpLoopLong));
+        assert!(ran(OpCode::OpTryLong));
+        assert!(!ran(OpCode::OpJump) && !ran(OpCode::OpJumpIfFalse));
+
+        // Code that fits keeps the short forms
+        let chunk = compile("var i = 0; while (i < 2) i = i + 1;", &mut vm).unwrap();
+        let (_, stats) = vm.interpret_with_stats(chunk);
+        assert!(stats.opcode_counts[OpCode::OpLoop as usize] > 0);
+        assert_eq!(stats.opcode_counts[OpCode::OpJumpIfFalseLong as usize], 0);
+    }
+
+    #[test]
+    fn test_constant_folding_long_constants() {
+        let mut source = String::new();
+        for i in 0..300 {
+            source.push_str(&format!("{};", i));
+        }
+        source.push_str("print 1000 + 1;");
+
+        let code = compile_code(&source, true);
+        let folded = code.len() - 7;
+        assert_eq!(code[folded], OpCode::OpConstantLong as u8);
+        assert_eq!(code[folded + 4], OpCode::OpPrint as u8);
+    }
+}
@@ -1,72 +1,172 @@
 use crate::chunk::{Chunk, OpCode};
-use crate::value;
+use crate::value::Value;
+use std::io::{self, Write};
 
-pub fn disassemble_chunk(chunk: &Chunk, name: &str) {
-    println!("== {} ==", name);
+pub fn disassemble_chunk(out: &mut dyn Write, chunk: &Chunk, name: &str) -> io::Result<()> {
+    writeln!(out, "== {} ==", name)?;
 
     let mut offset = 0;
     while offset < chunk.code.len() {
-        offset = disassemble_instruction(chunk, offset);
+        offset = disassemble_instruction(out, chunk, offset)?;
     }
+    Ok(())
 }
 
-pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
-    print!("{:04} ", offset);
+/// Disassembles `chunk` followed by the functions among its constants, and
+/// recursively the functions nested in those.
+pub fn disassemble_program(out: &mut dyn Write, chunk: &Chunk, name: &str) -> io::Result<()> {
+    disassemble_chunk(out, chunk, name)?;
+
+    for constant in chunk.constants() {
+        if let Value::Function(function) = &constant.to_value() {
+            writeln!(out)?;
+            let name = function.name.as_deref().unwrap_or("<script>");
+            disassemble_program(out, &function.chunk, name)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn disassemble_instruction(out: &mut dyn Write, chunk: &Chunk, offset: usize) -> io::Result<usize> {
+    write!(out, "{:04} ", offset)?;
+    if offset > 0 && chunk.spans[offset].line == chunk.spans[offset - 1].line {
+        write!(out, "   | ")?;
+    } else {
+        write!(out, "{:4} ", chunk.spans[offset].line)?;
+    }
 
     let instruction = chunk.code[offset];
 
     match instruction {
-        x if x == OpCode::OpConstant as u8 => constant_instruction("OP_CONSTANT", chunk, offset),
-        x if x == OpCode::OpNil as u8 => simple_instruction("OP_NIL", offset),
-        x if x == OpCode::OpTrue as u8 => simple_instruction("OP_TRUE", offset),
-        x if x == OpCode::OpFalse as u8 => simple_instruction("OP_FALSE", offset),
-        x if x == OpCode::OpEqual as u8 => simple_instruction("OP_EQUAL", offset),
-        x if x == OpCode::OpGreater as u8 => simple_instruction("OP_GREATER", offset),
-        x if x == OpCode::OpLess as u8 => simple_instruction("OP_LESS", offset),
-        x if x == OpCode::OpAdd as u8 => simple_instruction("OP_ADD", offset),
-        x if x == OpCode::OpSubtract as u8 => simple_instruction("OP_SUBTRACT", offset),
-        x if x == OpCode::OpMultiply as u8 => simple_instruction("OP_MULTIPLY", offset),
-        x if x == OpCode::OpDivide as u8 => simple_instruction("OP_DIVIDE", offset),
-        x if x == OpCode::OpNot as u8 => simple_instruction("OP_NOT", offset),
-        x if x == OpCode::OpNegate as u8 => simple_instruction("OP_NEGATE", offset),
-        x if x == OpCode::OpPop as u8 => simple_instruction("OP_POP", offset),
-        x if x == OpCode::OpPrint as u8 => simple_instruction("OP_PRINT", offset),
-        x if x == OpCode::OpDefineGlobal as u8 => constant_instruction("OP_DEFINE_GLOBAL", chunk, offset),
-        x if x == OpCode::OpGetGlobal as u8 => constant_instruction("OP_GET_GLOBAL", chunk, offset),
-        x if x == OpCode::OpSetGlobal as u8 => constant_instruction("OP_SET_GLOBAL", chunk, offset),
-        x if x == OpCode::OpGetLocal as u8 => byte_instruction("OP_GET_LOCAL", chunk, offset),
-        x if x == OpCode::OpSetLocal as u8 => byte_instruction("OP_SET_LOCAL", chunk, offset),
-        x if x == OpCode::OpJumpIfFalse as u8 => jump_instruction("OP_JUMP_IF_FALSE", 1, chunk, offset),
-        x if x == OpCode::OpJump as u8 => jump_instruction("OP_JUMP", 1, chunk, offset),
-        x if x == OpCode::OpLoop as u8 => jump_instruction("OP_LOOP", -1, chunk, offset),
-        x if x == OpCode::OpReturn as u8 => simple_instruction("OP_RETURN", offset),
+        x if x == OpCode::OpConstant as u8 => constant_instruction(out, "OP_CONSTANT", chunk, offset),
+        x if x == OpCode::OpConstantLong as u8 => constant_long_instruction(out, "OP_CONSTANT_LONG", chunk, offset),
+        x if x == OpCode::OpNil as u8 => simple_instruction(out, "OP_NIL", offset),
+        x if x == OpCode::OpTrue as u8 => simple_instruction(out, "OP_TRUE", offset),
+        x if x == OpCode::OpFalse as u8 => simple_instruction(out, "OP_FALSE", offset),
+        x if x == OpCode::OpEqual as u8 => simple_instruction(out, "OP_EQUAL", offset),
+        x if x == OpCode::OpGreater as u8 => simple_instruction(out, "OP_GREATER", offset),
+        x if x == OpCode::OpLess as u8 => simple_instruction(out, "OP_LESS", offset),
+        x if x == OpCode::OpAdd as u8 => simple_instruction(out, "OP_ADD", offset),
+        x if x == OpCode::OpSubtract as u8 => simple_instruction(out, "OP_SUBTRACT", offset),
+        x if x == OpCode::OpMultiply as u8 => simple_instruction(out, "OP_MULTIPLY", offset),
+        x if x == OpCode::OpDivide as u8 => simple_instruction(out, "OP_DIVIDE", offset),
+        x if x == OpCode::OpModulo as u8 => simple_instruction(out, "OP_MODULO", offset),
+        x if x == OpCode::OpPower as u8 => simple_instruction(out, "OP_POWER", offset),
+        x if x == OpCode::OpNot as u8 => simple_instruction(out, "OP_NOT", offset),
+        x if x == OpCode::OpNegate as u8 => simple_instruction(out, "OP_NEGATE", offset),
+        x if x == OpCode::OpPop as u8 => simple_instruction(out, "OP_POP", offset),
+        x if x == OpCode::OpPrint as u8 => simple_instruction(out, "OP_PRINT", offset),
+        x if x == OpCode::OpDefineGlobal as u8 => global_instruction(out, "OP_DEFINE_GLOBAL", chunk, offset),
+        x if x == OpCode::OpGetGlobal as u8 => global_instruction(out, "OP_GET_GLOBAL", chunk, offset),
+        x if x == OpCode::OpSetGlobal as u8 => global_instruction(out, "OP_SET_GLOBAL", chunk, offset),
+        x if x == OpCode::OpGetLocal as u8 => byte_instruction(out, "OP_GET_LOCAL", chunk, offset),
+        x if x == OpCode::OpSetLocal as u8 => byte_instruction(out, "OP_SET_LOCAL", chunk, offset),
+        x if x == OpCode::OpGetLocalLong as u8 => short_instruction(out, "OP_GET_LOCAL_LONG", chunk, offset),
+        x if x == OpCode::OpSetLocalLong as u8 => short_instruction(out, "OP_SET_LOCAL_LONG", chunk, offset),
+        x if x == OpCode::OpGetProperty as u8 => constant_instruction(out, "OP_GET_PROPERTY", chunk, offset),
+        x if x == OpCode::OpSetProperty as u8 => constant_instruction(out, "OP_SET_PROPERTY", chunk, offset),
+        x if x == OpCode::OpJumpIfFalse as u8 => jump_instruction(out, "OP_JUMP_IF_FALSE", 1, chunk, offset),
+        x if x == OpCode::OpJump as u8 => jump_instruction(out, "OP_JUMP", 1, chunk, offset),
+        x if x == OpCode::OpLoop as u8 => jump_instruction(out, "OP_LOOP", -1, chunk, offset),
+        x if x == OpCode::OpCall as u8 => byte_instruction(out, "OP_CALL", chunk, offset),
+        x if x == OpCode::OpInvoke as u8 => invoke_instruction(out, "OP_INVOKE", chunk, offset),
+        x if x == OpCode::OpReturn as u8 => simple_instruction(out, "OP_RETURN", offset),
+        x if x == OpCode::OpClass as u8 => constant_instruction(out, "OP_CLASS", chunk, offset),
+        x if x == OpCode::OpMethod as u8 => constant_instruction(out, "OP_METHOD", chunk, offset),
+        x if x == OpCode::OpBuildList as u8 => byte_instruction(out, "OP_BUILD_LIST", chunk, offset),
+        x if x == OpCode::OpIndexGet as u8 => simple_instruction(out, "OP_INDEX_GET", offset),
+        x if x == OpCode::OpIndexSet as u8 => simple_instruction(out, "OP_INDEX_SET", offset),
+        x if x == OpCode::OpBuildMap as u8 => byte_instruction(out, "OP_BUILD_MAP", chunk, offset),
+        x if x == OpCode::OpTry as u8 => jump_instruction(out, "OP_TRY", 1, chunk, offset),
+        x if x == OpCode::OpEndTry as u8 => simple_instruction(out, "OP_END_TRY", offset),
+        x if x == OpCode::OpThrow as u8 => simple_instruction(out, "OP_THROW", offset),
+        x if x == OpCode::OpAssert as u8 => byte_instruction(out, "OP_ASSERT", chunk, offset),
+        x if x == OpCode::OpImport as u8 => constant_instruction(out, "OP_IMPORT", chunk, offset),
+        x if x == OpCode::OpJumpLong as u8 => long_jump_instruction(out, "OP_JUMP_LONG", 1, chunk, offset),
+        x if x == OpCode::OpJumpIfFalseLong as u8 => long_jump_instruction(out, "OP_JUMP_IF_FALSE_LONG", 1, chunk, offset),
+        x if x == OpCode::OpLoopLong as u8 => long_jump_instruction(out, "OP_LOOP_LONG", -1, chunk, offset),
+        x if x == OpCode::OpTryLong as u8 => long_jump_instruction(out, "OP_TRY_LONG", 1, chunk, offset),
+        x if x == OpCode::OpAddConstant as u8 => constant_instruction(out, "OP_ADD_CONSTANT", chunk, offset),
+        x if x == OpCode::OpLessLocals as u8 => two_byte_instruction(out, "OP_LESS_LOCALS", chunk, offset),
+        x if x == OpCode::OpNotEqual as u8 => simple_instruction(out, "OP_NOT_EQUAL", offset),
         _ => {
-            println!("Unknown opcode {}", instruction);
-            offset + 1
+            writeln!(out, "Unknown opcode {}", instruction)?;
+            Ok(offset + 1)
         }
     }
 }
 
-fn simple_instruction(name: &str, offset: usize) -> usize {
-    println!("{}", name);
-    offset + 1
+fn simple_instruction(out: &mut dyn Write, name: &str, offset: usize) -> io::Result<usize> {
+    writeln!(out, "{}", name)?;
+    Ok(offset + 1)
+}
+
+fn constant_instruction(out: &mut dyn Write, name: &str, chunk: &Chunk, offset: usize) -> io::Result<usize> {
+    let constant_index = chunk.code[offset + 1] as usize;
+    writeln!(
+        out,
+        "{:<16} {:4} '{}'",
+        name,
+        constant_index,
+        chunk.get_constant(constant_index)
+    )?;
+    Ok(offset + 2)
+}
+
+fn constant_long_instruction(out: &mut dyn Write, name: &str, chunk: &Chunk, offset: usize) -> io::Result<usize> {
+    let constant_index = (chunk.code[offset + 1] as usize) << 16
+        | (chunk.code[offset + 2] as usize) << 8
+        | chunk.code[offset + 3] as usize;
+    writeln!(
+        out,
+        "{:<16} {:4} '{}'",
+        name,
+        constant_index,
+        chunk.get_constant(constant_index)
+    )?;
+    Ok(offset + 4)
 }
 
-fn constant_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
+fn invoke_instruction(out: &mut dyn Write, name: &str, chunk: &Chunk, offset: usize) -> io::Result<usize> {
     let constant_index = chunk.code[offset + 1] as usize;
-    print!("{:<16} {:4} '", name, constant_index);
-    value::print_value(&chunk.get_constant(constant_index));
-    println!("'");
-    offset + 2
+    let arg_count = chunk.code[offset + 2];
+    writeln!(
+        out,
+        "{:<16} ({} args) {:4} '{}'",
+        name,
+        arg_count,
+        constant_index,
+        chunk.get_constant(constant_index)
+    )?;
+    Ok(offset + 3)
 }
 
-fn byte_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
+fn global_instruction(out: &mut dyn Write, name: &str, chunk: &Chunk, offset: usize) -> io::Result<usize> {
+    let index = (chunk.code[offset + 1] as usize) << 8 | chunk.code[offset + 2] as usize;
+    writeln!(out, "{:<16} {:4} '{}'", name, index, chunk.get_global(index).name)?;
+    Ok(offset + 3)
+}
+
+fn byte_instruction(out: &mut dyn Write, name: &str, chunk: &Chunk, offset: usize) -> io::Result<usize> {
     let slot = chunk.code[offset + 1];
-    println!("{:<16} {:4}", name, slot);
-    offset + 2
+    writeln!(out, "{:<16} {:4}", name, slot)?;
+    Ok(offset + 2)
+}
+
+fn two_byte_instruction(out: &mut dyn Write, name: &str, chunk: &Chunk, offset: usize) -> io::Result<usize> {
+    let first = chunk.code[offset + 1];
+    let second = chunk.code[offset + 2];
+    writeln!(out, "{:<16} {:4} {:4}", name, first, second)?;
+    Ok(offset + 3)
+}
+
+fn short_instruction(out: &mut dyn Write, name: &str, chunk: &Chunk, offset: usize) -> io::Result<usize> {
+    let slot = (chunk.code[offset + 1] as u16) << 8 | chunk.code[offset + 2] as u16;
+    writeln!(out, "{:<16} {:4}", name, slot)?;
+    Ok(offset + 3)
 }
 
-fn jump_instruction(name: &str, sign: i32, chunk: &Chunk, offset: usize) -> usize {
+fn jump_instruction(out: &mut dyn Write, name: &str, sign: i32, chunk: &Chunk, offset: usize) -> io::Result<usize> {
     let high = chunk.code[offset + 1] as u16;
     let low = chunk.code[offset + 2] as u16;
     let jump = (high << 8) | low;
@@ -75,6 +175,18 @@ fn jump_instruction(name: &str, sign: i32, chunk: &Chunk, offset: usize) -> usiz
     } else {
         offset + 3 - jump as usize
     };
-    println!("{:<16} {:4} -> {}", name, offset, target);
-    offset + 3
+    writeln!(out, "{:<16} {:4} -> {}", name, offset, target)?;
+    Ok(offset + 3)
+}
+
+fn long_jump_instruction(out: &mut dyn Write, name: &str, sign: i32, chunk: &Chunk, offset: usize) -> io::Result<usize> {
+    let bytes = chunk.code[offset + 1..offset + 5].try_into().unwrap();
+    let jump = u32::from_be_bytes(bytes) as usize;
+    let target = if sign == 1 {
+        offset + 5 + jump
+    } else {
+        offset + 5 - jump
+    };
+    writeln!(out, "{:<16} {:4} -> {}", name, offset, target)?;
+    Ok(offset + 5)
 }
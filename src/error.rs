@@ -0,0 +1,251 @@
+use std::fmt;
+
+/// Where in the source a compile error was detected.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Location {
+    /// At the given token
+    Token(String),
+    /// At the end of the input
+    End,
+    /// Inside a token the scanner couldn't make sense of
+    Scanner,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileError {
+    /// Line the offending token starts on
+    pub line: usize,
+    /// Line the offending token ends on, which is the one the message
+    /// reports, like clox's
+    pub end_line: usize,
+    pub column: usize,
+    pub location: Location,
+    pub message: String,
+}
+
+impl CompileError {
+    /// Formats the error followed by the offending source line, with the
+    /// token underlined.
+    pub fn render(&self, source: &str) -> String {
+        let width = match &self.location {
+            Location::Token(lexeme) => lexeme.chars().count(),
+            Location::End | Location::Scanner => 1,
+        };
+        match excerpt(source, self.line, self.column, width) {
+            Some(excerpt) => format!("{}\n{}", self, excerpt),
+            None => self.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[line {}] Error", self.end_line)?;
+        match &self.location {
+            Location::Token(lexeme) => write!(f, " at '{}'", lexeme)?,
+            Location::End => write!(f, " at end")?,
+            Location::Scanner => {}
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// A call that was active when a runtime error occurred.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    pub line: usize,
+    pub column: usize,
+    /// Name of the function, or None for the top-level script
+    pub function: Option<String>,
+    /// Path of the imported module running the call, or None for the main
+    /// script
+    pub module: Option<String>,
+}
+
+/// A resource limit set with [`VmOptions`](crate::VmOptions).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    StackDepth,
+    CallDepth,
+    Instructions,
+    Time,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub message: String,
+    /// Active calls, innermost first
+    pub trace: Vec<TraceFrame>,
+    /// The limit the script exceeded, if that's what stopped it. Scripts
+    /// can't catch these errors.
+    pub limit: Option<Limit>,
+}
+
+impl RuntimeError {
+    /// Line of the instruction that failed, if it's known.
+    pub fn line(&self) -> Option<usize> {
+        self.trace.first().map(|frame| frame.line)
+    }
+
+    /// Formats the error like Display, with the source line that failed
+    /// inserted before the stack trace.
+    pub fn render(&self, source: &str) -> String {
+        let mut rendered = self.message.clone();
+        // Only the main script's source is at hand
+        if let Some(frame) = self.trace.first()
+            && frame.module.is_none()
+            && let Some(excerpt) = excerpt(source, frame.line, frame.column, 1)
+        {
+            rendered.push('\n');
+            rendered.push_str(&excerpt);
+        }
+        for frame in self.trace.iter() {
+            rendered.push('\n');
+            rendered.push_str(&frame.to_string());
+        }
+        rendered
+    }
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.function {
+            Some(name) => write!(f, "[line {}] in {}()", self.line, name)?,
+            None => write!(f, "[line {}] in script", self.line)?,
+        }
+        match &self.module {
+            Some(module) => write!(f, " ({})", module),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        for frame in self.trace.iter() {
+            write!(f, "\n{}", frame)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Compile,
+    Runtime,
+    /// A runtime error caused by exceeding a limit
+    Limit,
+}
+
+/// Any error produced while running a script.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RloxError {
+    Compile(Vec<CompileError>),
+    Runtime(RuntimeError),
+}
+
+impl RloxError {
+    /// Creates a runtime error, e.g. for a host function to return. The VM
+    /// fills in where the error happened.
+    pub fn new(message: impl Into<String>) -> Self {
+        RloxError::Runtime(RuntimeError {
+            message: message.into(),
+            trace: Vec::new(),
+            limit: None,
+        })
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            RloxError::Compile(_) => ErrorKind::Compile,
+            RloxError::Runtime(error) if error.limit.is_some() => ErrorKind::Limit,
+            RloxError::Runtime(_) => ErrorKind::Runtime,
+        }
+    }
+
+    /// Line of the (first) error, if it's known.
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            RloxError::Compile(errors) => errors.first().map(|error| error.line),
+            RloxError::Runtime(error) => error.line(),
+        }
+    }
+
+    /// Formats the error with excerpts of `source`, the script it came from.
+    pub fn render(&self, source: &str) -> String {
+        match self {
+            RloxError::Compile(errors) => errors
+                .iter()
+                .map(|error| error.render(source))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            RloxError::Runtime(error) => error.render(source),
+        }
+    }
+
+    /// Message of the (first) error.
+    pub fn message(&self) -> &str {
+        match self {
+            RloxError::Compile(errors) => errors.first().map_or("", |error| &error.message),
+            RloxError::Runtime(error) => &error.message,
+        }
+    }
+}
+
+impl fmt::Display for RloxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RloxError::Compile(errors) => {
+                for (i, error) in errors.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}", error)?;
+                }
+                Ok(())
+            }
+            RloxError::Runtime(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+/// Quotes line `line` of `source` with a caret under `width` characters
+/// starting at `column`, or returns None if the line doesn't exist.
+fn excerpt(source: &str, line: usize, column: usize, width: usize) -> Option<String> {
+    let text = source.lines().nth(line.checked_sub(1)?)?;
+    let gutter = line.to_string().len();
+    // Keep tabs so the caret lines up however they're displayed
+    let indent: String = text
+        .chars()
+        .take(column.saturating_sub(1))
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+
+    Some(format!(
+        "{:>gutter$} | {}\n{:>gutter$} | {}{}",
+        line,
+        text,
+        "",
+        indent,
+        "^".repeat(width.max(1)),
+    ))
+}
+
+impl std::error::Error for CompileError {}
+
+impl std::error::Error for RuntimeError {}
+
+impl std::error::Error for RloxError {}
+
+impl From<Vec<CompileError>> for RloxError {
+    fn from(errors: Vec<CompileError>) -> Self {
+        RloxError::Compile(errors)
+    }
+}
+
+impl From<RuntimeError> for RloxError {
+    fn from(error: RuntimeError) -> Self {
+        RloxError::Runtime(error)
+    }
+}
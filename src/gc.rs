@@ -0,0 +1,260 @@
+//! Mark-sweep collection of the objects scripts can build cycles out of.
+//!
+//! Values are reference counted, which frees most objects as soon as the
+//! last reference goes away but leaks cycles, like a list that contains
+//! itself. The heap keeps a weak reference to every instance, list, map and
+//! bound method the VM allocates, and a collection frees the ones that are
+//! only reachable from each other.
+//!
+//! The roots are the objects referenced from outside the heap: from the
+//! stack, globals, constants or host code. Rather than enumerate those
+//! places, a collection subtracts the references objects hold to each other
+//! from their reference counts, and whatever has references left over must
+//! be held from outside. Everything reachable from a root is marked, and the
+//! unmarked objects are swept by clearing their contents, which breaks their
+//! cycles and lets reference counting free them.
+
+use crate::table::Table;
+use crate::value::{BoundMethod, Instance, Map, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::mem;
+use std::rc::{Rc, Weak};
+
+/// Bytes allocated before the first collection.
+const INITIAL_THRESHOLD: usize = 1024 * 1024;
+const GROW_FACTOR: usize = 2;
+
+/// Memory usage of a VM's heap, as estimated from the objects' sizes when
+/// they were allocated.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct HeapStats {
+    pub live_objects: usize,
+    pub bytes_allocated: usize,
+    pub bytes_since_gc: usize,
+    pub collections: u64,
+}
+
+/// Callback run after each collection, e.g. to export the heap's size.
+pub type GcHook = dyn FnMut(&HeapStats);
+
+enum Object {
+    Instance(Weak<RefCell<Instance>>),
+    List(Weak<RefCell<Vec<Value>>>),
+    Map(Weak<RefCell<Map>>),
+    BoundMethod(Weak<BoundMethod>),
+}
+
+impl Object {
+    fn track(value: &Value) -> Option<Self> {
+        match value {
+            Value::Instance(instance) => Some(Object::Instance(Rc::downgrade(instance))),
+            Value::List(list) => Some(Object::List(Rc::downgrade(list))),
+            Value::Map(map) => Some(Object::Map(Rc::downgrade(map))),
+            Value::BoundMethod(bound) => Some(Object::BoundMethod(Rc::downgrade(bound))),
+            _ => None,
+        }
+    }
+
+    fn upgrade(&self) -> Option<Value> {
+        match self {
+            Object::Instance(weak) => weak.upgrade().map(Value::Instance),
+            Object::List(weak) => weak.upgrade().map(Value::List),
+            Object::Map(weak) => weak.upgrade().map(Value::Map),
+            Object::BoundMethod(weak) => weak.upgrade().map(Value::BoundMethod),
+        }
+    }
+
+    fn is_live(&self) -> bool {
+        match self {
+            Object::Instance(weak) => weak.strong_count() > 0,
+            Object::List(weak) => weak.strong_count() > 0,
+            Object::Map(weak) => weak.strong_count() > 0,
+            Object::BoundMethod(weak) => weak.strong_count() > 0,
+        }
+    }
+}
+
+pub(crate) struct Heap {
+    objects: Vec<(Object, usize)>,
+    bytes_allocated: usize,
+    bytes_since_gc: usize,
+    next_gc: usize,
+    collections: u64,
+    /// Collect on every allocation, to flush out objects that aren't
+    /// reachable when they should be
+    pub stress: bool,
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Heap {
+            objects: Vec::new(),
+            bytes_allocated: 0,
+            bytes_since_gc: 0,
+            next_gc: INITIAL_THRESHOLD,
+            collections: 0,
+            stress: false,
+        }
+    }
+
+    /// Starts tracking `value` if it's an object that can be part of a
+    /// cycle. Returns whether it's time to collect.
+    pub fn track(&mut self, value: &Value) -> bool {
+        let Some(object) = Object::track(value) else {
+            return false;
+        };
+        let size = size_of(value);
+        self.objects.push((object, size));
+        self.bytes_allocated += size;
+        self.bytes_since_gc += size;
+        self.stress || self.bytes_allocated > self.next_gc
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            live_objects: self
+                .objects
+                .iter()
+                .filter(|(object, _)| object.is_live())
+                .count(),
+            bytes_allocated: self.bytes_allocated,
+            bytes_since_gc: self.bytes_since_gc,
+            collections: self.collections,
+        }
+    }
+
+    pub fn collect(&mut self) {
+        // An object can be tracked twice, e.g. when a native returns a list
+        // it was passed
+        let mut live = Vec::new();
+        let mut sizes = Vec::new();
+        let mut index: HashMap<*const (), usize> = HashMap::new();
+        for (object, size) in mem::take(&mut self.objects) {
+            if let Some(value) = object.upgrade()
+                && !index.contains_key(&address(&value))
+            {
+                index.insert(address(&value), live.len());
+                live.push(value);
+                sizes.push(size);
+            }
+        }
+
+        // References from outside the heap, less the one `live` holds
+        let mut external: Vec<usize> = live.iter().map(|value| strong_count(value) - 1).collect();
+        // The children of an object that is being modified can't be seen,
+        // so they stay counted as external and survive
+        for value in live.iter() {
+            for_each_child(value, |child| {
+                if let Some(&i) = index.get(&address(child)) {
+                    external[i] -= 1;
+                }
+            });
+        }
+
+        let mut marked = vec![false; live.len()];
+        let mut gray: Vec<usize> = (0..live.len()).filter(|&i| external[i] > 0).collect();
+        for &i in gray.iter() {
+            marked[i] = true;
+        }
+        while let Some(i) = gray.pop() {
+            for_each_child(&live[i], |child| {
+                if let Some(&j) = index.get(&address(child))
+                    && !marked[j]
+                {
+                    marked[j] = true;
+                    gray.push(j);
+                }
+            });
+        }
+
+        for (i, value) in live.iter().enumerate() {
+            if marked[i] {
+                self.objects.push((Object::track(value).unwrap(), sizes[i]));
+            } else {
+                clear(value);
+            }
+        }
+
+        self.bytes_allocated = self.objects.iter().map(|(_, size)| size).sum();
+        self.bytes_since_gc = 0;
+        self.next_gc = (self.bytes_allocated * GROW_FACTOR).max(INITIAL_THRESHOLD);
+        self.collections += 1;
+        // Dropping `live` frees the objects cleared above
+    }
+}
+
+fn address(value: &Value) -> *const () {
+    match value {
+        Value::Instance(instance) => Rc::as_ptr(instance) as *const (),
+        Value::List(list) => Rc::as_ptr(list) as *const (),
+        Value::Map(map) => Rc::as_ptr(map) as *const (),
+        Value::BoundMethod(bound) => Rc::as_ptr(bound) as *const (),
+        _ => std::ptr::null(),
+    }
+}
+
+fn strong_count(value: &Value) -> usize {
+    match value {
+        Value::Instance(instance) => Rc::strong_count(instance),
+        Value::List(list) => Rc::strong_count(list),
+        Value::Map(map) => Rc::strong_count(map),
+        Value::BoundMethod(bound) => Rc::strong_count(bound),
+        _ => 0,
+    }
+}
+
+fn size_of(value: &Value) -> usize {
+    let value_size = mem::size_of::<Value>();
+    match value {
+        Value::Instance(instance) => {
+            mem::size_of::<RefCell<Instance>>()
+                + instance.borrow().fields.iter().count() * value_size * 2
+        }
+        Value::List(list) => {
+            mem::size_of::<RefCell<Vec<Value>>>() + list.borrow().len() * value_size
+        }
+        Value::Map(map) => {
+            mem::size_of::<RefCell<Map>>() + map.borrow().iter().count() * value_size * 2
+        }
+        Value::BoundMethod(_) => mem::size_of::<BoundMethod>(),
+        _ => 0,
+    }
+}
+
+/// Calls `visit` with each value an object refers to, unless the object is
+/// borrowed mutably.
+fn for_each_child(value: &Value, mut visit: impl FnMut(&Value)) {
+    match value {
+        Value::Instance(instance) => {
+            let Ok(instance) = instance.try_borrow() else {
+                return;
+            };
+            instance.fields.iter().for_each(|(_, field)| visit(field));
+        }
+        Value::List(list) => {
+            let Ok(list) = list.try_borrow() else {
+                return;
+            };
+            list.iter().for_each(visit);
+        }
+        Value::Map(map) => {
+            let Ok(map) = map.try_borrow() else {
+                return;
+            };
+            map.iter().for_each(|(_, value)| visit(value));
+        }
+        Value::BoundMethod(bound) => visit(&bound.receiver),
+        _ => {}
+    }
+}
+
+/// Drops an unreachable object's references to other objects.
+fn clear(value: &Value) {
+    match value {
+        Value::Instance(instance) => instance.borrow_mut().fields = Table::new(),
+        Value::List(list) => list.borrow_mut().clear(),
+        Value::Map(map) => *map.borrow_mut() = Map::new(),
+        _ => {}
+    }
+}
@@ -0,0 +1,41 @@
+//! A bytecode virtual machine for Lox, following the clox interpreter from
+//! Crafting Interpreters.
+//!
+//! Scripts can be run in one go with [`interpret`], or compiled with
+//! [`compile`] and run on a [`Vm`] that the host has set up with its own
+//! globals and functions.
+
+mod chunk;
+mod compiler;
+mod debug;
+mod error;
+mod gc;
+#[cfg(feature = "nan-boxing")]
+mod nanbox;
+mod natives;
+#[cfg(test)]
+mod program_gen;
+mod scanner;
+mod table;
+mod value;
+mod verify;
+mod vm;
+
+pub use chunk::{Chunk, FORMAT_VERSION, LoadError};
+pub use compiler::{CompileOptions, compile, compile_with};
+pub use debug::disassemble_program;
+pub use error::{CompileError, ErrorKind, Limit, Location, RloxError, RuntimeError, TraceFrame};
+pub use gc::HeapStats;
+#[cfg(feature = "nan-boxing")]
+pub use nanbox::NanBoxed;
+pub use scanner::{Token, TokenType, is_incomplete, scan};
+pub use value::{Value, write_value};
+pub use vm::{ExecutionStats, Feature, VM as Vm, VmOptions};
+
+/// Compiles and runs `source` on a fresh VM.
+pub fn interpret(source: &str) -> Result<(), RloxError> {
+    let mut vm = Vm::new();
+    let chunk = compile(source, &mut vm)?;
+    vm.interpret(chunk)?;
+    Ok(())
+}
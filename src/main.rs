@@ -1,63 +1,582 @@
-use crate::vm::{InterpretResult, VM};
-use std::io::Write;
-use std::{env, fs, io, process};
-
-mod chunk;
-mod compiler;
-mod debug;
-mod scanner;
-mod table;
-mod value;
-mod vm;
+use rlox::{Chunk, CompileOptions, ExecutionStats, HeapStats, RloxError, RuntimeError, Vm};
+use rustyline::DefaultEditor;
+use rustyline::error::ReadlineError;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::{env, fs, process};
+
+mod cache;
+mod session;
+
+use session::{Entry, Session};
+
+struct Options {
+    stats: bool,
+    profile: bool,
+    check: bool,
+    tokens: bool,
+    json: bool,
+    seed: Option<u64>,
+    compat: bool,
+    cache: bool,
+    disassemble: bool,
+    trace: bool,
+    optimize: bool,
+    gc_stress: bool,
+}
+
+impl Options {
+    fn compile_options(&self, repl: bool) -> CompileOptions {
+        CompileOptions {
+            repl,
+            optimize: self.optimize,
+        }
+    }
+}
 
 fn main() {
-    let mut vm = VM::new();
-    let args: Vec<String> = env::args().collect();
+    let mut vm = Vm::new();
+    let mut options = Options {
+        stats: false,
+        profile: false,
+        check: false,
+        tokens: false,
+        json: false,
+        seed: None,
+        compat: false,
+        cache: true,
+        disassemble: false,
+        trace: false,
+        optimize: false,
+        gc_stress: false,
+    };
+    let mut paths = Vec::new();
+    let mut output = None;
+    let mut eval = None;
+    let mut session = None;
+    let mut clear = false;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--stats" => options.stats = true,
+            "--profile" => options.profile = true,
+            "--check" => options.check = true,
+            "--tokens" => options.tokens = true,
+            "--json" => options.json = true,
+            "--deterministic" => options.seed = options.seed.or(Some(0)),
+            "--seed" => options.seed = Some(parse_seed(args.next())),
+            "--compat" => options.compat = true,
+            "--no-cache" => options.cache = false,
+            "--clear-cache" => clear = true,
+            "--disassemble" | "-d" => options.disassemble = true,
+            "--trace" => options.trace = true,
+            "-O" => options.optimize = true,
+            "--gc-stress" => options.gc_stress = true,
+            "-o" => output = Some(args.next().unwrap_or_else(|| usage())),
+            "-e" => eval = Some(args.next().unwrap_or_else(|| usage())),
+            "--session" => session = Some(args.next().unwrap_or_else(|| usage())),
+            _ => paths.push(arg),
+        }
+    }
+
+    vm.set_deterministic(options.seed);
+    vm.set_trace(options.trace);
+    vm.set_gc_stress(options.gc_stress);
+
+    if clear {
+        if eval.is_some() || !paths.is_empty() || output.is_some() || session.is_some() {
+            usage();
+        }
+        clear_cache();
+        return;
+    }
+
+    if let Some(code) = eval {
+        if !paths.is_empty() || output.is_some() {
+            usage();
+        }
+        run_source("<eval>", &code, &mut vm, &options);
+        return;
+    }
+
+    let paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+    match (paths.as_slice(), output.as_deref()) {
+        ([], None) => repl(&mut vm, &options, session.as_deref()),
+        _ if session.is_some() => usage(),
+        (["-"], None) => run_stdin(&mut vm, &options),
+        (["compile", path], output) => compile_file(path, output, &mut vm, &options),
+        (["run", path], None) => run_bytecode(path, &mut vm, &options),
+        ([path], None) => run_file(path, &mut vm, &options),
+        _ => usage(),
+    }
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "Usage: rlox [--stats] [--profile] [--check] [--tokens [--json]] [--deterministic | --seed <n>] [--compat] [--no-cache] [--disassemble] [--trace] [-O] [--gc-stress] [path | - | -e <code>]"
+    );
+    eprintln!("       rlox --session <path>");
+    eprintln!("       rlox compile <path> [-o <output>]");
+    eprintln!("       rlox run <bytecode path>");
+    eprintln!("       rlox --clear-cache");
+    process::exit(64);
+}
+
+fn parse_seed(arg: Option<String>) -> u64 {
+    arg.and_then(|seed| seed.parse().ok())
+        .unwrap_or_else(|| usage())
+}
+
+fn clear_cache() {
+    if let Err(error) = cache::clear() {
+        eprintln!("Failed to clear the bytecode cache: {}", error);
+        process::exit(74);
+    }
+}
+
+fn run_file(path: &str, vm: &mut Vm, options: &Options) {
+    let source = read_file(path);
+    if options.tokens {
+        print_tokens(&source, options);
+        return;
+    }
+    vm.set_script_path(path);
+
+    // Checking a file shouldn't leave bytecode behind for every version of it
+    let use_cache = options.cache && !options.check;
+    let cached = if use_cache {
+        cache::load(&source, options.optimize, vm)
+    } else {
+        None
+    };
 
-    match args.len() {
-        1 => repl(&mut vm),
-        2 => run_file(&args[1], &mut vm),
-        _ => {
-            eprintln!("Usage: rlox [path]");
-            process::exit(64);
+    let chunk = match cached {
+        Some(chunk) => chunk,
+        None => {
+            let chunk = compile_or_exit(&source, vm, options);
+            if use_cache {
+                cache::store(&source, options.optimize, &chunk);
+            }
+            chunk
+        }
+    };
+
+    execute(chunk, path, &source, vm, options);
+}
+
+/// Runs the program on standard input, as `rlox -` does.
+fn run_stdin(vm: &mut Vm, options: &Options) {
+    let mut source = String::new();
+    if let Err(error) = io::stdin().read_to_string(&mut source) {
+        eprintln!("Failed to read standard input: {}", error);
+        process::exit(74);
+    }
+    run_source("<stdin>", &source, vm, options);
+}
+
+/// Runs source that doesn't come from a file, so it isn't cached and its
+/// imports are relative to the working directory.
+fn run_source(name: &str, source: &str, vm: &mut Vm, options: &Options) {
+    if options.tokens {
+        print_tokens(source, options);
+        return;
+    }
+    let chunk = compile_or_exit(source, vm, options);
+    execute(chunk, name, source, vm, options);
+}
+
+fn compile_or_exit(source: &str, vm: &mut Vm, options: &Options) -> Chunk {
+    match rlox::compile_with(source, vm, options.compile_options(false)) {
+        Ok(chunk) => chunk,
+        Err(errors) => {
+            report(&RloxError::Compile(errors), source, options);
+            process::exit(65);
         }
     }
 }
 
-fn run_file(path: &str, vm: &mut VM) {
+/// Compiles the script at `path` to a bytecode file, by default next to it
+/// with the `.rloxc` extension.
+fn compile_file(path: &str, output: Option<&str>, vm: &mut Vm, options: &Options) {
     let source = read_file(path);
-    let result = interpret(&source, vm);
-    match result {
-        InterpretResult::CompileError => process::exit(65),
-        InterpretResult::RuntimeError => process::exit(70),
-        _ => {}
+    let chunk = compile_or_exit(&source, vm, options);
+
+    let output = match output {
+        Some(output) => PathBuf::from(output),
+        None => Path::new(path).with_extension("rloxc"),
+    };
+    if let Err(error) = fs::write(&output, chunk.serialize()) {
+        eprintln!("Failed to write '{}': {}", output.display(), error);
+        process::exit(74);
+    }
+}
+
+fn run_bytecode(path: &str, vm: &mut Vm, options: &Options) {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(error) => {
+            eprintln!("Failed to read '{}': {}", path, error);
+            process::exit(74);
+        }
+    };
+    let chunk = match vm.load_chunk(&bytes) {
+        Ok(chunk) => chunk,
+        Err(error) => {
+            eprintln!("Failed to load '{}': {}", path, error);
+            process::exit(65);
+        }
+    };
+
+    // There's no source to quote in errors
+    vm.set_script_path(path);
+    execute(chunk, path, "", vm, options);
+}
+
+fn execute(chunk: Chunk, name: &str, source: &str, vm: &mut Vm, options: &Options) {
+    // The code compiled, which is all --check wants to know
+    if options.check {
+        return;
+    }
+    if options.disassemble {
+        if let Err(error) = rlox::disassemble_program(&mut io::stdout(), &chunk, name) {
+            eprintln!("Failed to write disassembly: {}", error);
+            process::exit(74);
+        }
+        return;
+    }
+
+    if let Err(error) = run(chunk, source, vm, options) {
+        report(&error.into(), source, options);
+        process::exit(70);
+    }
+}
+
+fn report(error: &RloxError, source: &str, options: &Options) {
+    // Tools comparing against clox expect its exact, excerpt-free output
+    if options.compat {
+        eprintln!("{}", error);
+    } else {
+        eprintln!("{}", error.render(source));
+    }
+}
+
+fn interpret(source: &str, vm: &mut Vm, options: &Options) -> Result<(), RloxError> {
+    let chunk = rlox::compile_with(source, vm, options.compile_options(true))?;
+    run(chunk, source, vm, options)?;
+    Ok(())
+}
+
+fn run(chunk: Chunk, source: &str, vm: &mut Vm, options: &Options) -> Result<(), RuntimeError> {
+    if !options.stats && !options.profile {
+        return vm.interpret(chunk);
     }
+
+    let (result, stats) = vm.interpret_with_stats(chunk);
+    if options.stats {
+        print_stats(&stats, &vm.heap_stats());
+    }
+    if options.profile {
+        print_profile(&stats, source);
+    }
+    result
+}
+
+fn print_stats(stats: &ExecutionStats, heap: &HeapStats) {
+    eprintln!("== execution stats ==");
+    eprintln!("instructions executed: {}", stats.instructions_executed());
+    eprintln!("peak stack depth:      {}", stats.peak_stack_depth);
+    eprintln!("allocations:           {}", stats.allocations);
+    eprintln!("elapsed:               {:?}", stats.elapsed);
+    eprintln!("live objects:          {}", heap.live_objects);
+    eprintln!("garbage collections:   {}", heap.collections);
+    for (opcode, count) in stats.executed_opcodes() {
+        eprintln!("  {:<16} {}", format!("{:?}", opcode), count);
+    }
+}
+
+// Lines listed in the profile
+const HOTTEST_LINES: usize = 10;
+
+/// Prints where the script spent its instructions: per opcode, and on the
+/// lines that ran the most, quoted from `source` when they're in it.
+fn print_profile(stats: &ExecutionStats, source: &str) {
+    let total = stats.instructions_executed().max(1) as f64;
+    let percent = |count: u64| 100.0 * count as f64 / total;
+
+    eprintln!("== profile ==");
+    eprintln!(
+        "{} instructions in {:?}",
+        stats.instructions_executed(),
+        stats.elapsed
+    );
+
+    let mut opcodes: Vec<_> = stats.executed_opcodes().collect();
+    opcodes.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+    eprintln!();
+    eprintln!("{:<20} {:>12} {:>7}", "opcode", "count", "%");
+    for (opcode, count) in opcodes {
+        let name = format!("{:?}", opcode);
+        eprintln!("{:<20} {:>12} {:>6.2}%", name, count, percent(count));
+    }
+
+    let lines: Vec<&str> = source.lines().collect();
+    eprintln!();
+    eprintln!("{:<20} {:>12} {:>7}", "line", "count", "%");
+    for (module, line, count) in stats.hottest_lines().into_iter().take(HOTTEST_LINES) {
+        let (location, text) = match module {
+            Some(module) => (format!("{}:{}", module, line), ""),
+            None => (
+                line.to_string(),
+                lines.get(line.wrapping_sub(1)).copied().unwrap_or(""),
+            ),
+        };
+        eprintln!(
+            "{:<20} {:>12} {:>6.2}%  | {}",
+            location,
+            count,
+            percent(count),
+            text.trim()
+        );
+    }
+}
+
+/// Prints the tokens of `source` instead of running it, one per line, or as
+/// a JSON array of objects with `--json`.
+fn print_tokens(source: &str, options: &Options) {
+    let tokens = rlox::scan(source);
+    if !options.json {
+        for token in tokens {
+            let position = format!("{}:{}", token.line, token.column);
+            let name = format!("{:?}", token.token_type);
+            println!("{:>8}  {:<14} {}", position, name, token.lexeme);
+        }
+        return;
+    }
+
+    println!("[");
+    for (i, token) in tokens.iter().enumerate() {
+        let separator = if i + 1 < tokens.len() { "," } else { "" };
+        println!(
+            "  {{\"type\": \"{:?}\", \"lexeme\": {}, \"line\": {}, \"end_line\": {}, \"column\": {}, \"start\": {}, \"end\": {}}}{}",
+            token.token_type,
+            json_string(token.lexeme),
+            token.line,
+            token.end_line,
+            token.column,
+            token.start,
+            token.end,
+            separator
+        );
+    }
+    println!("]");
 }
 
-fn interpret(source: &str, vm: &mut VM) -> InterpretResult {
-    match compiler::compile(source, vm) {
-        Some(chunk) => vm.interpret(chunk),
-        None => InterpretResult::CompileError,
+fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
     }
+    quoted.push('"');
+    quoted
 }
 
 fn read_file(path: &str) -> String {
-    fs::read_to_string(path).expect("Failed to read file")
+    match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(error) => {
+            eprintln!("Failed to read '{}': {}", path, error);
+            process::exit(74);
+        }
+    }
 }
 
-fn repl(vm: &mut VM) {
-    let stdin = io::stdin();
+/// Runs the REPL. With a session path, the session saved there is restored
+/// first, and every input is saved to it.
+fn repl(vm: &mut Vm, options: &Options, session_path: Option<&str>) {
+    // Ctrl-C aborts the running script instead of killing the session
+    let interrupt = vm.interrupt_handle();
+    ctrlc::set_handler(move || interrupt.store(true, Ordering::Relaxed))
+        .expect("Failed to install Ctrl-C handler");
+
+    let mut session = Session::new(session_path.map(PathBuf::from));
+    if let Some(path) = session_path.map(Path::new)
+        && path.exists()
+    {
+        restore_session(path, vm, options, &mut session);
+    }
+
+    let mut editor = DefaultEditor::new().expect("Failed to initialize the line editor");
+    let mut source = String::new();
     loop {
-        print!("> ");
-        io::stdout().flush().unwrap();
+        let prompt = if source.is_empty() { "> " } else { "... " };
+        match editor.readline(prompt) {
+            Ok(line) if source.is_empty() && line.starts_with(':') => {
+                let _ = editor.add_history_entry(line.as_str());
+                if !run_command(&line, vm, options, &mut session) {
+                    break;
+                }
+            }
+            Ok(line) => {
+                source.push_str(&line);
+                source.push('\n');
+                if rlox::is_incomplete(&source) {
+                    continue;
+                }
 
-        let mut line = String::new();
-        match stdin.read_line(&mut line) {
-            Ok(0) => break, // EOF
-            Ok(_) => {
-                interpret(&line, vm);
+                let _ = editor.add_history_entry(source.trim_end());
+                let result = interpret(&source, vm, options);
+                if let Err(error) = &result {
+                    report(error, &source, options);
+                }
+                if compiled(&result) {
+                    session.record_input(&source);
+                    autosave(&session);
+                }
+                source.clear();
             }
-            Err(_) => break,
-        };
+            // Ctrl-C at the prompt discards the unfinished input
+            Err(ReadlineError::Interrupted) => source.clear(),
+            Err(_) => break, // EOF
+        }
+    }
+}
+
+/// Runs a REPL meta-command, returning false if the session should end.
+fn run_command(line: &str, vm: &mut Vm, options: &Options, session: &mut Session) -> bool {
+    let (command, argument) = line.split_once(' ').unwrap_or((line, ""));
+    let argument = argument.trim();
+
+    match command {
+        ":globals" => print_globals(vm),
+        ":disasm" => match rlox::compile_with(argument, vm, options.compile_options(true)) {
+            Ok(chunk) => {
+                if let Err(error) = rlox::disassemble_program(&mut io::stdout(), &chunk, argument) {
+                    eprintln!("Failed to write disassembly: {}", error);
+                }
+            }
+            Err(errors) => report(&RloxError::Compile(errors), argument, options),
+        },
+        ":load" => {
+            if load(argument, vm, options) {
+                session.record_load(argument);
+                autosave(session);
+            }
+        }
+        ":save" => {
+            if let Err(error) = session.save(Path::new(argument)) {
+                eprintln!("Failed to write '{}': {}", argument, error);
+            }
+        }
+        ":load-session" => {
+            restore_session(Path::new(argument), vm, options, session);
+            autosave(session);
+        }
+        ":quit" => return false,
+        _ => eprintln!(
+            "Unknown command '{}'. Commands are :globals, :disasm <code>, :load <path>, :save <path>, :load-session <path> and :quit.",
+            command
+        ),
+    }
+    true
+}
+
+fn print_globals(vm: &Vm) {
+    let mut globals: Vec<_> = vm.globals().collect();
+    globals.sort_by_key(|(name, _)| *name);
+
+    for (name, value) in globals {
+        println!("{} = {}", name, value);
+    }
+}
+
+/// Runs the script at `path` in the session, returning whether it compiled.
+fn load(path: &str, vm: &mut Vm, options: &Options) -> bool {
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(error) => {
+            eprintln!("Failed to read '{}': {}", path, error);
+            return false;
+        }
+    };
+
+    let result = run_script(&source, vm, options);
+    if let Err(error) = &result {
+        report(error, &source, options);
+    }
+    compiled(&result)
+}
+
+fn run_script(source: &str, vm: &mut Vm, options: &Options) -> Result<(), RloxError> {
+    let chunk = rlox::compile_with(source, vm, options.compile_options(false))?;
+    run(chunk, source, vm, options)?;
+    Ok(())
+}
+
+/// Whether the code that produced `result` compiled, and so ran.
+fn compiled(result: &Result<(), RloxError>) -> bool {
+    !matches!(result, Err(RloxError::Compile(_)))
+}
+
+/// Replays the session saved at `path`, adding it to `session`. What it
+/// printed and the errors it hit were already seen when it was typed, so
+/// they're not shown again.
+fn restore_session(path: &Path, vm: &mut Vm, options: &Options, session: &mut Session) {
+    let transcript = match fs::read_to_string(path) {
+        Ok(transcript) => transcript,
+        Err(error) => {
+            eprintln!("Failed to read '{}': {}", path.display(), error);
+            return;
+        }
+    };
+
+    // Replaying shouldn't repeat the session's I/O, like reading input or
+    // rewriting files, so it runs without the I/O natives. Imports still
+    // work, since they only read modules. Entries that no longer compile
+    // stay in the transcript, to be replayed by a later restore.
+    vm.set_io_natives(false);
+    vm.set_output(io::sink());
+    let entries = session::entries(&transcript);
+    let mut restored = 0;
+    for entry in entries.iter() {
+        match entry {
+            Entry::Input(source) => {
+                if compiled(&interpret(source, vm, options)) {
+                    restored += 1;
+                }
+                session.record_input(source);
+            }
+            Entry::Load(script) => {
+                if let Ok(source) = fs::read_to_string(script)
+                    && compiled(&run_script(&source, vm, options))
+                {
+                    restored += 1;
+                }
+                session.record_load(script);
+            }
+        }
+    }
+    vm.set_output(io::stdout());
+    vm.set_io_natives(true);
+    eprintln!(
+        "Restored {} of {} inputs from '{}'.",
+        restored,
+        entries.len(),
+        path.display()
+    );
+}
+
+fn autosave(session: &Session) {
+    if let Err(error) = session.autosave() {
+        eprintln!("Failed to save the session: {}", error);
     }
 }
@@ -0,0 +1,351 @@
+//! NaN-boxed values, as in chapter 30 of Crafting Interpreters.
+//!
+//! A [`NanBoxed`] packs a [`Value`] into 64 bits. Numbers are stored as
+//! themselves. Everything else lives in the payload of a quiet NaN, which no
+//! arithmetic produces: nil and the booleans as small tags, and heap objects
+//! as their `Rc` pointer with the sign bit set. Pointers are 8-byte aligned,
+//! so their three low bits are free to say which kind of object they point
+//! to.
+//!
+//! With the `nan-boxing` feature the VM keeps its stack and its chunks'
+//! constants in this form. Globals, fields, elements and the arguments
+//! natives see are still [`Value`]s, converted at the boundary, which is
+//! cheap as only reference counts change.
+
+use crate::value::{BoundMethod, Class, Function, Instance, LoxString, Map, Native, Value};
+use std::cell::RefCell;
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
+
+#[cfg(not(target_pointer_width = "64"))]
+compile_error!("NaN boxing needs 64-bit pointers");
+
+const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+
+const TAG_NIL: u64 = 1;
+const TAG_FALSE: u64 = 2;
+const TAG_TRUE: u64 = 3;
+
+const NIL: u64 = QNAN | TAG_NIL;
+const FALSE: u64 = QNAN | TAG_FALSE;
+const TRUE: u64 = QNAN | TAG_TRUE;
+
+// Object kinds, kept in the pointer's low bits
+const OBJ_STRING: u64 = 0;
+const OBJ_FUNCTION: u64 = 1;
+const OBJ_CLASS: u64 = 2;
+const OBJ_INSTANCE: u64 = 3;
+const OBJ_BOUND_METHOD: u64 = 4;
+const OBJ_NATIVE: u64 = 5;
+const OBJ_LIST: u64 = 6;
+const OBJ_MAP: u64 = 7;
+const OBJ_MASK: u64 = 7;
+
+/// A value in 8 bytes, half the size of [`Value`]. Cloning and dropping a
+/// boxed object updates its reference count like the `Rc` it came from.
+pub struct NanBoxed(u64);
+
+impl NanBoxed {
+    pub fn number(n: f64) -> Self {
+        NanBoxed(n.to_bits())
+    }
+
+    pub fn nil() -> Self {
+        NanBoxed(NIL)
+    }
+
+    pub fn bool(b: bool) -> Self {
+        NanBoxed(if b { TRUE } else { FALSE })
+    }
+
+    pub fn is_number(&self) -> bool {
+        self.0 & QNAN != QNAN
+    }
+
+    pub fn is_nil(&self) -> bool {
+        self.0 == NIL
+    }
+
+    pub fn is_bool(&self) -> bool {
+        self.0 | 1 == TRUE
+    }
+
+    pub fn is_string(&self) -> bool {
+        self.is_object() && self.kind() == OBJ_STRING
+    }
+
+    fn is_object(&self) -> bool {
+        self.0 & (QNAN | SIGN_BIT) == QNAN | SIGN_BIT
+    }
+
+    /// Returns the number, or None if the value is something else.
+    pub fn as_number(&self) -> Option<f64> {
+        self.is_number().then(|| f64::from_bits(self.0))
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        self.is_bool().then_some(self.0 == TRUE)
+    }
+
+    pub fn is_falsey(&self) -> bool {
+        self.0 == NIL || self.0 == FALSE
+    }
+
+    /// Returns a copy of the boxed value, sharing its object if it has one.
+    pub fn to_value(&self) -> Value {
+        let copy = self.clone();
+        copy.into()
+    }
+
+    fn object(rc_ptr: *const (), kind: u64) -> Self {
+        let address = rc_ptr as u64;
+        debug_assert_eq!(address & !0x0000_ffff_ffff_fff8, 0, "pointer doesn't fit");
+        NanBoxed(SIGN_BIT | QNAN | address | kind)
+    }
+
+    fn kind(&self) -> u64 {
+        self.0 & OBJ_MASK
+    }
+
+    fn pointer(&self) -> *const () {
+        (self.0 & !(SIGN_BIT | QNAN | OBJ_MASK)) as *const ()
+    }
+}
+
+// SAFETY: for all three, `pointer` must come from `Rc::into_raw` on an
+// `Rc<T>` of the same `T`, whose reference the caller owns.
+unsafe fn increment<T>(pointer: *const ()) {
+    unsafe { Rc::increment_strong_count(pointer as *const T) }
+}
+
+unsafe fn decrement<T>(pointer: *const ()) {
+    unsafe { Rc::decrement_strong_count(pointer as *const T) }
+}
+
+unsafe fn take<T>(pointer: *const ()) -> Rc<T> {
+    unsafe { Rc::from_raw(pointer as *const T) }
+}
+
+impl From<Value> for NanBoxed {
+    fn from(value: Value) -> Self {
+        // The value's reference moves to the boxed value, so the value
+        // mustn't drop it
+        let value = mem::ManuallyDrop::new(value);
+        match &*value {
+            Value::Nil => NanBoxed::nil(),
+            Value::Bool(b) => NanBoxed::bool(*b),
+            Value::Number(n) => NanBoxed::number(*n),
+            Value::String(s) => NanBoxed::object(Rc::as_ptr(s) as *const (), OBJ_STRING),
+            Value::Function(f) => NanBoxed::object(Rc::as_ptr(f) as *const (), OBJ_FUNCTION),
+            Value::Class(c) => NanBoxed::object(Rc::as_ptr(c) as *const (), OBJ_CLASS),
+            Value::Instance(i) => NanBoxed::object(Rc::as_ptr(i) as *const (), OBJ_INSTANCE),
+            Value::BoundMethod(b) => NanBoxed::object(Rc::as_ptr(b) as *const (), OBJ_BOUND_METHOD),
+            Value::Native(n) => NanBoxed::object(Rc::as_ptr(n) as *const (), OBJ_NATIVE),
+            Value::List(l) => NanBoxed::object(Rc::as_ptr(l) as *const (), OBJ_LIST),
+            Value::Map(m) => NanBoxed::object(Rc::as_ptr(m) as *const (), OBJ_MAP),
+        }
+    }
+}
+
+impl From<NanBoxed> for Value {
+    fn from(boxed: NanBoxed) -> Self {
+        if boxed.is_number() {
+            return Value::Number(f64::from_bits(boxed.0));
+        }
+        if boxed.is_nil() {
+            return Value::Nil;
+        }
+        if boxed.is_bool() {
+            return Value::Bool(boxed.0 == TRUE);
+        }
+
+        let pointer = boxed.pointer();
+        let kind = boxed.kind();
+        // The value's reference moves to the Rc
+        mem::forget(boxed);
+        // SAFETY: the kind was set from the Rc the pointer came from
+        unsafe {
+            match kind {
+                OBJ_STRING => Value::String(take::<LoxString>(pointer)),
+                OBJ_FUNCTION => Value::Function(take::<Function>(pointer)),
+                OBJ_CLASS => Value::Class(take::<Class>(pointer)),
+                OBJ_INSTANCE => Value::Instance(take::<RefCell<Instance>>(pointer)),
+                OBJ_BOUND_METHOD => Value::BoundMethod(take::<BoundMethod>(pointer)),
+                OBJ_NATIVE => Value::Native(take::<Native>(pointer)),
+                OBJ_LIST => Value::List(take::<RefCell<Vec<Value>>>(pointer)),
+                _ => Value::Map(take::<RefCell<Map>>(pointer)),
+            }
+        }
+    }
+}
+
+impl Clone for NanBoxed {
+    fn clone(&self) -> Self {
+        if self.is_object() {
+            let pointer = self.pointer();
+            // SAFETY: the kind was set from the Rc the pointer came from
+            unsafe {
+                match self.kind() {
+                    OBJ_STRING => increment::<LoxString>(pointer),
+                    OBJ_FUNCTION => increment::<Function>(pointer),
+                    OBJ_CLASS => increment::<Class>(pointer),
+                    OBJ_INSTANCE => increment::<RefCell<Instance>>(pointer),
+                    OBJ_BOUND_METHOD => increment::<BoundMethod>(pointer),
+                    OBJ_NATIVE => increment::<Native>(pointer),
+                    OBJ_LIST => increment::<RefCell<Vec<Value>>>(pointer),
+                    _ => increment::<RefCell<Map>>(pointer),
+                }
+            }
+        }
+        NanBoxed(self.0)
+    }
+}
+
+impl Drop for NanBoxed {
+    fn drop(&mut self) {
+        if !self.is_object() {
+            return;
+        }
+        let pointer = self.pointer();
+        // SAFETY: the kind was set from the Rc the pointer came from
+        unsafe {
+            match self.kind() {
+                OBJ_STRING => decrement::<LoxString>(pointer),
+                OBJ_FUNCTION => decrement::<Function>(pointer),
+                OBJ_CLASS => decrement::<Class>(pointer),
+                OBJ_INSTANCE => decrement::<RefCell<Instance>>(pointer),
+                OBJ_BOUND_METHOD => decrement::<BoundMethod>(pointer),
+                OBJ_NATIVE => decrement::<Native>(pointer),
+                OBJ_LIST => decrement::<RefCell<Vec<Value>>>(pointer),
+                _ => decrement::<RefCell<Map>>(pointer),
+            }
+        }
+    }
+}
+
+impl PartialEq for NanBoxed {
+    /// Compares like [`Value`]: numbers by value, objects by identity and
+    /// strings by contents.
+    fn eq(&self, other: &Self) -> bool {
+        if self.is_number() && other.is_number() {
+            return f64::from_bits(self.0) == f64::from_bits(other.0);
+        }
+        self.0 == other.0 || (self.is_object() && self.to_value() == other.to_value())
+    }
+}
+
+impl fmt::Display for NanBoxed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.to_value(), f)
+    }
+}
+
+impl fmt::Debug for NanBoxed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("NanBoxed").field(&self.to_value()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scalars_round_trip() {
+        for value in [
+            Value::nil(),
+            Value::bool(true),
+            Value::bool(false),
+            Value::number(0.0),
+            Value::number(-0.0),
+            Value::number(1.5),
+            Value::number(f64::INFINITY),
+            Value::number(f64::NEG_INFINITY),
+            Value::number(f64::MIN_POSITIVE),
+        ] {
+            let boxed = NanBoxed::from(value.clone());
+            assert_eq!(boxed.to_value(), value);
+            assert_eq!(Value::from(boxed), value);
+        }
+
+        let nan = NanBoxed::number(f64::NAN);
+        assert!(nan.is_number());
+        assert!(nan.as_number().unwrap().is_nan());
+        assert_eq!(NanBoxed::nil().as_number(), None);
+        assert_eq!(NanBoxed::bool(true).as_bool(), Some(true));
+        assert_eq!(NanBoxed::nil().as_bool(), None);
+        assert!(NanBoxed::nil().is_falsey() && NanBoxed::bool(false).is_falsey());
+        assert!(!NanBoxed::number(0.0).is_falsey());
+    }
+
+    #[test]
+    fn test_objects_keep_their_reference_counts() {
+        let list = Value::list(vec![Value::number(1.0)]);
+        let Value::List(rc) = &list else {
+            unreachable!()
+        };
+        let rc = Rc::clone(rc);
+
+        let boxed = NanBoxed::from(list);
+        assert_eq!(Rc::strong_count(&rc), 2);
+        let copy = boxed.clone();
+        assert_eq!(Rc::strong_count(&rc), 3);
+        assert_eq!(copy, boxed);
+        drop(copy);
+        assert_eq!(Rc::strong_count(&rc), 2);
+
+        let unboxed = Value::from(boxed);
+        let Value::List(list) = &unboxed else {
+            panic!("Expected a list");
+        };
+        assert!(Rc::ptr_eq(list, &rc));
+        drop(unboxed);
+        assert_eq!(Rc::strong_count(&rc), 1);
+    }
+
+    #[test]
+    fn test_object_kinds_round_trip() {
+        let class = Rc::new(Class::new("Point".to_string()));
+        let function = Rc::new(Function::new(Some("f".to_string())));
+        let instance = Value::instance(Instance::new(Rc::clone(&class)));
+        for value in [
+            Value::string("abc".to_string()),
+            Value::Function(Rc::clone(&function)),
+            Value::class(Rc::clone(&class)),
+            Value::bound_method(instance.clone(), function),
+            instance,
+            Value::list(Vec::new()),
+            Value::map(Map::new()),
+        ] {
+            let boxed = NanBoxed::from(value.clone());
+            assert!(!boxed.is_number() && !boxed.is_nil() && !boxed.is_bool());
+            assert_eq!(boxed.to_value(), value);
+        }
+        assert_eq!(
+            NanBoxed::from(Value::string("abc".to_string())),
+            NanBoxed::from(Value::string("abc".to_string()))
+        );
+        assert_eq!(mem::size_of::<NanBoxed>(), 8);
+    }
+
+    #[test]
+    fn test_vm_runs_on_boxed_values() {
+        assert_eq!(mem::size_of::<crate::value::Slot>(), 8);
+
+        let mut vm = crate::vm::VM::new();
+        let chunk = crate::compiler::compile(
+            "class P { init(x) { this.x = x; } }
+             fun f(a, b) { return [a, b.x, nil, true, \"s\" + \"t\"]; }
+             var result = f(1.5, P(-0));",
+            &mut vm,
+        )
+        .unwrap();
+        vm.interpret(chunk).unwrap();
+        assert_eq!(
+            vm.get_global("result").unwrap().to_string(),
+            "[1.5, -0, nil, true, st]"
+        );
+    }
+}
@@ -0,0 +1,270 @@
+//! Functions implemented in Rust and available to every script as globals.
+
+use crate::value::{MapKey, NativeFn, Value};
+use crate::vm::{VM, feature_disabled};
+use std::fs;
+
+/// The natives disabled along with `Feature::Io`.
+pub(crate) const IO_NATIVES: [&str; 3] = ["readLine", "readFile", "writeFile"];
+
+pub fn define_natives(vm: &mut VM) {
+    vm.define_native("clock", 0, clock);
+    vm.define_native("random", 0, random);
+    vm.define_native("len", 1, len);
+    vm.define_native("push", 2, push);
+    vm.define_native("pop", 1, pop);
+    vm.define_native("has", 2, has);
+    vm.define_native("remove", 2, remove);
+    vm.define_native("keys", 1, keys);
+    vm.define_native("substr", 3, substr);
+    vm.define_native("indexOf", 2, index_of);
+    vm.define_native("split", 2, split);
+    vm.define_native("upper", 1, upper);
+    vm.define_native("lower", 1, lower);
+    vm.define_native("type", 1, type_of);
+    vm.define_native("num", 1, num);
+    vm.define_native("str", 1, str);
+    define_io_natives(vm);
+}
+
+/// Defines the natives in `IO_NATIVES`, except those whose names a script or
+/// the host has already given to a global of its own.
+pub(crate) fn define_io_natives(vm: &mut VM) {
+    let natives: [(&str, usize, NativeFn); 3] = [
+        ("readLine", 0, read_line),
+        ("readFile", 1, read_file),
+        ("writeFile", 2, write_file),
+    ];
+    for (name, arity, function) in natives {
+        if vm.get_global(name).is_none() {
+            vm.define_native(name, arity, function);
+        }
+    }
+}
+
+/// Seconds elapsed since the VM started, measured by the VM's clock so that
+/// deterministic runs see virtual time.
+fn clock(vm: &mut VM, _args: &[Value]) -> Result<Value, String> {
+    Ok(Value::number(vm.elapsed().as_secs_f64()))
+}
+
+/// A number in `[0, 1)` from the VM's generator, which deterministic runs
+/// seed themselves.
+fn random(vm: &mut VM, _args: &[Value]) -> Result<Value, String> {
+    Ok(Value::number(vm.random()))
+}
+
+fn len(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    match &args[0] {
+        Value::List(list) => Ok(Value::number(list.borrow().len() as f64)),
+        Value::Map(map) => Ok(Value::number(map.borrow().iter().count() as f64)),
+        Value::String(s) => Ok(Value::number(s.chars().count() as f64)),
+        _ => Err("Argument to 'len' must be a list, map or string.".to_string()),
+    }
+}
+
+/// Appends a value to the end of a list.
+fn push(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let Some(list) = args[0].as_list() else {
+        return Err("First argument to 'push' must be a list.".to_string());
+    };
+    list.borrow_mut().push(args[1].clone());
+    Ok(Value::nil())
+}
+
+/// Removes and returns the last element of a list.
+fn pop(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let Some(list) = args[0].as_list() else {
+        return Err("Argument to 'pop' must be a list.".to_string());
+    };
+    list.borrow_mut()
+        .pop()
+        .ok_or_else(|| "Can't pop from an empty list.".to_string())
+}
+
+fn map_key(key: &Value) -> Result<MapKey, String> {
+    MapKey::from_value(key).ok_or_else(|| "Map keys must be strings or numbers.".to_string())
+}
+
+/// Whether a map contains a key.
+fn has(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let Some(map) = args[0].as_map() else {
+        return Err("First argument to 'has' must be a map.".to_string());
+    };
+    let key = map_key(&args[1])?;
+    Ok(Value::bool(map.borrow().get(&key).is_some()))
+}
+
+/// Removes a key from a map, returning its value or nil if it was absent.
+fn remove(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let Some(map) = args[0].as_map() else {
+        return Err("First argument to 'remove' must be a map.".to_string());
+    };
+    let key = map_key(&args[1])?;
+    let mut map = map.borrow_mut();
+    let value = map.get(&key).cloned().unwrap_or(Value::Nil);
+    map.delete(&key);
+    Ok(value)
+}
+
+/// A new list holding a map's keys, in no particular order.
+fn keys(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let Some(map) = args[0].as_map() else {
+        return Err("Argument to 'keys' must be a map.".to_string());
+    };
+    let keys = map.borrow().iter().map(|(key, _)| key.to_value()).collect();
+    Ok(Value::list(keys))
+}
+
+fn string_arg<'a>(args: &'a [Value], position: usize, name: &str) -> Result<&'a str, String> {
+    match &args[position] {
+        Value::String(s) => Ok(s),
+        _ => Err(format!(
+            "{} to '{}' must be a string.",
+            argument_name(args, position),
+            name
+        )),
+    }
+}
+
+fn count_arg(args: &[Value], position: usize, name: &str) -> Result<usize, String> {
+    match args[position] {
+        Value::Number(n) if n >= 0.0 && n.fract() == 0.0 => Ok(n as usize),
+        _ => Err(format!(
+            "{} to '{}' must be a non-negative integer.",
+            argument_name(args, position),
+            name
+        )),
+    }
+}
+
+fn argument_name(args: &[Value], position: usize) -> &'static str {
+    match (args.len(), position) {
+        (1, _) => "Argument",
+        (_, 0) => "First argument",
+        (_, 1) => "Second argument",
+        _ => "Third argument",
+    }
+}
+
+/// The `length` characters of a string starting at character `start`.
+fn substr(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let s = string_arg(args, 0, "substr")?;
+    let start = count_arg(args, 1, "substr")?;
+    let length = count_arg(args, 2, "substr")?;
+    if start.saturating_add(length) > s.chars().count() {
+        return Err("Substring out of range.".to_string());
+    }
+    Ok(Value::string(s.chars().skip(start).take(length).collect()))
+}
+
+/// The character index of the first occurrence of `needle`, or -1.
+fn index_of(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let s = string_arg(args, 0, "indexOf")?;
+    let needle = string_arg(args, 1, "indexOf")?;
+    let index = match s.find(needle) {
+        Some(byte_index) => s[..byte_index].chars().count() as f64,
+        None => -1.0,
+    };
+    Ok(Value::number(index))
+}
+
+/// Splits a string around each occurrence of a non-empty separator.
+fn split(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let s = string_arg(args, 0, "split")?;
+    let separator = string_arg(args, 1, "split")?;
+    if separator.is_empty() {
+        return Err("Separator passed to 'split' can't be empty.".to_string());
+    }
+    let parts = s
+        .split(separator)
+        .map(|part| Value::string(part.to_string()))
+        .collect();
+    Ok(Value::list(parts))
+}
+
+fn upper(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::string(string_arg(args, 0, "upper")?.to_uppercase()))
+}
+
+fn lower(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::string(string_arg(args, 0, "lower")?.to_lowercase()))
+}
+
+/// The next line of input, or nil at the end of it.
+fn read_line(vm: &mut VM, _args: &[Value]) -> Result<Value, String> {
+    check_io(vm, "readLine")?;
+    match vm.read_line() {
+        Ok(Some(line)) => Ok(Value::string(line)),
+        Ok(None) => Ok(Value::nil()),
+        Err(error) => Err(format!("Could not read input: {}", error)),
+    }
+}
+
+// The natives are undefined while taken away, but a script may have kept
+// one from before
+fn check_io(vm: &VM, name: &str) -> Result<(), String> {
+    if !vm.io_natives_enabled() {
+        return Err(feature_disabled(name));
+    }
+    Ok(())
+}
+
+fn read_file(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    check_io(vm, "readFile")?;
+    let path = string_arg(args, 0, "readFile")?;
+    fs::read_to_string(path)
+        .map(Value::string)
+        .map_err(|error| format!("Could not read file '{}': {}", path, error))
+}
+
+/// Replaces the contents of a file, creating it if needed.
+fn write_file(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    check_io(vm, "writeFile")?;
+    let path = string_arg(args, 0, "writeFile")?;
+    let contents = string_arg(args, 1, "writeFile")?;
+    fs::write(path, contents)
+        .map(|_| Value::nil())
+        .map_err(|error| format!("Could not write file '{}': {}", path, error))
+}
+
+/// The name of a value's type: "nil", "bool", "number", "string",
+/// "function", "class", "instance", "list" or "map".
+fn type_of(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let name = match &args[0] {
+        Value::Nil => "nil",
+        Value::Bool(_) => "bool",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Function(_) | Value::BoundMethod(_) | Value::Native(_) => "function",
+        Value::Class(_) => "class",
+        Value::Instance(_) => "instance",
+        Value::List(_) => "list",
+        Value::Map(_) => "map",
+    };
+    Ok(Value::string(name.to_string()))
+}
+
+/// Parses a string as a number, returning nil if it isn't one.
+fn num(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    match &args[0] {
+        Value::Number(n) => Ok(Value::number(*n)),
+        Value::String(s) => {
+            let s = s.trim();
+            // Rust also parses words like "inf" and "NaN", which aren't Lox numbers
+            let is_numeric = s
+                .chars()
+                .all(|c| c.is_ascii_digit() || matches!(c, '.' | '-' | '+' | 'e' | 'E'));
+            match s.parse::<f64>() {
+                Ok(n) if is_numeric => Ok(Value::number(n)),
+                _ => Ok(Value::nil()),
+            }
+        }
+        _ => Err("Argument to 'num' must be a string or number.".to_string()),
+    }
+}
+
+/// Converts a value to the string `print` would show for it.
+fn str(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::string(args[0].to_string()))
+}
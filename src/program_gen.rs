@@ -0,0 +1,290 @@
+//! Proptest generator of well-formed Lox programs for compiler and VM tests.
+//!
+//! Programs are generated as a small AST and rendered to source afterwards.
+//! Variable references are stored as indices and resolved against the
+//! variables in scope while rendering, so every program compiles. Expressions
+//! are typed so that most programs also run without runtime errors.
+
+use proptest::prelude::*;
+
+#[derive(Debug, Clone)]
+enum NumExpr {
+    Literal(u16),
+    Negate(Box<NumExpr>),
+    Binary(Box<NumExpr>, &'static str, Box<NumExpr>),
+}
+
+#[derive(Debug, Clone)]
+enum BoolExpr {
+    Literal(bool),
+    Not(Box<BoolExpr>),
+    Compare(NumExpr, &'static str, NumExpr),
+    Equal(Box<Expr>, Box<Expr>),
+    Logical(Box<BoolExpr>, &'static str, Box<BoolExpr>),
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Nil,
+    Number(NumExpr),
+    Bool(BoolExpr),
+    String(u8, u8),
+    Variable(usize),
+}
+
+#[derive(Debug, Clone)]
+enum Stmt {
+    Print(Expr),
+    Expression(Expr),
+    Var(Expr),
+    Assign(usize, Expr),
+    If(BoolExpr, Box<Stmt>, Option<Box<Stmt>>),
+    Block(Vec<Stmt>),
+    For(u8, Box<Stmt>),
+}
+
+fn num_expr() -> impl Strategy<Value = NumExpr> {
+    (0..1000u16)
+        .prop_map(NumExpr::Literal)
+        .prop_recursive(4, 16, 2, |inner| {
+            prop_oneof![
+                inner.clone().prop_map(|e| NumExpr::Negate(Box::new(e))),
+                (
+                    inner.clone(),
+                    prop::sample::select(vec!["+", "-", "*", "/", "%"]),
+                    inner
+                )
+                    .prop_map(|(a, op, b)| NumExpr::Binary(
+                        Box::new(a),
+                        op,
+                        Box::new(b)
+                    )),
+            ]
+        })
+}
+
+fn bool_expr() -> impl Strategy<Value = BoolExpr> {
+    let leaf = prop_oneof![
+        any::<bool>().prop_map(BoolExpr::Literal),
+        (
+            num_expr(),
+            prop::sample::select(vec!["<", "<=", ">", ">="]),
+            num_expr()
+        )
+            .prop_map(|(a, op, b)| BoolExpr::Compare(a, op, b)),
+        (leaf_expr(), leaf_expr()).prop_map(|(a, b)| BoolExpr::Equal(Box::new(a), Box::new(b))),
+    ];
+
+    leaf.prop_recursive(3, 12, 2, |inner| {
+        prop_oneof![
+            inner.clone().prop_map(|e| BoolExpr::Not(Box::new(e))),
+            (
+                inner.clone(),
+                prop::sample::select(vec!["and", "or"]),
+                inner
+            )
+                .prop_map(|(a, op, b)| BoolExpr::Logical(Box::new(a), op, Box::new(b))),
+        ]
+    })
+}
+
+fn leaf_expr() -> impl Strategy<Value = Expr> {
+    prop_oneof![
+        Just(Expr::Nil),
+        (0..100u16).prop_map(|n| Expr::Number(NumExpr::Literal(n))),
+        any::<bool>().prop_map(|b| Expr::Bool(BoolExpr::Literal(b))),
+        (0..4u8, 0..4u8).prop_map(|(a, b)| Expr::String(a, b)),
+        any::<usize>().prop_map(Expr::Variable),
+    ]
+}
+
+fn expr() -> impl Strategy<Value = Expr> {
+    prop_oneof![
+        leaf_expr(),
+        num_expr().prop_map(Expr::Number),
+        bool_expr().prop_map(Expr::Bool),
+    ]
+}
+
+fn stmt() -> impl Strategy<Value = Stmt> {
+    let leaf = prop_oneof![
+        expr().prop_map(Stmt::Print),
+        expr().prop_map(Stmt::Expression),
+        expr().prop_map(Stmt::Var),
+        (any::<usize>(), expr()).prop_map(|(target, value)| Stmt::Assign(target, value)),
+    ];
+
+    leaf.prop_recursive(4, 32, 6, |inner| {
+        prop_oneof![
+            (
+                bool_expr(),
+                inner.clone().prop_map(Box::new),
+                prop::option::of(inner.clone().prop_map(Box::new))
+            )
+                .prop_map(|(condition, then, otherwise)| Stmt::If(condition, then, otherwise)),
+            prop::collection::vec(inner.clone(), 0..6).prop_map(Stmt::Block),
+            (0..4u8, inner).prop_map(|(count, body)| Stmt::For(count, Box::new(body))),
+        ]
+    })
+}
+
+/// Generates the source of a complete, well-formed Lox program.
+pub fn program() -> impl Strategy<Value = String> {
+    prop::collection::vec(stmt(), 0..12).prop_map(|stmts| {
+        let mut renderer = Renderer {
+            out: String::new(),
+            scopes: vec![Vec::new()],
+            next_name: 0,
+        };
+        for stmt in stmts.iter() {
+            renderer.stmt(stmt);
+        }
+        renderer.out
+    })
+}
+
+struct Renderer {
+    out: String,
+    scopes: Vec<Vec<String>>,
+    next_name: usize,
+}
+
+impl Renderer {
+    fn variable(&self, index: usize) -> Option<String> {
+        let visible: Vec<&String> = self.scopes.iter().flatten().collect();
+        if visible.is_empty() {
+            None
+        } else {
+            Some(visible[index % visible.len()].clone())
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Print(value) => {
+                self.out.push_str("print ");
+                self.expr(value);
+                self.out.push_str(";\n");
+            }
+            Stmt::Expression(value) => {
+                self.expr(value);
+                self.out.push_str(";\n");
+            }
+            Stmt::Var(value) => {
+                let name = format!("v{}", self.next_name);
+                self.next_name += 1;
+                self.out.push_str(&format!("var {} = ", name));
+                self.expr(value);
+                self.out.push_str(";\n");
+                self.scopes.last_mut().unwrap().push(name);
+            }
+            Stmt::Assign(target, value) => {
+                if let Some(name) = self.variable(*target) {
+                    self.out.push_str(&format!("{} = ", name));
+                }
+                self.expr(value);
+                self.out.push_str(";\n");
+            }
+            Stmt::If(condition, then, otherwise) => {
+                self.out.push_str("if (");
+                self.bool_expr(condition);
+                self.out.push_str(") ");
+                self.branch(then);
+                if let Some(otherwise) = otherwise {
+                    self.out.push_str("else ");
+                    self.branch(otherwise);
+                }
+            }
+            Stmt::Block(stmts) => {
+                self.out.push_str("{\n");
+                self.scopes.push(Vec::new());
+                for stmt in stmts.iter() {
+                    self.stmt(stmt);
+                }
+                self.scopes.pop();
+                self.out.push_str("}\n");
+            }
+            Stmt::For(count, body) => {
+                // The counter isn't added to the scope so the body can't
+                // reassign it and loop forever
+                let counter = format!("i{}", self.next_name);
+                self.next_name += 1;
+                self.out.push_str(&format!(
+                    "for (var {0} = 0; {0} < {1}; {0} = {0} + 1) ",
+                    counter, count
+                ));
+                self.branch(body);
+            }
+        }
+    }
+
+    // Declarations aren't allowed directly in a branch or loop body, so wrap
+    // them in a block
+    fn branch(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Var(_) => self.stmt(&Stmt::Block(vec![stmt.clone()])),
+            _ => self.stmt(stmt),
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Nil => self.out.push_str("nil"),
+            Expr::Number(value) => self.num_expr(value),
+            Expr::Bool(value) => self.bool_expr(value),
+            Expr::String(a, b) => self.out.push_str(&format!("\"s{}\" + \"s{}\"", a, b)),
+            Expr::Variable(index) => match self.variable(*index) {
+                Some(name) => self.out.push_str(&name),
+                None => self.out.push_str("nil"),
+            },
+        }
+    }
+
+    fn num_expr(&mut self, expr: &NumExpr) {
+        match expr {
+            NumExpr::Literal(n) => self.out.push_str(&n.to_string()),
+            NumExpr::Negate(operand) => {
+                self.out.push_str("-(");
+                self.num_expr(operand);
+                self.out.push(')');
+            }
+            NumExpr::Binary(a, op, b) => {
+                self.out.push('(');
+                self.num_expr(a);
+                self.out.push_str(&format!(" {} ", op));
+                self.num_expr(b);
+                self.out.push(')');
+            }
+        }
+    }
+
+    fn bool_expr(&mut self, expr: &BoolExpr) {
+        match expr {
+            BoolExpr::Literal(b) => self.out.push_str(&b.to_string()),
+            BoolExpr::Not(operand) => {
+                self.out.push_str("!(");
+                self.bool_expr(operand);
+                self.out.push(')');
+            }
+            BoolExpr::Compare(a, op, b) => {
+                self.num_expr(a);
+                self.out.push_str(&format!(" {} ", op));
+                self.num_expr(b);
+            }
+            BoolExpr::Equal(a, b) => {
+                self.out.push('(');
+                self.expr(a);
+                self.out.push_str(") == (");
+                self.expr(b);
+                self.out.push(')');
+            }
+            BoolExpr::Logical(a, op, b) => {
+                self.out.push('(');
+                self.bool_expr(a);
+                self.out.push_str(&format!(" {} ", op));
+                self.bool_expr(b);
+                self.out.push(')');
+            }
+        }
+    }
+}
@@ -1,9 +1,12 @@
 use crate::scanner::TokenType::{
-    And, Bang, BangEqual, Class, Comma, Dot, Else, Eof, Equal, EqualEqual, False, For, Fun,
-    Greater, GreaterEqual, Identifier, If, LeftBrace, LeftParen, Less, LessEqual, Minus, Nil,
-    Number, Or, Plus, Print, Return, RightBrace, RightParen, Semicolon, Slash, Star, String, Super,
-    This, True, Var, While,
+    And, Assert, Bang, BangEqual, Break, Catch, Class, Colon, Comma, Continue, Dot, Else, Eof,
+    Equal, EqualEqual, False, For, Fun, Greater, GreaterEqual, Identifier, If, Import, LeftBrace,
+    LeftBracket, LeftParen, Less, LessEqual, Minus, Nil, Number, Or, Percent, Plus, Print, Return,
+    RightBrace, RightBracket, RightParen, Semicolon, Slash, Star, StarStar, String, Super, This,
+    Throw, True, Try, Var, While,
 };
+use std::ops::Range;
+use unicode_ident::{is_xid_continue, is_xid_start};
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum TokenType {
@@ -11,6 +14,9 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
+    Colon,
     Comma,
     Dot,
     Minus,
@@ -18,6 +24,7 @@ pub enum TokenType {
     Semicolon,
     Slash,
     Star,
+    Percent,
     Bang,
     BangEqual,
     Equal,
@@ -26,23 +33,31 @@ pub enum TokenType {
     GreaterEqual,
     Less,
     LessEqual,
+    StarStar,
     Identifier,
     String,
     Number,
     And,
+    Assert,
+    Break,
+    Catch,
     Class,
+    Continue,
     Else,
     False,
     For,
     Fun,
     If,
+    Import,
     Nil,
     Or,
     Print,
     Return,
     Super,
     This,
+    Throw,
     True,
+    Try,
     Var,
     While,
 
@@ -50,11 +65,30 @@ pub enum TokenType {
     Eof,
 }
 
+/// A token of source text. For an error token, `lexeme` is the error
+/// message instead, and the span covers the text it's about.
 #[derive(Debug, Clone, Copy)]
 pub struct Token<'a> {
     pub token_type: TokenType,
     pub lexeme: &'a str,
+    /// Line of the token's first character
     pub line: i32,
+    /// Line of the token's last character, which is where clox reports
+    /// errors at a multi-line string
+    pub end_line: i32,
+    /// 1-based column of the token's first character
+    pub column: usize,
+    /// Byte offset of the token's first character in the source
+    pub start: usize,
+    /// Byte offset just past the token's last character
+    pub end: usize,
+}
+
+impl Token<'_> {
+    /// The token's byte range in the source.
+    pub fn span(&self) -> Range<usize> {
+        self.start..self.end
+    }
 }
 
 pub struct Scanner<'a> {
@@ -62,6 +96,13 @@ pub struct Scanner<'a> {
     start: usize,
     current: usize,
     line: i32,
+    // Offset of the first character of the current line
+    line_start: usize,
+    // UTF-8 continuation bytes since the start of the line, so columns
+    // count characters rather than bytes
+    line_continuations: usize,
+    start_line: i32,
+    start_column: usize,
 }
 
 pub fn init_scanner(source: &str) -> Scanner<'_> {
@@ -70,6 +111,10 @@ pub fn init_scanner(source: &str) -> Scanner<'_> {
         start: 0,
         current: 0,
         line: 1,
+        line_start: 0,
+        line_continuations: 0,
+        start_line: 1,
+        start_column: 1,
     }
 }
 
@@ -78,6 +123,8 @@ impl<'a> Scanner<'a> {
         self.skip_whitespace();
 
         self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.start - self.line_start - self.line_continuations + 1;
         if self.is_at_end() {
             return self.make_token(Eof);
         }
@@ -88,52 +135,68 @@ impl<'a> Scanner<'a> {
             return self.identifier();
         }
 
+        if !c.is_ascii() {
+            self.current = self.start;
+            let c = self.advance_char();
+            if is_xid_start(c) {
+                return self.identifier();
+            }
+            return self.error_token("Unexpected character.");
+        }
+
         if self.is_digit(c) {
             return self.number();
         }
 
         match c {
-            '(' => self.make_token(LeftParen),
-            ')' => self.make_token(RightParen),
-            '{' => self.make_token(LeftBrace),
-            '}' => self.make_token(RightBrace),
-            ';' => self.make_token(Semicolon),
-            '.' => self.make_token(Dot),
-            ',' => self.make_token(Comma),
-            '-' => self.make_token(Minus),
-            '+' => self.make_token(Plus),
-            '/' => self.make_token(Slash),
-            '*' => self.make_token(Star),
-            '!' => {
-                let token_type = if self.match_ch('=') { BangEqual } else { Bang };
+            b'(' => self.make_token(LeftParen),
+            b')' => self.make_token(RightParen),
+            b'{' => self.make_token(LeftBrace),
+            b'}' => self.make_token(RightBrace),
+            b'[' => self.make_token(LeftBracket),
+            b']' => self.make_token(RightBracket),
+            b';' => self.make_token(Semicolon),
+            b'.' => self.make_token(Dot),
+            b':' => self.make_token(Colon),
+            b',' => self.make_token(Comma),
+            b'-' => self.make_token(Minus),
+            b'+' => self.make_token(Plus),
+            b'/' => self.make_token(Slash),
+            b'*' => {
+                let token_type = if self.match_ch(b'*') { StarStar } else { Star };
+                self.make_token(token_type)
+            }
+            b'%' => self.make_token(Percent),
+            b'!' => {
+                let token_type = if self.match_ch(b'=') { BangEqual } else { Bang };
                 self.make_token(token_type)
             }
-            '=' => {
-                let token_type = if self.match_ch('=') {
+            b'=' => {
+                let token_type = if self.match_ch(b'=') {
                     EqualEqual
                 } else {
                     Equal
                 };
                 self.make_token(token_type)
             }
-            '<' => {
-                let token_type = if self.match_ch('=') { LessEqual } else { Less };
+            b'<' => {
+                let token_type = if self.match_ch(b'=') { LessEqual } else { Less };
                 self.make_token(token_type)
             }
-            '>' => {
-                let token_type = if self.match_ch('=') {
+            b'>' => {
+                let token_type = if self.match_ch(b'=') {
                     GreaterEqual
                 } else {
                     Greater
                 };
                 self.make_token(token_type)
             }
-            '"' => self.string(),
+            b'"' => self.string(),
             _ => self.error_token("Unexpected character."),
         }
     }
 
-    fn match_ch(&mut self, expected: char) -> bool {
+    fn match_ch(&mut self, expected: u8) -> bool {
         if self.is_at_end() || self.peek() != expected {
             return false;
         }
@@ -141,8 +204,12 @@ impl<'a> Scanner<'a> {
         true
     }
 
-    fn peek(&self) -> char {
-        self.source.chars().nth(self.current).unwrap_or('\0')
+    fn peek(&self) -> u8 {
+        self.source
+            .as_bytes()
+            .get(self.current)
+            .copied()
+            .unwrap_or(b'\0')
     }
 
     fn is_at_end(&self) -> bool {
@@ -153,7 +220,11 @@ impl<'a> Scanner<'a> {
         Token {
             token_type,
             lexeme: &self.source[self.start..self.current],
-            line: self.line,
+            line: self.start_line,
+            end_line: self.line,
+            column: self.start_column,
+            start: self.start,
+            end: self.current,
         }
     }
 
@@ -161,13 +232,34 @@ impl<'a> Scanner<'a> {
         Token {
             token_type: TokenType::Error,
             lexeme: message,
-            line: self.line,
+            line: self.start_line,
+            end_line: self.line,
+            column: self.start_column,
+            start: self.start,
+            end: self.current,
         }
     }
 
-    fn advance(&mut self) -> char {
+    fn advance(&mut self) -> u8 {
         self.current += 1;
-        self.source.chars().nth(self.current - 1).unwrap()
+        let byte = self.source.as_bytes()[self.current - 1];
+        if byte & 0xC0 == 0x80 {
+            self.line_continuations += 1;
+        }
+        byte
+    }
+
+    /// Consumes the whole character at the current position, which may be
+    /// several bytes long.
+    fn advance_char(&mut self) -> char {
+        let c = self.peek_char();
+        self.current += c.len_utf8();
+        self.line_continuations += c.len_utf8() - 1;
+        c
+    }
+
+    fn peek_char(&self) -> char {
+        self.source[self.current..].chars().next().unwrap_or('\0')
     }
 
     fn skip_whitespace(&mut self) {
@@ -175,20 +267,18 @@ impl<'a> Scanner<'a> {
             let c = self.peek();
 
             match c {
-                ' ' | '\r' | '\t' => {
+                b' ' | b'\r' | b'\t' => {
                     self.advance();
                 }
-                '\n' => {
+                b'\n' => {
                     self.line += 1;
                     self.advance();
+                    self.line_start = self.current;
+                    self.line_continuations = 0;
                 }
-                '/' => {
-                    if self.peek_next() == '/' {
-                        while self.peek() != '\n' && !self.is_at_end() {
-                            self.advance();
-                        }
-                    } else {
-                        return;
+                b'/' if self.peek_next() == b'/' => {
+                    while self.peek() != b'\n' && !self.is_at_end() {
+                        self.advance();
                     }
                 }
                 _ => return,
@@ -196,14 +286,22 @@ impl<'a> Scanner<'a> {
         }
     }
 
-    fn peek_next(&self) -> char {
-        self.source.chars().nth(self.current + 1).unwrap_or('\0')
+    fn peek_next(&self) -> u8 {
+        self.source
+            .as_bytes()
+            .get(self.current + 1)
+            .copied()
+            .unwrap_or(b'\0')
     }
 
     fn string(&mut self) -> Token<'a> {
-        while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
+        // Multi-byte characters pass through unchanged, since none of their
+        // bytes are ASCII
+        while self.peek() != b'"' && !self.is_at_end() {
+            if self.peek() == b'\n' {
                 self.line += 1;
+                self.line_start = self.current + 1;
+                self.line_continuations = 0;
             }
             self.advance();
         }
@@ -216,8 +314,8 @@ impl<'a> Scanner<'a> {
         self.make_token(String)
     }
 
-    fn is_digit(&self, c: char) -> bool {
-        c >= '0' && c <= '9'
+    fn is_digit(&self, c: u8) -> bool {
+        c.is_ascii_digit()
     }
 
     fn number(&mut self) -> Token<'a> {
@@ -225,7 +323,7 @@ impl<'a> Scanner<'a> {
             self.advance();
         }
 
-        if self.peek() == '.' && self.is_digit(self.peek_next()) {
+        if self.peek() == b'.' && self.is_digit(self.peek_next()) {
             self.advance();
 
             while self.is_digit(self.peek()) {
@@ -236,13 +334,23 @@ impl<'a> Scanner<'a> {
         self.make_token(Number)
     }
 
-    fn is_alpha(&self, c: char) -> bool {
-        (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || (c == '_')
+    /// Identifiers start with a letter or `_` and continue with letters,
+    /// digits or `_`. Beyond ASCII, letters are the characters Unicode
+    /// allows in identifiers (XID_Start and XID_Continue).
+    fn is_alpha(&self, c: u8) -> bool {
+        c.is_ascii_alphabetic() || c == b'_'
     }
 
     fn identifier(&mut self) -> Token<'a> {
-        while self.is_alpha(self.peek()) || self.is_digit(self.peek()) {
-            self.advance();
+        loop {
+            let c = self.peek();
+            if self.is_alpha(c) || self.is_digit(c) {
+                self.advance();
+            } else if !c.is_ascii() && is_xid_continue(self.peek_char()) {
+                self.advance_char();
+            } else {
+                break;
+            }
         }
         self.make_token(self.identifier_type())
     }
@@ -250,8 +358,24 @@ impl<'a> Scanner<'a> {
     fn identifier_type(&self) -> TokenType {
         // Trie-based keyword recognition
         match self.source.as_bytes()[self.start] {
-            b'a' => self.check_keyword(1, "nd", And),
-            b'c' => self.check_keyword(1, "lass", Class),
+            b'a' if self.current - self.start > 1 => match self.source.as_bytes()[self.start + 1] {
+                b'n' => self.check_keyword(2, "d", And),
+                b's' => self.check_keyword(2, "sert", Assert),
+                _ => Identifier,
+            },
+            b'b' => self.check_keyword(1, "reak", Break),
+            b'c' => {
+                if self.current - self.start > 1 {
+                    match self.source.as_bytes()[self.start + 1] {
+                        b'a' => self.check_keyword(2, "tch", Catch),
+                        b'l' => self.check_keyword(2, "ass", Class),
+                        b'o' => self.check_keyword(2, "ntinue", Continue),
+                        _ => Identifier,
+                    }
+                } else {
+                    Identifier
+                }
+            }
             b'e' => self.check_keyword(1, "lse", Else),
             b'f' => {
                 if self.current - self.start > 1 {
@@ -265,7 +389,11 @@ impl<'a> Scanner<'a> {
                     Identifier
                 }
             }
-            b'i' => self.check_keyword(1, "f", If),
+            b'i' if self.current - self.start > 1 => match self.source.as_bytes()[self.start + 1] {
+                b'f' => self.check_keyword(2, "", If),
+                b'm' => self.check_keyword(2, "port", Import),
+                _ => Identifier,
+            },
             b'n' => self.check_keyword(1, "il", Nil),
             b'o' => self.check_keyword(1, "r", Or),
             b'p' => self.check_keyword(1, "rint", Print),
@@ -274,8 +402,20 @@ impl<'a> Scanner<'a> {
             b't' => {
                 if self.current - self.start > 1 {
                     match self.source.as_bytes()[self.start + 1] {
-                        b'h' => self.check_keyword(2, "is", This),
-                        b'r' => self.check_keyword(2, "ue", True),
+                        b'h' if self.current - self.start > 2 => {
+                            match self.source.as_bytes()[self.start + 2] {
+                                b'i' => self.check_keyword(3, "s", This),
+                                b'r' => self.check_keyword(3, "ow", Throw),
+                                _ => Identifier,
+                            }
+                        }
+                        b'r' if self.current - self.start > 2 => {
+                            match self.source.as_bytes()[self.start + 2] {
+                                b'u' => self.check_keyword(3, "e", True),
+                                b'y' => self.check_keyword(3, "", Try),
+                                _ => Identifier,
+                            }
+                        }
                         _ => Identifier,
                     }
                 } else {
@@ -299,3 +439,158 @@ impl<'a> Scanner<'a> {
         }
     }
 }
+
+/// Splits `source` into tokens, up to but not including the end of input.
+/// Errors don't stop scanning; they come back as error tokens.
+pub fn scan(source: &str) -> Vec<Token<'_>> {
+    let mut scanner = init_scanner(source);
+    let mut tokens = Vec::new();
+    loop {
+        let token = scanner.scan_token();
+        if token.token_type == Eof {
+            return tokens;
+        }
+        tokens.push(token);
+    }
+}
+
+/// Returns whether `source` stops inside an unclosed bracket or string, in
+/// which case the REPL keeps reading lines before compiling it.
+pub fn is_incomplete(source: &str) -> bool {
+    let mut scanner = init_scanner(source);
+    let mut depth = 0;
+
+    loop {
+        let token = scanner.scan_token();
+        match token.token_type {
+            LeftParen | LeftBrace | LeftBracket => depth += 1,
+            RightParen | RightBrace | RightBracket => depth -= 1,
+            TokenType::Error if token.lexeme == "Unterminated string." => return true,
+            Eof => return depth > 0,
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_columns() {
+        let mut scanner = init_scanner("var x = 1;\n  print \"a\nb\" + x;");
+        let mut positions = Vec::new();
+        loop {
+            let token = scanner.scan_token();
+            if token.token_type == TokenType::Eof {
+                break;
+            }
+            positions.push((token.lexeme, token.line, token.column));
+        }
+
+        assert_eq!(
+            positions,
+            vec![
+                ("var", 1, 1),
+                ("x", 1, 5),
+                ("=", 1, 7),
+                ("1", 1, 9),
+                (";", 1, 10),
+                ("print", 2, 3),
+                ("\"a\nb\"", 2, 9),
+                ("+", 3, 4),
+                ("x", 3, 6),
+                (";", 3, 7),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_keywords() {
+        let mut scanner = init_scanner(
+            "break breaks class continue cont c try true tr throw this th catch assert if import i",
+        );
+        let types: Vec<_> = (0..17).map(|_| scanner.scan_token().token_type).collect();
+        assert_eq!(
+            types,
+            vec![
+                Break, Identifier, Class, Continue, Identifier, Identifier, Try, True, Identifier,
+                Throw, This, Identifier, Catch, Assert, If, Import, Identifier
+            ]
+        );
+    }
+
+    #[test]
+    fn test_non_ascii_source() {
+        let mut scanner = init_scanner("print \"héllo → wörld\";\nvar café_1 = π; → _x a");
+        let mut tokens = Vec::new();
+        loop {
+            let token = scanner.scan_token();
+            if token.token_type == Eof {
+                break;
+            }
+            tokens.push((token.token_type, token.lexeme, token.line, token.column));
+        }
+
+        assert_eq!(
+            tokens,
+            vec![
+                (Print, "print", 1, 1),
+                (String, "\"héllo → wörld\"", 1, 7),
+                (Semicolon, ";", 1, 22),
+                (Var, "var", 2, 1),
+                (Identifier, "café_1", 2, 5),
+                (Equal, "=", 2, 12),
+                (Identifier, "π", 2, 14),
+                (Semicolon, ";", 2, 15),
+                (TokenType::Error, "Unexpected character.", 2, 17),
+                (Identifier, "_x", 2, 19),
+                (Identifier, "a", 2, 22),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_scan_spans() {
+        let source = "var é = \"a\nb\"; @\n\"open";
+        let tokens: Vec<_> = scan(source)
+            .into_iter()
+            .map(|token| {
+                (
+                    token.token_type,
+                    &source[token.span()],
+                    token.line,
+                    token.column,
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            tokens,
+            vec![
+                (Var, "var", 1, 1),
+                (Identifier, "é", 1, 5),
+                (Equal, "=", 1, 7),
+                (String, "\"a\nb\"", 1, 9),
+                (Semicolon, ";", 2, 3),
+                (TokenType::Error, "@", 2, 5),
+                (TokenType::Error, "\"open", 3, 1),
+            ]
+        );
+        assert!(scan("").is_empty());
+    }
+
+    #[test]
+    fn test_is_incomplete() {
+        assert!(is_incomplete("fun f() {\n"));
+        assert!(is_incomplete("print (1 +\n"));
+        assert!(is_incomplete("print \"abc\n"));
+        assert!(is_incomplete("{ { }"));
+        assert!(is_incomplete("var a = [1,\n"));
+
+        assert!(!is_incomplete("print 1"));
+        assert!(!is_incomplete("fun f() {}\n"));
+        assert!(!is_incomplete("print \"{\";"));
+        assert!(!is_incomplete("}"));
+    }
+}
@@ -0,0 +1,121 @@
+//! Saved REPL sessions. A session file is the transcript of the lines typed
+//! at the prompt, keeping the inputs that compiled and the `:load` commands
+//! that ran, so replaying it line by line, the way the REPL reads them,
+//! rebuilds the globals the session defined.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Something to replay from a transcript.
+#[derive(Debug, PartialEq)]
+pub enum Entry {
+    /// Source the REPL compiled as one input
+    Input(String),
+    /// A script run with `:load`
+    Load(String),
+}
+
+#[derive(Default)]
+pub struct Session {
+    transcript: String,
+    // File the transcript is saved to after every input, from --session
+    autosave: Option<PathBuf>,
+}
+
+impl Session {
+    pub fn new(autosave: Option<PathBuf>) -> Self {
+        Session {
+            transcript: String::new(),
+            autosave,
+        }
+    }
+
+    /// Adds an input that compiled to the transcript.
+    pub fn record_input(&mut self, source: &str) {
+        self.transcript.push_str(source);
+        if !source.ends_with('\n') {
+            self.transcript.push('\n');
+        }
+    }
+
+    /// Adds a `:load` of `path`, made absolute so the session can be
+    /// restored from another directory.
+    pub fn record_load(&mut self, path: &str) {
+        let path = fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+        self.transcript
+            .push_str(&format!(":load {}\n", path.display()));
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, &self.transcript)
+    }
+
+    /// Saves the transcript to the --session file, if there is one.
+    pub fn autosave(&self) -> io::Result<()> {
+        match &self.autosave {
+            Some(path) => self.save(path),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Splits a transcript into the inputs the REPL read from it: a line
+/// starting with `:` is a command when no input is pending, and otherwise
+/// lines are gathered until they no longer end inside brackets or a string.
+/// Commands other than `:load` are skipped.
+pub fn entries(transcript: &str) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut source = String::new();
+    for line in transcript.lines() {
+        if source.is_empty() && line.starts_with(':') {
+            if let Some(path) = line.strip_prefix(":load ") {
+                entries.push(Entry::Load(path.trim().to_string()));
+            }
+            continue;
+        }
+
+        source.push_str(line);
+        source.push('\n');
+        if !rlox::is_incomplete(&source) {
+            entries.push(Entry::Input(std::mem::take(&mut source)));
+        }
+    }
+    if !source.is_empty() {
+        entries.push(Entry::Input(source));
+    }
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entries_follow_repl_input() {
+        let transcript = "var a = 1;\n\
+                          fun f() {\n\
+                          \x20 return \":load x\";\n\
+                          }\n\
+                          :load /tmp/lib.lox\n\
+                          a = f()\n";
+        assert_eq!(
+            entries(transcript),
+            vec![
+                Entry::Input("var a = 1;\n".to_string()),
+                Entry::Input("fun f() {\n  return \":load x\";\n}\n".to_string()),
+                Entry::Load("/tmp/lib.lox".to_string()),
+                Entry::Input("a = f()\n".to_string()),
+            ]
+        );
+
+        let mut session = Session::default();
+        for entry in entries(transcript) {
+            match entry {
+                Entry::Input(source) => session.record_input(&source),
+                Entry::Load(path) => session.record_load(&path),
+            }
+        }
+        assert_eq!(session.transcript, transcript);
+    }
+}
@@ -1,18 +1,35 @@
-use crate::value::Value;
+use crate::value::{LoxString, Value};
+use std::borrow::Borrow;
+use std::rc::Rc;
 
-#[derive(Clone)]
-enum Entry {
+/// Types that can be used, or looked up, as table keys.
+pub trait Key: PartialEq {
+    fn hash_key(&self) -> u32;
+}
+
+impl<T: Key + ?Sized> Key for Rc<T> {
+    fn hash_key(&self) -> u32 {
+        (**self).hash_key()
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Entry<K> {
     Empty,
-    Occupied { key: String, value: Value },
+    Occupied { key: K, value: Value },
     Tombstone,
 }
 
-pub struct Table {
-    entries: Vec<Entry>,
+/// Open-addressing hash table with linear probing, keyed by string handles
+/// unless specified otherwise. Keys carry their hash, and interned keys are
+/// found by pointer comparison.
+#[derive(Debug)]
+pub struct Table<K = Rc<LoxString>> {
+    entries: Vec<Entry<K>>,
     count: usize,
 }
 
-impl Table {
+impl<K: Key + Clone> Table<K> {
     pub fn new() -> Self {
         Table {
             entries: Vec::new(),
@@ -20,7 +37,7 @@ impl Table {
         }
     }
 
-    pub fn set(&mut self, key: String, value: Value) -> bool {
+    pub fn set(&mut self, key: K, value: Value) -> bool {
         if self.count + 1 > self.entries.len() * 3 / 4 {
             let capacity = if self.entries.len() < 8 {
                 8
@@ -41,7 +58,11 @@ impl Table {
         is_new_key
     }
 
-    pub fn get(&self, key: &str) -> Option<&Value> {
+    pub fn get<Q>(&self, key: &Q) -> Option<&Value>
+    where
+        K: Borrow<Q>,
+        Q: Key + ?Sized,
+    {
         if self.entries.is_empty() {
             return None;
         }
@@ -53,7 +74,11 @@ impl Table {
         }
     }
 
-    pub fn delete(&mut self, key: &str) -> bool {
+    pub fn delete<Q>(&mut self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Key + ?Sized,
+    {
         if self.entries.is_empty() {
             return false;
         }
@@ -68,31 +93,36 @@ impl Table {
         }
     }
 
-    pub fn find_string(&self, string: &str, hash: u32) -> Option<&str> {
-        if self.entries.is_empty() {
-            return None;
-        }
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &Value)> {
+        self.entries.iter().filter_map(|entry| match entry {
+            Entry::Occupied { key, value } => Some((key, value)),
+            _ => None,
+        })
+    }
 
-        let mut index = (hash as usize) % self.entries.len();
+    /// Consumes the table, yielding its values.
+    pub fn into_values(self) -> impl Iterator<Item = Value> {
+        self.entries.into_iter().filter_map(|entry| match entry {
+            Entry::Occupied { value, .. } => Some(value),
+            _ => None,
+        })
+    }
 
-        loop {
-            match &self.entries[index] {
-                Entry::Empty => {
-                    return None;
-                }
-                Entry::Occupied { key, .. } => {
-                    if key == string {
-                        return Some(key.as_str());
-                    }
-                }
-                Entry::Tombstone => {}
-            }
-            index = (index + 1) % self.entries.len();
+    /// Copies every entry into `to`, overwriting the keys it already has,
+    /// e.g. so a subclass starts out with its superclass's methods.
+    #[allow(dead_code)]
+    pub fn add_all(&self, to: &mut Table<K>) {
+        for (key, value) in self.iter() {
+            to.set(key.clone(), value.clone());
         }
     }
 
-    fn find_entry(&self, key: &str) -> usize {
-        let mut index = (hash_string(key) as usize) % self.entries.len();
+    fn find_entry<Q>(&self, key: &Q) -> usize
+    where
+        K: Borrow<Q>,
+        Q: Key + ?Sized,
+    {
+        let mut index = (key.hash_key() as usize) % self.entries.len();
         let mut tombstone: Option<usize> = None;
 
         loop {
@@ -101,7 +131,7 @@ impl Table {
                     return tombstone.unwrap_or(index);
                 }
                 Entry::Occupied { key: entry_key, .. } => {
-                    if entry_key == key {
+                    if entry_key.borrow() == key {
                         return index;
                     }
                 }
@@ -133,8 +163,8 @@ impl Table {
         self.entries = new_entries;
     }
 
-    fn find_entry_in(entries: &[Entry], key: &str) -> usize {
-        let mut index = (hash_string(key) as usize) % entries.len();
+    fn find_entry_in(entries: &[Entry<K>], key: &K) -> usize {
+        let mut index = (key.hash_key() as usize) % entries.len();
 
         loop {
             match &entries[index] {
@@ -152,6 +182,33 @@ impl Table {
     }
 }
 
+impl Table<Rc<LoxString>> {
+    /// Finds the key equal to `string`, whose hash is `hash`, so an interner
+    /// can return it instead of allocating a copy.
+    pub fn find_string(&self, string: &str, hash: u32) -> Option<&Rc<LoxString>> {
+        if self.entries.is_empty() {
+            return None;
+        }
+
+        let mut index = (hash as usize) % self.entries.len();
+
+        loop {
+            match &self.entries[index] {
+                Entry::Empty => {
+                    return None;
+                }
+                Entry::Occupied { key, .. } => {
+                    if key.hash_key() == hash && key.as_str() == string {
+                        return Some(key);
+                    }
+                }
+                Entry::Tombstone => {}
+            }
+            index = (index + 1) % self.entries.len();
+        }
+    }
+}
+
 pub fn hash_string(key: &str) -> u32 {
     let mut hash: u32 = 2166136261;
     for byte in key.bytes() {
@@ -165,42 +222,46 @@ pub fn hash_string(key: &str) -> u32 {
 mod tests {
     use super::*;
 
+    fn key(name: &str) -> Rc<LoxString> {
+        Rc::new(LoxString::new(name))
+    }
+
     #[test]
     fn test_table_set_and_get() {
         let mut table = Table::new();
 
-        table.set("name".to_string(), Value::string("Alice".to_string()));
-        table.set("age".to_string(), Value::number(30.0));
+        table.set(key("name"), Value::string("Alice".to_string()));
+        table.set(key("age"), Value::number(30.0));
 
-        assert_eq!(table.get("name").unwrap().as_string(), "Alice");
-        assert_eq!(table.get("age").unwrap().as_number(), 30.0);
-        assert!(table.get("unknown").is_none());
+        assert_eq!(table.get(&key("name")).unwrap().as_string(), "Alice");
+        assert_eq!(table.get(&key("age")).unwrap().as_number(), 30.0);
+        assert!(table.get(&key("unknown")).is_none());
     }
 
     #[test]
     fn test_table_update() {
         let mut table = Table::new();
 
-        let is_new = table.set("key".to_string(), Value::number(1.0));
+        let is_new = table.set(key("key"), Value::number(1.0));
         assert!(is_new);
 
-        let is_new = table.set("key".to_string(), Value::number(2.0));
+        let is_new = table.set(key("key"), Value::number(2.0));
         assert!(!is_new);
 
-        assert_eq!(table.get("key").unwrap().as_number(), 2.0);
+        assert_eq!(table.get(&key("key")).unwrap().as_number(), 2.0);
     }
 
     #[test]
     fn test_table_delete() {
         let mut table = Table::new();
 
-        table.set("key".to_string(), Value::number(42.0));
-        assert!(table.get("key").is_some());
+        table.set(key("key"), Value::number(42.0));
+        assert!(table.get(&key("key")).is_some());
 
-        assert!(table.delete("key"));
-        assert!(table.get("key").is_none());
+        assert!(table.delete(&key("key")));
+        assert!(table.get(&key("key")).is_none());
 
-        assert!(!table.delete("key"));
+        assert!(!table.delete(&key("key")));
     }
 
     #[test]
@@ -208,13 +269,92 @@ mod tests {
         let mut table = Table::new();
 
         for i in 0..100 {
-            let key = format!("key{}", i);
-            table.set(key.clone(), Value::number(i as f64));
+            table.set(key(&format!("key{}", i)), Value::number(i as f64));
         }
 
         for i in 0..100 {
-            let key = format!("key{}", i);
-            assert_eq!(table.get(&key).unwrap().as_number(), i as f64);
+            let name = key(&format!("key{}", i));
+            assert_eq!(table.get(&name).unwrap().as_number(), i as f64);
+        }
+    }
+
+    #[test]
+    fn test_table_iter() {
+        let mut table = Table::new();
+
+        table.set(key("a"), Value::number(1.0));
+        table.set(key("b"), Value::number(2.0));
+        table.set(key("c"), Value::number(3.0));
+        table.delete(&key("b"));
+
+        let mut entries: Vec<_> = table.iter().collect();
+        entries.sort_by_key(|(key, _)| key.as_str());
+        assert_eq!(
+            entries,
+            vec![
+                (&key("a"), &Value::number(1.0)),
+                (&key("c"), &Value::number(3.0))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_table_add_all() {
+        let mut from = Table::new();
+        from.set(key("a"), Value::number(1.0));
+        from.set(key("b"), Value::number(2.0));
+
+        let mut to = Table::new();
+        to.set(key("b"), Value::nil());
+        to.set(key("c"), Value::number(3.0));
+        from.add_all(&mut to);
+
+        assert_eq!(to.iter().count(), 3);
+        assert_eq!(to.get(&key("a")), Some(&Value::number(1.0)));
+        assert_eq!(to.get(&key("b")), Some(&Value::number(2.0)));
+        assert_eq!(to.get(&key("c")), Some(&Value::number(3.0)));
+        assert_eq!(from.iter().count(), 2);
+    }
+
+    #[test]
+    fn test_keys_carry_their_hash() {
+        let name = key("name");
+        assert_eq!(name.hash_key(), hash_string("name"));
+
+        // The key found is the handle that was stored, not a copy
+        let mut table = Table::new();
+        table.set(Rc::clone(&name), Value::nil());
+        let (stored, _) = table.iter().next().unwrap();
+        assert!(Rc::ptr_eq(stored, &name));
+        assert!(table.get(&LoxString::new("name")).is_some());
+    }
+
+    #[test]
+    fn test_map_keys() {
+        use crate::value::MapKey;
+
+        let mut table: Table<MapKey> = Table::new();
+        for i in 0..20 {
+            table.set(
+                MapKey::from_value(&Value::number(i as f64)).unwrap(),
+                Value::nil(),
+            );
         }
+        table.set(
+            MapKey::String(Rc::new(LoxString::new("1"))),
+            Value::bool(true),
+        );
+
+        let key = |value: Value| MapKey::from_value(&value).unwrap();
+        assert_eq!(table.get(&key(Value::number(19.0))), Some(&Value::nil()));
+        assert_eq!(table.get(&key(Value::number(-0.0))), Some(&Value::nil()));
+        assert_eq!(
+            table.get(&key(Value::string("1".to_string()))),
+            Some(&Value::bool(true))
+        );
+        assert!(table.delete(&key(Value::number(1.0))));
+        assert_eq!(table.get(&key(Value::number(1.0))), None);
+        assert_eq!(table.iter().count(), 20);
+        assert!(MapKey::from_value(&Value::nil()).is_none());
     }
 }
@@ -1,23 +1,259 @@
-struct ValueArray {
-    values: Vec<Value>,
+use crate::chunk::Chunk;
+use crate::table::{Key, Table, hash_string};
+use crate::vm::VM;
+use std::cell::RefCell;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
+use std::ops::Deref;
+use std::rc::Rc;
+
+/// An immutable string that carries its hash, so tables don't rehash it on
+/// every lookup. The VM interns the strings in compiled code, which makes
+/// comparing them a pointer comparison.
+pub struct LoxString {
+    hash: u32,
+    chars: Box<str>,
+}
+
+impl LoxString {
+    pub fn new(chars: impl Into<Box<str>>) -> Self {
+        let chars = chars.into();
+        LoxString {
+            hash: hash_string(&chars),
+            chars,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.chars
+    }
 }
 
-impl ValueArray {
-    fn new() -> Self {
-        ValueArray { values: Vec::new() }
+impl Deref for LoxString {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.chars
     }
+}
 
-    fn write(&mut self, value: Value) {
-        self.values.push(value);
+impl PartialEq for LoxString {
+    fn eq(&self, other: &Self) -> bool {
+        // Interned strings are equal only to themselves
+        std::ptr::eq(self, other) || (self.hash == other.hash && self.chars == other.chars)
     }
 }
 
+impl Eq for LoxString {}
+
+impl Hash for LoxString {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u32(self.hash);
+    }
+}
+
+impl Key for LoxString {
+    fn hash_key(&self) -> u32 {
+        self.hash
+    }
+}
+
+impl fmt::Debug for LoxString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&*self.chars, f)
+    }
+}
+
+impl fmt::Display for LoxString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.chars)
+    }
+}
+
+#[derive(Debug)]
+pub struct Function {
+    pub arity: usize,
+    pub chunk: Chunk,
+    pub name: Option<String>,
+    /// Path of the imported module the function was compiled from, or None
+    /// for the main script
+    pub module: Option<Rc<str>>,
+}
+
+impl Function {
+    pub fn new(name: Option<String>) -> Self {
+        Function {
+            arity: 0,
+            chunk: Chunk::new(),
+            name,
+            module: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Class {
+    pub name: String,
+    pub methods: RefCell<Table>,
+}
+
+impl Class {
+    pub fn new(name: String) -> Self {
+        Class {
+            name,
+            methods: RefCell::new(Table::new()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    pub class: Rc<Class>,
+    pub fields: Table,
+}
+
+impl Instance {
+    pub fn new(class: Rc<Class>) -> Self {
+        Instance {
+            class,
+            fields: Table::new(),
+        }
+    }
+}
+
+/// Signature of functions implemented in Rust. Errors are reported as
+/// runtime errors at the call site.
+pub type NativeFn = fn(&mut VM, &[Value]) -> Result<Value, String>;
+pub type NativeClosure = dyn Fn(&mut VM, &[Value]) -> Result<Value, String>;
+
+pub struct Native {
+    /// Expected argument count, or None if the native checks it itself
+    pub arity: Option<usize>,
+    pub function: Box<NativeClosure>,
+}
+
+impl fmt::Debug for Native {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Native")
+            .field("arity", &self.arity)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A method looked up on an instance, remembering the instance so that
+/// `this` is bound when it's eventually called.
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: Rc<Function>,
+}
+
+/// A key in a map value. Only strings and numbers can be keys; numbers are
+/// compared by their bits, with -0 folded into 0.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MapKey {
+    Number(u64),
+    String(Rc<LoxString>),
+}
+
+impl MapKey {
+    pub fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Number(n) if *n == 0.0 => Some(MapKey::Number(0)),
+            Value::Number(n) => Some(MapKey::Number(n.to_bits())),
+            Value::String(s) => Some(MapKey::String(Rc::clone(s))),
+            _ => None,
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        match self {
+            MapKey::Number(bits) => Value::number(f64::from_bits(*bits)),
+            MapKey::String(s) => Value::String(Rc::clone(s)),
+        }
+    }
+}
+
+impl Key for MapKey {
+    fn hash_key(&self) -> u32 {
+        match self {
+            MapKey::Number(bits) => (*bits ^ (*bits >> 32)) as u32,
+            MapKey::String(s) => s.hash_key(),
+        }
+    }
+}
+
+impl fmt::Display for MapKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapKey::Number(bits) => write!(f, "{}", format_number(f64::from_bits(*bits))),
+            MapKey::String(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+pub type Map = Table<MapKey>;
+
+/// How the VM holds values on its stack and in chunks' constants: NaN-boxed
+/// with the `nan-boxing` feature, and as they are otherwise.
+#[cfg(feature = "nan-boxing")]
+pub type Slot = crate::nanbox::NanBoxed;
+#[cfg(not(feature = "nan-boxing"))]
+pub type Slot = Value;
+
 #[derive(Debug, Clone)]
 pub enum Value {
     Bool(bool),
     Nil,
     Number(f64),
-    String(String),
+    String(Rc<LoxString>),
+    Function(Rc<Function>),
+    Class(Rc<Class>),
+    Instance(Rc<RefCell<Instance>>),
+    BoundMethod(Rc<BoundMethod>),
+    Native(Rc<Native>),
+    List(Rc<RefCell<Vec<Value>>>),
+    Map(Rc<RefCell<Map>>),
+}
+
+// Dropping the last reference to an object drops the values it holds, so a
+// long chain of objects, like a linked list built out of instances, would
+// be freed by a recursion as deep as the chain and overflow the stack.
+// Instead, the objects only this value refers to hand their contents over
+// to a worklist, which is emptied the same way one value at a time.
+impl Drop for Value {
+    fn drop(&mut self) {
+        let mut worklist = Vec::new();
+        take_contents(self, &mut worklist);
+        while let Some(mut value) = worklist.pop() {
+            take_contents(&mut value, &mut worklist);
+        }
+    }
+}
+
+/// Moves the values held by `value`'s object into `worklist`, if nothing
+/// else holds the object.
+fn take_contents(value: &mut Value, worklist: &mut Vec<Value>) {
+    match value {
+        Value::Instance(instance) if Rc::strong_count(instance) == 1 => {
+            if let Ok(mut instance) = instance.try_borrow_mut() {
+                worklist
+                    .extend(std::mem::replace(&mut instance.fields, Table::new()).into_values());
+            }
+        }
+        Value::List(list) if Rc::strong_count(list) == 1 => {
+            if let Ok(mut list) = list.try_borrow_mut() {
+                worklist.append(&mut list);
+            }
+        }
+        Value::Map(map) if Rc::strong_count(map) == 1 => {
+            if let Ok(mut map) = map.try_borrow_mut() {
+                worklist.extend(std::mem::replace(&mut *map, Map::new()).into_values());
+            }
+        }
+        _ => {}
+    }
 }
 
 impl Value {
@@ -34,7 +270,63 @@ impl Value {
     }
 
     pub fn string(value: String) -> Self {
-        Value::String(value)
+        Value::String(Rc::new(LoxString::new(value)))
+    }
+
+    pub fn function(value: Rc<Function>) -> Self {
+        Value::Function(value)
+    }
+
+    pub fn class(value: Rc<Class>) -> Self {
+        Value::Class(value)
+    }
+
+    pub fn instance(value: Instance) -> Self {
+        Value::Instance(Rc::new(RefCell::new(value)))
+    }
+
+    pub fn bound_method(receiver: Value, method: Rc<Function>) -> Self {
+        Value::BoundMethod(Rc::new(BoundMethod { receiver, method }))
+    }
+
+    pub fn native(value: Native) -> Self {
+        Value::Native(Rc::new(value))
+    }
+
+    pub fn list(elements: Vec<Value>) -> Self {
+        Value::List(Rc::new(RefCell::new(elements)))
+    }
+
+    pub fn map(entries: Map) -> Self {
+        Value::Map(Rc::new(RefCell::new(entries)))
+    }
+
+    /// Converts the value to the VM's representation.
+    #[cfg(feature = "nan-boxing")]
+    pub fn into_slot(self) -> Slot {
+        self.into()
+    }
+
+    #[cfg(not(feature = "nan-boxing"))]
+    pub fn into_slot(self) -> Slot {
+        self
+    }
+
+    #[cfg(feature = "nan-boxing")]
+    pub fn from_slot(slot: Slot) -> Self {
+        slot.into()
+    }
+
+    #[cfg(not(feature = "nan-boxing"))]
+    pub fn from_slot(slot: Slot) -> Self {
+        slot
+    }
+
+    /// Returns a copy of the value, as `NanBoxed::to_value` does, so code
+    /// reading slots works with either representation.
+    #[cfg(not(feature = "nan-boxing"))]
+    pub fn to_value(&self) -> Value {
+        self.clone()
     }
 
     pub fn is_bool(&self) -> bool {
@@ -69,11 +361,32 @@ impl Value {
 
     pub fn as_string(&self) -> &str {
         match self {
-            Value::String(s) => s.as_str(),
+            Value::String(s) => s,
             _ => panic!("Not a string"),
         }
     }
 
+    pub fn as_list(&self) -> Option<&Rc<RefCell<Vec<Value>>>> {
+        match self {
+            Value::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub fn as_map(&self) -> Option<&Rc<RefCell<Map>>> {
+        match self {
+            Value::Map(map) => Some(map),
+            _ => None,
+        }
+    }
+
+    pub fn as_instance(&self) -> Option<&Rc<RefCell<Instance>>> {
+        match self {
+            Value::Instance(instance) => Some(instance),
+            _ => None,
+        }
+    }
+
     pub fn is_falsey(&self) -> bool {
         matches!(self, Value::Nil | Value::Bool(false))
     }
@@ -85,17 +398,158 @@ impl PartialEq for Value {
             (Value::Bool(a), Value::Bool(b)) => a == b,
             (Value::Nil, Value::Nil) => true,
             (Value::Number(a), Value::Number(b)) => a == b,
-            (Value::String(a), Value::String(b)) => a == b,
+            (Value::String(a), Value::String(b)) => Rc::ptr_eq(a, b) || a == b,
+            (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(a, b),
+            (Value::Class(a), Value::Class(b)) => Rc::ptr_eq(a, b),
+            (Value::Instance(a), Value::Instance(b)) => Rc::ptr_eq(a, b),
+            (Value::BoundMethod(a), Value::BoundMethod(b)) => Rc::ptr_eq(a, b),
+            (Value::Native(a), Value::Native(b)) => Rc::ptr_eq(a, b),
+            (Value::List(a), Value::List(b)) => Rc::ptr_eq(a, b),
+            (Value::Map(a), Value::Map(b)) => Rc::ptr_eq(a, b),
             _ => false,
         }
     }
 }
 
-pub fn print_value(value: &Value) {
+pub fn write_value(out: &mut dyn Write, value: &Value) -> io::Result<()> {
+    write!(out, "{}", value)
+}
+
+/// Formats a value the way `print` writes it, with numbers formatted like
+/// clox does, by C's `%g`.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_nested(f, self, &mut Vec::new())
+    }
+}
+
+// `enclosing` holds the lists and maps being written, so one that contains
+// itself is written as `[...]` or `{...}` instead of recursing forever
+fn write_nested(
+    f: &mut fmt::Formatter,
+    value: &Value,
+    enclosing: &mut Vec<*const ()>,
+) -> fmt::Result {
     match value {
-        Value::Bool(b) => print!("{}", b),
-        Value::Nil => print!("nil"),
-        Value::Number(n) => print!("{}", n),
-        Value::String(s) => print!("{}", s),
+        Value::Bool(b) => write!(f, "{}", b),
+        Value::Nil => write!(f, "nil"),
+        Value::Number(n) => write!(f, "{}", format_number(*n)),
+        Value::String(s) => write!(f, "{}", s),
+        Value::Function(function) => write_function(f, function),
+        Value::Class(class) => write!(f, "{}", class.name),
+        Value::Instance(instance) => write!(f, "{} instance", instance.borrow().class.name),
+        Value::BoundMethod(bound) => write_function(f, &bound.method),
+        Value::Native(_) => write!(f, "<native fn>"),
+        Value::List(list) => {
+            let pointer = Rc::as_ptr(list) as *const ();
+            if enclosing.contains(&pointer) {
+                return write!(f, "[...]");
+            }
+            enclosing.push(pointer);
+            write!(f, "[")?;
+            for (i, element) in list.borrow().iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write_nested(f, element, enclosing)?;
+            }
+            enclosing.pop();
+            write!(f, "]")
+        }
+        Value::Map(map) => {
+            let pointer = Rc::as_ptr(map) as *const ();
+            if enclosing.contains(&pointer) {
+                return write!(f, "{{...}}");
+            }
+            enclosing.push(pointer);
+            write!(f, "{{")?;
+            for (i, (key, value)) in map.borrow().iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write_nested(f, &key.to_value(), enclosing)?;
+                write!(f, ": ")?;
+                write_nested(f, value, enclosing)?;
+            }
+            enclosing.pop();
+            write!(f, "}}")
+        }
+    }
+}
+
+fn write_function(f: &mut fmt::Formatter, function: &Function) -> fmt::Result {
+    match &function.name {
+        Some(name) => write!(f, "<fn {}>", name),
+        None => write!(f, "<script>"),
+    }
+}
+
+/// Formats a number like C's `%g`: six significant digits, no trailing
+/// zeros, and an exponent below 1e-4 or from 1e6 up.
+fn format_number(n: f64) -> String {
+    const PRECISION: i32 = 6;
+
+    if n.is_nan() {
+        return "nan".to_string();
+    }
+    if n.is_infinite() {
+        return if n > 0.0 { "inf" } else { "-inf" }.to_string();
+    }
+    if n == 0.0 {
+        return if n.is_sign_negative() { "-0" } else { "0" }.to_string();
+    }
+
+    // Round to the target precision first so the exponent reflects carries
+    let scientific = format!("{:.*e}", (PRECISION - 1) as usize, n);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+
+    if (-4..PRECISION).contains(&exponent) {
+        let decimals = (PRECISION - 1 - exponent) as usize;
+        trim_fraction(&format!("{:.*}", decimals, n)).to_string()
+    } else {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", trim_fraction(mantissa), sign, exponent.abs())
+    }
+}
+
+fn trim_fraction(digits: &str) -> &str {
+    if digits.contains('.') {
+        digits.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        digits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let list = Value::List(Rc::new(RefCell::new(vec![
+            Value::number(1.0),
+            Value::number(1e-10),
+            Value::nil(),
+        ])));
+        assert_eq!(list.to_string(), "[1, 1e-10, nil]");
+        assert_eq!(Value::number(1.0 / 3.0).to_string(), "0.333333");
+        assert_eq!(Value::bool(true).to_string(), "true");
+    }
+
+    #[test]
+    fn test_format_number() {
+        assert_eq!(format_number(1.0), "1");
+        assert_eq!(format_number(-2.5), "-2.5");
+        assert_eq!(format_number(0.1 + 0.2), "0.3");
+        assert_eq!(format_number(123456.0), "123456");
+        assert_eq!(format_number(1234567.0), "1.23457e+06");
+        assert_eq!(format_number(999999.5), "1e+06");
+        assert_eq!(format_number(1e21), "1e+21");
+        assert_eq!(format_number(0.0001), "0.0001");
+        assert_eq!(format_number(0.00001), "1e-05");
+        assert_eq!(format_number(-0.0), "-0");
+        assert_eq!(format_number(f64::INFINITY), "inf");
+        assert_eq!(format_number(f64::NAN), "nan");
     }
 }
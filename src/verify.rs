@@ -0,0 +1,428 @@
+//! Checks bytecode before the VM runs it.
+//!
+//! The VM trusts its code: it reads operands without bounds checks, pops
+//! without checking for underflow and indexes the constant pool and the
+//! stack directly. Code from the compiler upholds that trust, but a loaded
+//! bytecode file can contain anything, so every chunk is verified first.
+//!
+//! Verification decodes every instruction, checks that its operands refer
+//! to constants and globals that exist, and then follows each path through
+//! the code to compute the stack height before every instruction. Heights
+//! must agree wherever paths meet, no instruction may pop more than the
+//! stack holds or read a local past its top, and no path may run off the
+//! end of the code.
+
+use crate::chunk::{Chunk, OpCode};
+use crate::value::Value;
+
+/// Verifies `chunk`, the code of a function taking `arity` arguments, and
+/// the functions among its constants. `global_slots` is the number of
+/// globals in the VM that will run it.
+pub(crate) fn verify(chunk: &Chunk, arity: usize, global_slots: usize) -> Result<(), String> {
+    let instructions = decode(chunk, global_slots)?;
+    check_stack(chunk, &instructions, arity)?;
+
+    for constant in chunk.constants() {
+        if let Value::Function(function) = &constant.to_value() {
+            verify(&function.chunk, function.arity, global_slots)?;
+        }
+    }
+    Ok(())
+}
+
+/// The instruction starting at each offset, if one does.
+fn decode(chunk: &Chunk, global_slots: usize) -> Result<Vec<Option<OpCode>>, String> {
+    let code = &chunk.code;
+    if chunk.spans.len() != code.len() {
+        return Err("line info out of sync".to_string());
+    }
+
+    let mut instructions = vec![None; code.len()];
+    let mut offset = 0;
+    while offset < code.len() {
+        let opcode = OpCode::try_from(code[offset])
+            .map_err(|byte| format!("unknown opcode {} at {}", byte, offset))?;
+        let length = length(opcode);
+        if offset + length > code.len() {
+            return Err(format!("truncated operand at {}", offset));
+        }
+
+        let constant = match opcode {
+            OpCode::OpConstant | OpCode::OpAddConstant => Some(operand(code, offset)),
+            OpCode::OpConstantLong => {
+                Some(operand(code, offset) << 16 | short_operand(code, offset + 1))
+            }
+            _ => None,
+        };
+        if constant.is_some_and(|index| index >= chunk.constants().len()) {
+            return Err(format!("constant out of range at {}", offset));
+        }
+
+        // The VM reads these operands as names
+        if matches!(
+            opcode,
+            OpCode::OpImport
+                | OpCode::OpGetProperty
+                | OpCode::OpSetProperty
+                | OpCode::OpClass
+                | OpCode::OpMethod
+                | OpCode::OpInvoke
+        ) && !matches!(
+            chunk.constants().get(operand(code, offset)).map(|name| name.to_value()),
+            Some(Value::String(_))
+        ) {
+            return Err(format!("name is not a string constant at {}", offset));
+        }
+
+        if matches!(
+            opcode,
+            OpCode::OpDefineGlobal | OpCode::OpGetGlobal | OpCode::OpSetGlobal
+        ) {
+            let index = short_operand(code, offset);
+            if chunk
+                .globals()
+                .get(index)
+                .is_none_or(|global| global.slot >= global_slots)
+            {
+                return Err(format!("global out of range at {}", offset));
+            }
+        }
+
+        instructions[offset] = Some(opcode);
+        offset += length;
+    }
+    Ok(instructions)
+}
+
+/// Follows every path from the start of the code, tracking the stack
+/// height, which starts out with the callee and its arguments.
+fn check_stack(chunk: &Chunk, instructions: &[Option<OpCode>], arity: usize) -> Result<(), String> {
+    let code = &chunk.code;
+    let mut heights: Vec<Option<usize>> = vec![None; code.len()];
+    let mut pending = Vec::new();
+    enter(&mut heights, &mut pending, instructions, 0, arity + 1)?;
+
+    while let Some(offset) = pending.pop() {
+        let opcode = instructions[offset].expect("only instructions are entered");
+        let height = heights[offset].expect("entered offsets have heights");
+        let (pops, pushes) = stack_effect(opcode, code, offset);
+        if pops > height {
+            return Err(format!("stack underflow at {}", offset));
+        }
+
+        let slot = match opcode {
+            OpCode::OpGetLocal | OpCode::OpSetLocal => Some(operand(code, offset)),
+            OpCode::OpGetLocalLong | OpCode::OpSetLocalLong => Some(short_operand(code, offset)),
+            OpCode::OpLessLocals => Some(operand(code, offset).max(code[offset + 2] as usize)),
+            _ => None,
+        };
+        if slot.is_some_and(|slot| slot >= height) {
+            return Err(format!("local out of range at {}", offset));
+        }
+
+        let after = height - pops + pushes;
+        let next = offset + length(opcode);
+        let jump = || {
+            if opcode.is_long_jump() {
+                long_operand(code, offset)
+            } else {
+                short_operand(code, offset)
+            }
+        };
+        match opcode {
+            OpCode::OpReturn | OpCode::OpThrow => {}
+            OpCode::OpJump | OpCode::OpJumpLong => {
+                let target = next + jump();
+                enter(&mut heights, &mut pending, instructions, target, after)?;
+            }
+            OpCode::OpLoop | OpCode::OpLoopLong => {
+                let target = next
+                    .checked_sub(jump())
+                    .ok_or(format!("loop before the start at {}", offset))?;
+                enter(&mut heights, &mut pending, instructions, target, after)?;
+            }
+            OpCode::OpJumpIfFalse | OpCode::OpJumpIfFalseLong => {
+                let target = next + jump();
+                enter(&mut heights, &mut pending, instructions, target, after)?;
+                enter(&mut heights, &mut pending, instructions, next, after)?;
+            }
+            OpCode::OpTry | OpCode::OpTryLong => {
+                // The catch block starts with the exception pushed
+                let target = next + jump();
+                enter(&mut heights, &mut pending, instructions, target, after + 1)?;
+                enter(&mut heights, &mut pending, instructions, next, after)?;
+            }
+            _ => enter(&mut heights, &mut pending, instructions, next, after)?,
+        }
+    }
+    Ok(())
+}
+
+/// Records that the instruction at `offset` runs with `height` values on
+/// the stack, queueing it to be checked if it's the first path there.
+fn enter(
+    heights: &mut [Option<usize>],
+    pending: &mut Vec<usize>,
+    instructions: &[Option<OpCode>],
+    offset: usize,
+    height: usize,
+) -> Result<(), String> {
+    if offset >= instructions.len() {
+        return Err("execution runs off the end of the code".to_string());
+    }
+    if instructions[offset].is_none() {
+        return Err(format!(
+            "jump into the middle of an instruction at {}",
+            offset
+        ));
+    }
+    match heights[offset] {
+        None => {
+            heights[offset] = Some(height);
+            pending.push(offset);
+            Ok(())
+        }
+        Some(previous) if previous == height => Ok(()),
+        Some(_) => Err(format!("inconsistent stack height at {}", offset)),
+    }
+}
+
+/// The values an instruction needs on the stack and the values it leaves
+/// in their place. Instructions that only peek count those values as both.
+fn stack_effect(opcode: OpCode, code: &[u8], offset: usize) -> (usize, usize) {
+    match opcode {
+        OpCode::OpConstant
+        | OpCode::OpConstantLong
+        | OpCode::OpNil
+        | OpCode::OpTrue
+        | OpCode::OpFalse
+        | OpCode::OpGetGlobal
+        | OpCode::OpGetLocal
+        | OpCode::OpGetLocalLong
+        | OpCode::OpClass
+        | OpCode::OpImport
+        | OpCode::OpLessLocals => (0, 1),
+        OpCode::OpPop
+        | OpCode::OpPrint
+        | OpCode::OpDefineGlobal
+        | OpCode::OpThrow
+        | OpCode::OpReturn => (1, 0),
+        OpCode::OpEqual
+        | OpCode::OpNotEqual
+        | OpCode::OpGreater
+        | OpCode::OpLess
+        | OpCode::OpAdd
+        | OpCode::OpSubtract
+        | OpCode::OpMultiply
+        | OpCode::OpDivide
+        | OpCode::OpModulo
+        | OpCode::OpPower
+        | OpCode::OpSetProperty
+        | OpCode::OpIndexGet
+        | OpCode::OpMethod => (2, 1),
+        OpCode::OpNot
+        | OpCode::OpNegate
+        | OpCode::OpAddConstant
+        | OpCode::OpSetGlobal
+        | OpCode::OpSetLocal
+        | OpCode::OpSetLocalLong
+        | OpCode::OpGetProperty
+        | OpCode::OpJumpIfFalse
+        | OpCode::OpJumpIfFalseLong => (1, 1),
+        OpCode::OpIndexSet => (3, 1),
+        OpCode::OpJump
+        | OpCode::OpJumpLong
+        | OpCode::OpLoop
+        | OpCode::OpLoopLong
+        | OpCode::OpTry
+        | OpCode::OpTryLong
+        | OpCode::OpEndTry => (0, 0),
+        OpCode::OpCall => (operand(code, offset) + 1, 1),
+        OpCode::OpInvoke => (code[offset + 2] as usize + 1, 1),
+        OpCode::OpBuildList => (operand(code, offset), 1),
+        OpCode::OpBuildMap => (operand(code, offset) * 2, 1),
+        // The condition, and the message if the operand is 1
+        OpCode::OpAssert => (1 + (operand(code, offset) == 1) as usize, 0),
+    }
+}
+
+/// The length of an instruction, including its operands.
+fn length(opcode: OpCode) -> usize {
+    match opcode {
+        OpCode::OpConstant
+        | OpCode::OpImport
+        | OpCode::OpGetProperty
+        | OpCode::OpSetProperty
+        | OpCode::OpClass
+        | OpCode::OpMethod
+        | OpCode::OpGetLocal
+        | OpCode::OpSetLocal
+        | OpCode::OpCall
+        | OpCode::OpBuildList
+        | OpCode::OpBuildMap
+        | OpCode::OpAssert
+        | OpCode::OpAddConstant => 2,
+        OpCode::OpGetLocalLong
+        | OpCode::OpSetLocalLong
+        | OpCode::OpDefineGlobal
+        | OpCode::OpGetGlobal
+        | OpCode::OpSetGlobal
+        | OpCode::OpInvoke
+        | OpCode::OpJump
+        | OpCode::OpJumpIfFalse
+        | OpCode::OpLoop
+        | OpCode::OpTry
+        | OpCode::OpLessLocals => 3,
+        OpCode::OpConstantLong => 4,
+        OpCode::OpJumpLong | OpCode::OpJumpIfFalseLong | OpCode::OpLoopLong | OpCode::OpTryLong => {
+            5
+        }
+        _ => 1,
+    }
+}
+
+fn operand(code: &[u8], offset: usize) -> usize {
+    code[offset + 1] as usize
+}
+
+fn short_operand(code: &[u8], offset: usize) -> usize {
+    (code[offset + 1] as usize) << 8 | code[offset + 2] as usize
+}
+
+fn long_operand(code: &[u8], offset: usize) -> usize {
+    short_operand(code, offset) << 16 | short_operand(code, offset + 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::Span;
+    use crate::value::Function;
+    use crate::vm::VM;
+    use std::rc::Rc;
+
+    const SPAN: Span = Span { line: 1, column: 1 };
+
+    fn assemble(code: &[u8]) -> Chunk {
+        let mut chunk = Chunk::new();
+        for &byte in code {
+            chunk.write_byte(byte, SPAN);
+        }
+        chunk
+    }
+
+    fn error(chunk: &Chunk) -> String {
+        verify(chunk, 0, 0).unwrap_err()
+    }
+
+    #[test]
+    fn test_accepts_compiled_code() {
+        let mut vm = VM::new();
+        let source = "fun f(a, b) { try { return a[b]; } catch (e) { return e; } }
+                      var x = 1; while (x < 10) { x = x * 2; if (x == 4) break; }
+                      class C { m(n) { this.n = n; } } C().m(f([1], 0));";
+        let chunk = crate::compiler::compile(source, &mut vm).unwrap();
+        assert_eq!(verify(&chunk, 0, vm.global_count()), Ok(()));
+    }
+
+    #[test]
+    fn test_rejects_bad_operands() {
+        let nil = OpCode::OpNil as u8;
+        let ret = OpCode::OpReturn as u8;
+
+        assert_eq!(error(&assemble(&[255])), "unknown opcode 255 at 0");
+        assert_eq!(
+            error(&assemble(&[OpCode::OpConstant as u8])),
+            "truncated operand at 0"
+        );
+        assert_eq!(
+            error(&assemble(&[OpCode::OpConstant as u8, 0, ret])),
+            "constant out of range at 0"
+        );
+        assert_eq!(
+            error(&assemble(&[OpCode::OpGetGlobal as u8, 0, 0, ret])),
+            "global out of range at 0"
+        );
+
+        let mut chunk = assemble(&[OpCode::OpClass as u8, 0, ret]);
+        chunk.add_constant(Value::number(1.0));
+        assert_eq!(error(&chunk), "name is not a string constant at 0");
+
+        let mut chunk = assemble(&[nil, ret]);
+        chunk.spans.pop();
+        assert_eq!(error(&chunk), "line info out of sync");
+    }
+
+    #[test]
+    fn test_rejects_bad_stack_use() {
+        let nil = OpCode::OpNil as u8;
+        let pop = OpCode::OpPop as u8;
+        let ret = OpCode::OpReturn as u8;
+        let jump_if_false = OpCode::OpJumpIfFalse as u8;
+
+        // The callee's slot is the only value to start with
+        assert_eq!(
+            error(&assemble(&[pop, pop, nil, ret])),
+            "stack underflow at 1"
+        );
+        assert_eq!(
+            error(&assemble(&[OpCode::OpGetLocal as u8, 1, ret])),
+            "local out of range at 0"
+        );
+        assert_eq!(
+            verify(&assemble(&[OpCode::OpGetLocal as u8, 0, ret]), 0, 0),
+            Ok(())
+        );
+
+        // One path pushes nil, the other doesn't
+        assert_eq!(
+            error(&assemble(&[nil, jump_if_false, 0, 1, nil, nil, ret])),
+            "inconsistent stack height at 5"
+        );
+        assert_eq!(
+            error(&assemble(&[
+                nil,
+                OpCode::OpJump as u8,
+                0,
+                1,
+                OpCode::OpGetLocal as u8,
+                0,
+                ret
+            ])),
+            "jump into the middle of an instruction at 5"
+        );
+        assert_eq!(
+            error(&assemble(&[OpCode::OpLoop as u8, 0, 9, ret])),
+            "loop before the start at 0"
+        );
+        assert_eq!(
+            error(&assemble(&[nil, OpCode::OpPrint as u8])),
+            "execution runs off the end of the code"
+        );
+    }
+
+    #[test]
+    fn test_checks_nested_functions() {
+        let script = |arity| {
+            let mut function = Function::new(Some("f".to_string()));
+            function.arity = arity;
+            function.chunk = assemble(&[OpCode::OpGetLocal as u8, 2, OpCode::OpReturn as u8]);
+            let mut chunk = assemble(&[OpCode::OpConstant as u8, 0, OpCode::OpReturn as u8]);
+            chunk.add_constant(Value::function(Rc::new(function)));
+            chunk
+        };
+
+        // Slots 0 to 2 hold the callee and its arguments
+        assert_eq!(verify(&script(2), 0, 0), Ok(()));
+        assert_eq!(error(&script(1)), "local out of range at 0");
+    }
+
+    #[test]
+    fn test_interpret_rejects_invalid_chunks() {
+        let mut vm = VM::new();
+        let error = vm
+            .interpret(assemble(&[OpCode::OpPop as u8, OpCode::OpPop as u8]))
+            .unwrap_err();
+        assert_eq!(error.message, "Invalid bytecode: stack underflow at 1.");
+    }
+}
@@ -1,252 +1,1504 @@
-use crate::chunk::{Chunk, OpCode};
+use crate::chunk::{Chunk, LoadError, OpCode};
+use crate::error::{Limit, RloxError, RuntimeError, TraceFrame};
+use crate::gc::{GcHook, Heap, HeapStats};
 use crate::table::Table;
-use crate::value::Value;
+use crate::value::{
+    Class, Function, Instance, LoxString, Map, MapKey, Native, NativeFn, Slot, Value,
+};
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
-const STACK_MAX: usize = 256;
+const FRAMES_MAX: usize = 64;
+const STACK_MAX: usize = FRAMES_MAX * 256;
 
-#[derive(Debug)]
-pub enum InterpretResult {
-    Ok,
-    RuntimeError,
-    CompileError,
+#[derive(Debug, Clone)]
+pub struct ExecutionStats {
+    pub opcode_counts: [u64; 256],
+    /// Instructions executed per source line, keyed by the path of the
+    /// module the line is in, or None for the script
+    pub line_counts: HashMap<(Option<Rc<str>>, usize), u64>,
+    pub peak_stack_depth: usize,
+    pub allocations: u64,
+    pub elapsed: Duration,
 }
 
-pub struct VM {
-    chunk: Option<Chunk>,
+impl ExecutionStats {
+    fn new() -> Self {
+        ExecutionStats {
+            opcode_counts: [0; 256],
+            line_counts: HashMap::new(),
+            peak_stack_depth: 0,
+            allocations: 0,
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    pub fn instructions_executed(&self) -> u64 {
+        self.opcode_counts.iter().sum()
+    }
+
+    pub fn executed_opcodes(&self) -> impl Iterator<Item = (OpCode, u64)> + '_ {
+        self.opcode_counts
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .filter_map(|(byte, count)| Some((OpCode::try_from(byte as u8).ok()?, *count)))
+    }
+
+    /// The lines that executed the most instructions, busiest first, as
+    /// their module, line number and instruction count.
+    pub fn hottest_lines(&self) -> Vec<(Option<&str>, usize, u64)> {
+        let mut lines: Vec<_> = self
+            .line_counts
+            .iter()
+            .map(|((module, line), count)| (module.as_deref(), *line, *count))
+            .collect();
+        lines.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| (a.0, a.1).cmp(&(b.0, b.1))));
+        lines
+    }
+}
+
+/// Source of time for the VM. The virtual clock advances one microsecond per
+/// executed instruction, so deterministic runs observe identical timings.
+enum Clock {
+    Wall(Instant),
+    Virtual(u64),
+}
+
+impl Clock {
+    /// The wall clock, where there is one. wasm32-unknown-unknown has none,
+    /// so time there is virtual, as in deterministic runs.
+    fn wall() -> Clock {
+        if cfg!(all(target_arch = "wasm32", target_os = "unknown")) {
+            Clock::Virtual(0)
+        } else {
+            Clock::Wall(Instant::now())
+        }
+    }
+
+    fn now(&self) -> Duration {
+        match self {
+            Clock::Wall(start) => start.elapsed(),
+            Clock::Virtual(ticks) => Duration::from_micros(*ticks),
+        }
+    }
+}
+
+/// SplitMix64, the generator behind `random()`. Any seed, zero included,
+/// gives a full-period sequence.
+struct Rng(u64);
+
+impl Rng {
+    /// A generator seeded differently in every run.
+    fn from_entropy() -> Rng {
+        // The standard library seeds each RandomState randomly
+        Rng(RandomState::new().build_hasher().finish())
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+/// Parts of the language an embedder can turn off, for scripts that
+/// shouldn't be able to use them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    /// `print` statements
+    Print,
+    /// `import` and the natives that do I/O: `readLine`, `readFile` and
+    /// `writeFile`
+    Io,
+    /// Class declarations
+    Classes,
+}
+
+/// Limits on the resources a script may use and the features it may use,
+/// for running untrusted code. By default only the stack and call depth are
+/// bounded, as in clox, and everything is enabled.
+#[derive(Debug, Clone, Copy)]
+pub struct VmOptions {
+    max_stack_depth: usize,
+    max_call_depth: usize,
+    instruction_budget: Option<u64>,
+    timeout: Option<Duration>,
+    // One bit per Feature
+    disabled: u8,
+}
+
+impl Default for VmOptions {
+    fn default() -> Self {
+        VmOptions {
+            max_stack_depth: STACK_MAX,
+            max_call_depth: FRAMES_MAX,
+            instruction_budget: None,
+            timeout: None,
+            disabled: 0,
+        }
+    }
+}
+
+impl VmOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Values the stack may hold at once.
+    pub fn max_stack_depth(mut self, depth: usize) -> Self {
+        self.max_stack_depth = depth;
+        self
+    }
+
+    /// Calls that may be active at once.
+    pub fn max_call_depth(mut self, depth: usize) -> Self {
+        self.max_call_depth = depth;
+        self
+    }
+
+    /// Instructions each call to [`VM::interpret`] may run.
+    pub fn instruction_budget(mut self, instructions: u64) -> Self {
+        self.instruction_budget = Some(instructions);
+        self
+    }
+
+    /// Time each call to [`VM::interpret`] may take, as measured by the
+    /// VM's clock.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Turns off `feature`: code using it fails to compile, and bytecode
+    /// using it fails at runtime.
+    pub fn disable(mut self, feature: Feature) -> Self {
+        self.disabled |= 1 << feature as u8;
+        self
+    }
+
+    pub fn is_enabled(&self, feature: Feature) -> bool {
+        self.disabled & (1 << feature as u8) == 0
+    }
+
+    pub fn build(self) -> VM {
+        VM::with_options(self)
+    }
+}
+
+/// The error for using a disabled feature through `name`.
+pub(crate) fn feature_disabled(name: &str) -> String {
+    format!("Feature disabled: '{}'.", name)
+}
+
+// Instructions between checks of the timeout, since reading the clock is
+// slow
+const TIMEOUT_INTERVAL: u64 = 1024;
+
+struct CallFrame {
+    function: Rc<Function>,
     ip: usize,
-    stack: Vec<Value>,
+    // Index of the frame's first stack slot, which holds the callee
+    slots: usize,
+}
+
+/// An active `try` block, recording where to resume when an exception is
+/// thrown inside it.
+struct Handler {
+    frame_count: usize,
+    stack_len: usize,
+    catch_ip: usize,
+}
+
+/// A module whose top-level code is running.
+struct Import {
+    // Canonical path, to detect cycles
+    path: PathBuf,
+    // Directory that imports inside the module are relative to
+    dir: PathBuf,
+    frame_count: usize,
+}
+
+pub struct VM {
+    frames: Vec<CallFrame>,
+    handlers: Vec<Handler>,
+    imports: Vec<Import>,
+    // Canonical paths of the modules imported so far
+    modules: HashSet<PathBuf>,
+    // Directory that the main script's imports are relative to
+    base_dir: PathBuf,
+    // Canonical path of the main script, which modules can't import
+    script_path: Option<PathBuf>,
+    stack: Vec<Slot>,
     strings: Table,
-    globals: Table,
+    // Values of the global variables by slot, or None until defined
+    globals: Vec<Option<Value>>,
+    // Slot of every global name compiled or defined so far
+    global_slots: HashMap<Rc<LoxString>, usize>,
+    // Looked up on every class call
+    init_string: Rc<LoxString>,
+    stats: Option<ExecutionStats>,
+    heap: Heap,
+    // Called after each garbage collection
+    gc_hook: Option<Box<GcHook>>,
+    clock: Clock,
+    rng: Rng,
+    trace: bool,
+    interrupt: Arc<AtomicBool>,
+    output: Box<dyn Write>,
+    // None reads from stdin
+    input: Option<Box<dyn BufRead>>,
+    options: VmOptions,
+    // False while the I/O natives are taken away, even with Feature::Io on
+    io_natives: bool,
+    // What the current script has left of the instruction budget
+    instructions_left: u64,
+    deadline: Option<Duration>,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VM {
     pub fn new() -> Self {
-        VM {
-            chunk: None,
-            ip: 0,
+        let mut vm = VM {
+            frames: Vec::with_capacity(FRAMES_MAX),
+            handlers: Vec::new(),
+            imports: Vec::new(),
+            modules: HashSet::new(),
+            base_dir: PathBuf::new(),
+            script_path: None,
             stack: Vec::with_capacity(STACK_MAX),
             strings: Table::new(),
-            globals: Table::new(),
+            globals: Vec::new(),
+            global_slots: HashMap::new(),
+            init_string: Rc::new(LoxString::new("init")),
+            stats: None,
+            heap: Heap::new(),
+            gc_hook: None,
+            clock: Clock::wall(),
+            rng: Rng::from_entropy(),
+            trace: false,
+            interrupt: Arc::new(AtomicBool::new(false)),
+            output: Box::new(io::stdout()),
+            input: None,
+            options: VmOptions::default(),
+            io_natives: true,
+            instructions_left: u64::MAX,
+            deadline: None,
+        };
+        vm.init_string = vm.intern_string("init".to_string());
+        crate::natives::define_natives(&mut vm);
+        vm
+    }
+
+    pub fn with_options(options: VmOptions) -> Self {
+        let mut vm = VM::new();
+        vm.options = options;
+        vm.update_io_natives();
+        vm
+    }
+
+    pub fn is_enabled(&self, feature: Feature) -> bool {
+        self.options.is_enabled(feature)
+    }
+
+    /// Creates a VM whose `print` statements write to `writer` instead of
+    /// stdout.
+    pub fn with_output(writer: impl Write + 'static) -> Self {
+        let mut vm = VM::new();
+        vm.set_output(writer);
+        vm
+    }
+
+    pub fn set_output(&mut self, writer: impl Write + 'static) {
+        self.output = Box::new(writer);
+    }
+
+    /// Makes `readLine` read from `reader` instead of stdin.
+    pub fn set_input(&mut self, reader: impl BufRead + 'static) {
+        self.input = Some(Box::new(reader));
+    }
+
+    /// Reads the next line of input without its line terminator, or None
+    /// at end of input.
+    pub(crate) fn read_line(&mut self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        let read = match self.input.as_mut() {
+            Some(input) => input.read_line(&mut line)?,
+            None => io::stdin().read_line(&mut line)?,
+        };
+        if read == 0 {
+            return Ok(None);
+        }
+        if line.ends_with('\n') {
+            line.pop();
+            if line.ends_with('\r') {
+                line.pop();
+            }
+        }
+        Ok(Some(line))
+    }
+
+    /// Makes a Rust function callable from scripts as the global `name`.
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        let native = Native {
+            arity: Some(arity),
+            function: Box::new(function),
+        };
+        self.set_global(name, Value::native(native));
+    }
+
+    /// Defines or overwrites the global `name`, e.g. to pass host data to a
+    /// script before running it.
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        let name = self.intern_string(name.to_string());
+        let slot = self.global_slot(name);
+        self.globals[slot] = Some(value);
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        let slot = *self.global_slots.get(&LoxString::new(name))?;
+        self.globals[slot].clone()
+    }
+
+    /// Iterates over the defined globals, in no particular order.
+    pub fn globals(&self) -> impl Iterator<Item = (&str, &Value)> {
+        self.global_slots
+            .iter()
+            .filter_map(|(name, &slot)| Some((name.as_str(), self.globals[slot].as_ref()?)))
+    }
+
+    /// Number of global slots allocated so far, defined or not.
+    pub(crate) fn global_count(&self) -> usize {
+        self.globals.len()
+    }
+
+    /// Returns the slot holding the global `name`, allocating an undefined
+    /// one the first time the name is seen.
+    pub(crate) fn global_slot(&mut self, name: Rc<LoxString>) -> usize {
+        let next = self.globals.len();
+        let slot = *self.global_slots.entry(name).or_insert(next);
+        if slot == next {
+            self.globals.push(None);
+        }
+        slot
+    }
+
+    /// Registers a host callback as the global function `name`. It accepts
+    /// any number of arguments, and an error it returns aborts the script
+    /// with a runtime error.
+    pub fn register_fn<F>(&mut self, name: &str, function: F)
+    where
+        F: Fn(&[Value]) -> Result<Value, RloxError> + 'static,
+    {
+        let native = Native {
+            arity: None,
+            function: Box::new(move |_vm: &mut VM, args: &[Value]| {
+                function(args).map_err(|error| error.message().to_string())
+            }),
+        };
+        self.set_global(name, Value::native(native));
+    }
+
+    /// Time since the VM started, or the virtual time in deterministic mode.
+    pub fn elapsed(&self) -> Duration {
+        self.clock.now()
+    }
+
+    /// Returns a token that aborts the running script when set, e.g. from a
+    /// signal handler. It is cleared at the start of each interpret call.
+    pub fn interrupt_handle(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.interrupt)
+    }
+
+    /// Prints the stack and the instruction about to run before executing
+    /// each instruction.
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    /// Collects the instances, lists and maps that are only reachable from
+    /// each other. This happens automatically as the script allocates.
+    pub fn collect_garbage(&mut self) {
+        self.heap.collect();
+        if let Some(hook) = self.gc_hook.as_mut() {
+            hook(&self.heap.stats());
+        }
+    }
+
+    pub fn heap_stats(&self) -> HeapStats {
+        self.heap.stats()
+    }
+
+    /// Calls `hook` with the heap's statistics after each collection.
+    pub fn on_collect(&mut self, hook: impl FnMut(&HeapStats) + 'static) {
+        self.gc_hook = Some(Box::new(hook));
+    }
+
+    /// Collects garbage on every allocation, which is slow but exposes
+    /// objects that are freed while still in use.
+    pub fn set_gc_stress(&mut self, stress: bool) {
+        self.heap.stress = stress;
+    }
+
+    /// Resolves the main script's imports relative to the directory
+    /// containing `path`, rather than the working directory.
+    pub fn set_script_path(&mut self, path: impl AsRef<Path>) {
+        let path = path.as_ref();
+        self.base_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        self.script_path = fs::canonicalize(path).ok();
+    }
+
+    /// Denies scripts access to files and input, as building the VM with
+    /// `Feature::Io` disabled does: `import` is an error and the I/O natives
+    /// aren't defined.
+    pub fn set_sandboxed(&mut self, sandboxed: bool) {
+        let io = 1 << Feature::Io as u8;
+        if sandboxed {
+            self.options.disabled |= io;
+        } else {
+            self.options.disabled &= !io;
+        }
+        self.update_io_natives();
+    }
+
+    /// Takes away the I/O natives, or gives them back, while `import` keeps
+    /// working. The REPL replays saved sessions this way, so that they load
+    /// their modules again without repeating their other I/O.
+    pub fn set_io_natives(&mut self, enabled: bool) {
+        self.io_natives = enabled;
+        self.update_io_natives();
+    }
+
+    /// Whether scripts may call the I/O natives.
+    pub(crate) fn io_natives_enabled(&self) -> bool {
+        self.io_natives && self.is_enabled(Feature::Io)
+    }
+
+    fn update_io_natives(&mut self) {
+        if self.io_natives_enabled() {
+            crate::natives::define_io_natives(self);
+            return;
+        }
+        for name in crate::natives::IO_NATIVES {
+            let slot = self.global_slots[&LoxString::new(name)];
+            // Leave globals the script or host defined in their place
+            if matches!(self.globals[slot], Some(Value::Native(_))) {
+                self.globals[slot] = None;
+            }
+        }
+    }
+
+    pub fn is_sandboxed(&self) -> bool {
+        !self.is_enabled(Feature::Io)
+    }
+
+    /// With a seed, pins `clock()` to virtual time and seeds `random()`
+    /// with it, so that runs print the same on every machine. With None,
+    /// time is real again and `random()` is seeded differently every run.
+    pub fn set_deterministic(&mut self, seed: Option<u64>) {
+        match seed {
+            Some(seed) => {
+                self.clock = Clock::Virtual(0);
+                self.rng = Rng(seed);
+            }
+            None => {
+                self.clock = Clock::wall();
+                self.rng = Rng::from_entropy();
+            }
         }
     }
 
-    pub fn interpret(&mut self, chunk: Chunk) -> InterpretResult {
-        self.chunk = Some(chunk);
-        self.ip = 0;
+    /// The next number from `random()`'s generator, in `[0, 1)`.
+    pub(crate) fn random(&mut self) -> f64 {
+        // The top 53 bits fill a double's mantissa exactly
+        (self.rng.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Runs `chunk` as a script. Chunks that wouldn't run safely, like ones
+    /// assembled by hand with out-of-range operands, fail without running.
+    pub fn interpret(&mut self, chunk: Chunk) -> Result<(), RuntimeError> {
+        if let Err(reason) = crate::verify::verify(&chunk, 0, self.globals.len()) {
+            return Err(self.runtime_error(&format!("Invalid bytecode: {}.", reason)));
+        }
+
+        let mut script = Function::new(None);
+        script.chunk = chunk;
+        let script = Rc::new(script);
+
+        self.interrupt.store(false, Ordering::Relaxed);
+        self.instructions_left = self.options.instruction_budget.unwrap_or(u64::MAX);
+        self.deadline = self
+            .options
+            .timeout
+            .map(|timeout| self.clock.now() + timeout);
+        self.push(Value::function(Rc::clone(&script)));
+        self.call(script, 0)?;
         self.run()
     }
 
-    fn run(&mut self) -> InterpretResult {
+    /// Runs until the script finishes, resuming at the innermost `catch`
+    /// block whenever an instruction fails.
+    fn run(&mut self) -> Result<(), RuntimeError> {
+        loop {
+            let error = match self.execute() {
+                Ok(()) => return Ok(()),
+                Err(error) => error,
+            };
+            // Interrupting must stop the script, whatever it catches, and so
+            // must running out of a resource
+            if self.interrupt.load(Ordering::Relaxed)
+                || error.limit.is_some()
+                || !self.catch(Value::string(error.message.clone()))
+            {
+                self.stack.clear();
+                self.frames.clear();
+                self.handlers.clear();
+                self.imports.clear();
+                return Err(error);
+            }
+        }
+    }
+
+    /// Unwinds to the innermost handler and pushes `exception` for its
+    /// `catch` block. Returns false if there is no handler.
+    fn catch(&mut self, exception: Value) -> bool {
+        let Some(handler) = self.handlers.pop() else {
+            return false;
+        };
+        // Only bytecode that skips OpEndTry can pop what the handler expects
+        // to find on the stack
+        if self.stack.len() < handler.stack_len {
+            return false;
+        }
+        self.frames.truncate(handler.frame_count);
+        self.stack.truncate(handler.stack_len);
+        self.end_imports();
+        self.frame_mut().ip = handler.catch_ip;
+        self.push(exception);
+        true
+    }
+
+    pub fn interpret_with_stats(
+        &mut self,
+        chunk: Chunk,
+    ) -> (Result<(), RuntimeError>, ExecutionStats) {
+        self.stats = Some(ExecutionStats::new());
+        let start = self.clock.now();
+        let result = self.interpret(chunk);
+
+        let mut stats = self.stats.take().unwrap();
+        stats.elapsed = self.clock.now() - start;
+        (result, stats)
+    }
+
+    fn trace_instruction(&mut self) -> io::Result<()> {
+        write!(self.output, "          ")?;
+        for value in self.stack.iter() {
+            write!(self.output, "[ {} ]", value)?;
+        }
+        writeln!(self.output)?;
+        let frame = self.frames.last().unwrap();
+        crate::debug::disassemble_instruction(&mut self.output, &frame.function.chunk, frame.ip)?;
+        Ok(())
+    }
+
+    fn execute(&mut self) -> Result<(), RuntimeError> {
         loop {
+            if self.trace
+                && let Err(error) = self.trace_instruction()
+            {
+                return Err(self.runtime_error(&format!("Failed to trace: {}.", error)));
+            }
+
             let instruction = self.read_byte();
+            if let Some(stats) = self.stats.as_mut() {
+                stats.opcode_counts[instruction as usize] += 1;
+                if let Some(frame) = self.frames.last() {
+                    let line = frame.function.chunk.spans[frame.ip - 1].line;
+                    let key = (frame.function.module.clone(), line);
+                    *stats.line_counts.entry(key).or_insert(0) += 1;
+                }
+            }
+            if let Clock::Virtual(ticks) = &mut self.clock {
+                *ticks += 1;
+            }
+            if self.interrupt.load(Ordering::Relaxed) {
+                return Err(self.runtime_error("Interrupted."));
+            }
+            self.check_limits()?;
+
+            let Ok(instruction) = OpCode::try_from(instruction) else {
+                return Err(self.runtime_error(&format!("Unknown opcode {}.", instruction)));
+            };
             match instruction {
-                x if x == OpCode::OpConstant as u8 => {
-                    let constant = self.read_constant();
-                    self.push(constant);
+                OpCode::OpConstant => {
+                    let constant = self.read_constant_slot();
+                    self.push_slot(constant);
+                }
+                OpCode::OpConstantLong => {
+                    let [high, middle, low] = self.read_bytes();
+                    let index = u32::from_be_bytes([0, high, middle, low]) as usize;
+                    let constant = self.frame().function.chunk.get_constant(index).clone();
+                    self.push_slot(constant);
                 }
-                x if x == OpCode::OpNil as u8 => {
+                OpCode::OpNil => {
                     self.push(Value::nil());
                 }
-                x if x == OpCode::OpTrue as u8 => {
+                OpCode::OpTrue => {
                     self.push(Value::bool(true));
                 }
-                x if x == OpCode::OpFalse as u8 => {
+                OpCode::OpFalse => {
                     self.push(Value::bool(false));
                 }
-                x if x == OpCode::OpPop as u8 => {
+                OpCode::OpPop => {
                     self.pop();
                 }
-                x if x == OpCode::OpEqual as u8 => {
+                OpCode::OpEqual => {
                     let b = self.pop();
                     let a = self.pop();
                     self.push(Value::bool(a == b));
                 }
-                x if x == OpCode::OpGreater as u8 => {
+                OpCode::OpGreater => {
                     if !self.peek(0).is_number() || !self.peek(1).is_number() {
-                        self.runtime_error("Operands must be numbers.");
-                        return InterpretResult::RuntimeError;
+                        return Err(self.runtime_error("Operands must be numbers."));
                     }
                     let b = self.pop().as_number();
                     let a = self.pop().as_number();
                     self.push(Value::bool(a > b));
                 }
-                x if x == OpCode::OpLess as u8 => {
+                OpCode::OpLess => {
                     if !self.peek(0).is_number() || !self.peek(1).is_number() {
-                        self.runtime_error("Operands must be numbers.");
-                        return InterpretResult::RuntimeError;
+                        return Err(self.runtime_error("Operands must be numbers."));
                     }
                     let b = self.pop().as_number();
                     let a = self.pop().as_number();
                     self.push(Value::bool(a < b));
                 }
-                x if x == OpCode::OpNot as u8 => {
+                OpCode::OpNot => {
                     let value = self.pop();
                     self.push(Value::bool(value.is_falsey()));
                 }
-                x if x == OpCode::OpNegate as u8 => {
+                OpCode::OpNotEqual => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    self.push(Value::bool(a != b));
+                }
+                OpCode::OpLessLocals => {
+                    let [first, second] = self.read_bytes();
+                    let slots = self.frame().slots;
+                    let (a, b) = (
+                        self.stack[slots + first as usize].to_value(),
+                        self.stack[slots + second as usize].to_value(),
+                    );
+                    if !a.is_number() || !b.is_number() {
+                        return Err(self.runtime_error("Operands must be numbers."));
+                    }
+                    let less = a.as_number() < b.as_number();
+                    self.push(Value::bool(less));
+                }
+                OpCode::OpNegate => {
                     if !self.peek(0).is_number() {
-                        self.runtime_error("Operand must be a number.");
-                        return InterpretResult::RuntimeError;
+                        return Err(self.runtime_error("Operand must be a number."));
                     }
                     let value = self.pop().as_number();
                     self.push(Value::number(-value));
                 }
-                x if x == OpCode::OpAdd as u8 => {
+                OpCode::OpAdd => {
                     if self.peek(0).is_string() && self.peek(1).is_string() {
                         let b = self.pop();
                         let a = self.pop();
-                        let result = format!("{}{}", a.as_string(), b.as_string());
-                        self.push(Value::string(result));
+                        let result = Value::string(format!("{}{}", a.as_string(), b.as_string()));
+                        self.record_allocation(&result);
+                        self.push(result);
                     } else if self.peek(0).is_number() && self.peek(1).is_number() {
                         let b = self.pop().as_number();
                         let a = self.pop().as_number();
                         self.push(Value::number(a + b));
                     } else {
-                        self.runtime_error("Operands must be two numbers or two strings.");
-                        return InterpretResult::RuntimeError;
+                        return Err(
+                            self.runtime_error("Operands must be two numbers or two strings.")
+                        );
+                    }
+                }
+                OpCode::OpAddConstant => {
+                    let b = self.read_constant();
+                    let a = self.peek(0).to_value();
+                    if a.is_string() && b.is_string() {
+                        let result = Value::string(format!("{}{}", a.as_string(), b.as_string()));
+                        self.record_allocation(&result);
+                        self.pop();
+                        self.push(result);
+                    } else if a.is_number() && b.is_number() {
+                        let a = self.pop().as_number();
+                        self.push(Value::number(a + b.as_number()));
+                    } else {
+                        return Err(
+                            self.runtime_error("Operands must be two numbers or two strings.")
+                        );
                     }
                 }
-                x if x == OpCode::OpSubtract as u8 => {
+                OpCode::OpSubtract => {
                     if !self.peek(0).is_number() || !self.peek(1).is_number() {
-                        self.runtime_error("Operands must be numbers.");
-                        return InterpretResult::RuntimeError;
+                        return Err(self.runtime_error("Operands must be numbers."));
                     }
                     let b = self.pop().as_number();
                     let a = self.pop().as_number();
                     self.push(Value::number(a - b));
                 }
-                x if x == OpCode::OpMultiply as u8 => {
+                OpCode::OpMultiply => {
                     if !self.peek(0).is_number() || !self.peek(1).is_number() {
-                        self.runtime_error("Operands must be numbers.");
-                        return InterpretResult::RuntimeError;
+                        return Err(self.runtime_error("Operands must be numbers."));
                     }
                     let b = self.pop().as_number();
                     let a = self.pop().as_number();
                     self.push(Value::number(a * b));
                 }
-                x if x == OpCode::OpDivide as u8 => {
+                OpCode::OpDivide => {
                     if !self.peek(0).is_number() || !self.peek(1).is_number() {
-                        self.runtime_error("Operands must be numbers.");
-                        return InterpretResult::RuntimeError;
+                        return Err(self.runtime_error("Operands must be numbers."));
                     }
                     let b = self.pop().as_number();
                     let a = self.pop().as_number();
                     self.push(Value::number(a / b));
                 }
-                x if x == OpCode::OpPrint as u8 => {
-                    crate::value::print_value(&self.pop());
-                    println!();
+                OpCode::OpModulo => {
+                    if !self.peek(0).is_number() || !self.peek(1).is_number() {
+                        return Err(self.runtime_error("Operands must be numbers."));
+                    }
+                    let b = self.pop().as_number();
+                    let a = self.pop().as_number();
+                    // Like C's fmod, the result takes the sign of the dividend
+                    self.push(Value::number(a % b));
+                }
+                OpCode::OpPower => {
+                    if !self.peek(0).is_number() || !self.peek(1).is_number() {
+                        return Err(self.runtime_error("Operands must be numbers."));
+                    }
+                    let b = self.pop().as_number();
+                    let a = self.pop().as_number();
+                    self.push(Value::number(a.powf(b)));
+                }
+                OpCode::OpPrint => {
+                    if !self.is_enabled(Feature::Print) {
+                        return Err(self.runtime_error(&feature_disabled("print")));
+                    }
+                    let value = self.pop();
+                    if let Err(error) = writeln!(self.output, "{}", value) {
+                        return Err(self.runtime_error(&format!("Failed to print: {}.", error)));
+                    }
                 }
-                x if x == OpCode::OpDefineGlobal as u8 => {
-                    let constant = self.read_constant();
-                    let name = constant.as_string().to_string();
+                OpCode::OpDefineGlobal => {
+                    let slot = self.read_global();
                     let value = self.pop();
-                    self.globals.set(name, value);
+                    self.globals[slot] = Some(value);
                 }
-                x if x == OpCode::OpGetGlobal as u8 => {
-                    let constant = self.read_constant();
-                    let name = constant.as_string();
-                    match self.globals.get(name) {
+                OpCode::OpGetGlobal => {
+                    let slot = self.read_global();
+                    match &self.globals[slot] {
                         Some(value) => {
                             self.push(value.clone());
                         }
                         None => {
-                            self.runtime_error(&format!("Undefined variable '{}'.", name));
-                            return InterpretResult::RuntimeError;
+                            return Err(self.undefined_global());
                         }
                     }
                 }
-                x if x == OpCode::OpSetGlobal as u8 => {
-                    let constant = self.read_constant();
-                    let name = constant.as_string().to_string();
-                    if self.globals.set(name.clone(), self.peek(0).clone()) {
-                        self.globals.delete(&name);
-                        self.runtime_error(&format!("Undefined variable '{}'.", name));
-                        return InterpretResult::RuntimeError;
+                OpCode::OpSetGlobal => {
+                    let slot = self.read_global();
+                    if self.globals[slot].is_none() {
+                        return Err(self.undefined_global());
                     }
+                    self.globals[slot] = Some(self.peek(0).to_value());
+                }
+                OpCode::OpGetLocal => {
+                    let slot = self.frame().slots + self.read_byte() as usize;
+                    self.push_slot(self.stack[slot].clone());
+                }
+                OpCode::OpSetLocal => {
+                    let slot = self.frame().slots + self.read_byte() as usize;
+                    self.stack[slot] = self.peek(0).clone();
                 }
-                x if x == OpCode::OpGetLocal as u8 => {
-                    let slot = self.read_byte() as usize;
-                    self.push(self.stack[slot].clone());
+                OpCode::OpGetLocalLong => {
+                    let slot = self.frame().slots + self.read_short() as usize;
+                    self.push_slot(self.stack[slot].clone());
                 }
-                x if x == OpCode::OpSetLocal as u8 => {
-                    let slot = self.read_byte() as usize;
+                OpCode::OpSetLocalLong => {
+                    let slot = self.frame().slots + self.read_short() as usize;
                     self.stack[slot] = self.peek(0).clone();
                 }
-                x if x == OpCode::OpJumpIfFalse as u8 => {
-                    let offset = self.read_short();
+                OpCode::OpGetProperty => {
+                    let Some(instance) = self.peek(0).to_value().as_instance().cloned() else {
+                        return Err(self.runtime_error("Only instances have properties."));
+                    };
+                    let name = self.read_string();
+                    let field = instance.borrow().fields.get(&name).cloned();
+                    if let Some(value) = field {
+                        self.pop(); // Instance
+                        self.push(value);
+                    } else {
+                        let class = Rc::clone(&instance.borrow().class);
+                        self.bind_method(&class, &name)?;
+                    }
+                }
+                OpCode::OpSetProperty => {
+                    let Some(instance) = self.peek(1).to_value().as_instance().cloned() else {
+                        return Err(self.runtime_error("Only instances have fields."));
+                    };
+                    let name = self.read_string();
+                    instance
+                        .borrow_mut()
+                        .fields
+                        .set(name, self.peek(0).to_value());
+
+                    let value = self.pop();
+                    self.pop(); // Instance
+                    self.push(value);
+                }
+                OpCode::OpJumpIfFalse | OpCode::OpJumpIfFalseLong => {
+                    let offset = self.read_jump(instruction);
                     if self.peek(0).is_falsey() {
-                        self.ip += offset as usize;
+                        self.frame_mut().ip += offset;
+                    }
+                }
+                OpCode::OpJump | OpCode::OpJumpLong => {
+                    let offset = self.read_jump(instruction);
+                    self.frame_mut().ip += offset;
+                }
+                OpCode::OpTry | OpCode::OpTryLong => {
+                    let offset = self.read_jump(instruction);
+                    self.handlers.push(Handler {
+                        frame_count: self.frames.len(),
+                        stack_len: self.stack.len(),
+                        catch_ip: self.frame().ip + offset,
+                    });
+                }
+                OpCode::OpEndTry => {
+                    self.handlers.pop();
+                }
+                OpCode::OpThrow => {
+                    let exception = self.pop();
+                    if !self.catch(exception.clone()) {
+                        let message = format!("Uncaught exception: {}", exception);
+                        return Err(self.runtime_error(&message));
+                    }
+                }
+                OpCode::OpAssert => {
+                    let message = if self.read_byte() == 1 {
+                        Some(self.pop())
+                    } else {
+                        None
+                    };
+                    if self.pop().is_falsey() {
+                        let message = match message {
+                            Some(message) => {
+                                format!("Assertion failed: {}", message)
+                            }
+                            None => "Assertion failed.".to_string(),
+                        };
+                        return Err(self.runtime_error(&message));
+                    }
+                }
+                OpCode::OpImport => {
+                    if !self.is_enabled(Feature::Io) {
+                        return Err(self.runtime_error(&feature_disabled("import")));
+                    }
+                    let path = self.read_constant().as_string().to_string();
+                    self.import(&path)?;
+                }
+                OpCode::OpLoop | OpCode::OpLoopLong => {
+                    let offset = self.read_jump(instruction);
+                    self.frame_mut().ip -= offset;
+                }
+                OpCode::OpCall => {
+                    let arg_count = self.read_byte() as usize;
+                    self.call_value(self.peek(arg_count).to_value(), arg_count)?;
+                }
+                OpCode::OpInvoke => {
+                    let method = self.read_string();
+                    let arg_count = self.read_byte() as usize;
+                    self.invoke(&method, arg_count)?;
+                }
+                OpCode::OpReturn => {
+                    let result = self.pop();
+                    let frame = self.frames.pop().unwrap();
+                    self.stack.truncate(frame.slots);
+                    // Returning from inside a try block leaves it
+                    while self
+                        .handlers
+                        .last()
+                        .is_some_and(|handler| handler.frame_count > self.frames.len())
+                    {
+                        self.handlers.pop();
+                    }
+                    self.end_imports();
+
+                    if self.frames.is_empty() {
+                        return Ok(());
                     }
+                    self.push(result);
                 }
-                x if x == OpCode::OpJump as u8 => {
-                    let offset = self.read_short();
-                    self.ip += offset as usize;
+                OpCode::OpClass => {
+                    if !self.is_enabled(Feature::Classes) {
+                        return Err(self.runtime_error(&feature_disabled("class")));
+                    }
+                    let name = self.read_constant().as_string().to_string();
+                    self.push(Value::class(Rc::new(Class::new(name))));
+                }
+                OpCode::OpBuildList => {
+                    let count = self.read_byte() as usize;
+                    let elements = self.pop_values(count);
+                    let list = Value::list(elements);
+                    self.record_allocation(&list);
+                    self.push(list);
+                }
+                OpCode::OpBuildMap => {
+                    let count = self.read_byte() as usize;
+                    let entries = self.pop_values(count * 2);
+                    let mut map = Map::new();
+                    for pair in entries.chunks(2) {
+                        let key = self.map_key(&pair[0])?;
+                        map.set(key, pair[1].clone());
+                    }
+                    let map = Value::map(map);
+                    self.record_allocation(&map);
+                    self.push(map);
                 }
-                x if x == OpCode::OpLoop as u8 => {
-                    let offset = self.read_short();
-                    self.ip -= offset as usize;
+                OpCode::OpIndexGet => {
+                    let index = self.pop();
+                    let target = self.pop();
+                    let element = match &target {
+                        Value::List(list) => {
+                            let index = self.list_index(&list.borrow(), &index)?;
+                            list.borrow()[index].clone()
+                        }
+                        Value::Map(map) => {
+                            let key = self.map_key(&index)?;
+                            let value = map.borrow().get(&key).cloned();
+                            match value {
+                                Some(value) => value,
+                                None => {
+                                    return Err(
+                                        self.runtime_error(&format!("Undefined key '{}'.", key))
+                                    );
+                                }
+                            }
+                        }
+                        _ => {
+                            return Err(self.runtime_error("Only lists and maps can be indexed."));
+                        }
+                    };
+                    self.push(element);
                 }
-                x if x == OpCode::OpReturn as u8 => {
-                    return InterpretResult::Ok;
+                OpCode::OpIndexSet => {
+                    let value = self.pop();
+                    let index = self.pop();
+                    let target = self.pop();
+                    match &target {
+                        Value::List(list) => {
+                            let index = self.list_index(&list.borrow(), &index)?;
+                            list.borrow_mut()[index] = value.clone();
+                        }
+                        Value::Map(map) => {
+                            let key = self.map_key(&index)?;
+                            map.borrow_mut().set(key, value.clone());
+                        }
+                        _ => {
+                            return Err(self.runtime_error("Only lists and maps can be indexed."));
+                        }
+                    }
+                    self.push(value);
                 }
-                _ => {
-                    return InterpretResult::RuntimeError;
+                OpCode::OpMethod => {
+                    let name = self.read_string();
+                    let method = self.pop();
+                    if let Value::Class(class) = &self.peek(0).to_value() {
+                        class.methods.borrow_mut().set(name, method);
+                    }
                 }
             }
         }
     }
 
-    fn read_byte(&mut self) -> u8 {
-        let byte = self.chunk.as_ref().unwrap().code[self.ip];
-        self.ip += 1;
-        byte
-    }
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), RuntimeError> {
+        match &callee {
+            Value::Function(function) => self.call(Rc::clone(function), arg_count),
+            Value::Class(class) => {
+                // The instance replaces the class in the callee slot
+                let slot = self.stack.len() - arg_count - 1;
+                let instance = Value::instance(Instance::new(Rc::clone(class)));
+                self.record_allocation(&instance);
+                self.stack[slot] = instance.into_slot();
 
-    fn read_constant(&mut self) -> Value {
-        let index = self.read_byte() as usize;
-        self.chunk.as_ref().unwrap().get_constant(index)
+                let initializer = class.methods.borrow().get(&self.init_string).cloned();
+                match &initializer {
+                    Some(Value::Function(initializer)) => {
+                        self.call(Rc::clone(initializer), arg_count)
+                    }
+                    _ if arg_count != 0 => {
+                        Err(self
+                            .runtime_error(&format!("Expected 0 arguments but got {}.", arg_count)))
+                    }
+                    _ => Ok(()),
+                }
+            }
+            Value::BoundMethod(bound) => {
+                let slot = self.stack.len() - arg_count - 1;
+                self.stack[slot] = bound.receiver.clone().into_slot();
+                self.call(Rc::clone(&bound.method), arg_count)
+            }
+            Value::Native(native) => self.call_native(native, arg_count),
+            _ => Err(self.runtime_error("Can only call functions and classes.")),
+        }
     }
 
-    fn read_short(&mut self) -> u16 {
-        let high = self.read_byte() as u16;
-        let low = self.read_byte() as u16;
-        (high << 8) | low
+    /// Checks that `index` is a whole number within `list`'s bounds.
+    fn list_index(&mut self, list: &[Value], index: &Value) -> Result<usize, RuntimeError> {
+        let Value::Number(index) = *index else {
+            return Err(self.runtime_error("List index must be a number."));
+        };
+        if index.fract() != 0.0 || index < 0.0 || index >= list.len() as f64 {
+            return Err(self.runtime_error("List index out of range."));
+        }
+        Ok(index as usize)
     }
 
-    fn push(&mut self, value: Value) {
-        self.stack.push(value);
+    /// Starts running the module at `path` in a new frame, unless it was
+    /// already imported. Either way, leaves a value for OpImport to pop.
+    fn import(&mut self, path: &str) -> Result<(), RuntimeError> {
+        let dir = match self.imports.last() {
+            Some(import) => &import.dir,
+            None => &self.base_dir,
+        };
+        let resolved = dir.join(path);
+        let canonical = match fs::canonicalize(&resolved) {
+            Ok(canonical) => canonical,
+            Err(error) => {
+                let message = format!("Could not import '{}': {}", path, error);
+                return Err(self.runtime_error(&message));
+            }
+        };
+
+        if self.script_path.as_ref() == Some(&canonical)
+            || self.imports.iter().any(|import| import.path == canonical)
+        {
+            return Err(self.runtime_error(&format!("Import cycle detected at '{}'.", path)));
+        }
+        if self.modules.contains(&canonical) {
+            self.push(Value::nil());
+            return Ok(());
+        }
+
+        let name = resolved.to_string_lossy().into_owned();
+        let source = match fs::read_to_string(&canonical) {
+            Ok(source) => source,
+            Err(error) => {
+                let message = format!("Could not import '{}': {}", path, error);
+                return Err(self.runtime_error(&message));
+            }
+        };
+        let chunk = match crate::compiler::compile_module(&source, self, &name) {
+            Ok(chunk) => chunk,
+            Err(errors) => {
+                let message = format!(
+                    "Could not compile '{}':\n{}",
+                    name,
+                    RloxError::Compile(errors)
+                );
+                return Err(self.runtime_error(&message));
+            }
+        };
+
+        let mut module = Function::new(None);
+        module.chunk = chunk;
+        module.module = Some(name.into());
+        let module = Rc::new(module);
+
+        self.modules.insert(canonical.clone());
+        self.imports.push(Import {
+            path: canonical,
+            dir: resolved.parent().map(Path::to_path_buf).unwrap_or_default(),
+            frame_count: self.frames.len() + 1,
+        });
+        self.push(Value::function(Rc::clone(&module)));
+        self.call(module, 0)
     }
 
-    fn pop(&mut self) -> Value {
-        self.stack.pop().expect("Stack underflow")
+    /// Forgets the imports whose top-level code is no longer running.
+    fn end_imports(&mut self) {
+        while self
+            .imports
+            .last()
+            .is_some_and(|import| import.frame_count > self.frames.len())
+        {
+            self.imports.pop();
+        }
     }
 
-    fn peek(&self, distance: usize) -> &Value {
-        &self.stack[self.stack.len() - 1 - distance]
+    fn map_key(&mut self, key: &Value) -> Result<MapKey, RuntimeError> {
+        MapKey::from_value(key)
+            .ok_or_else(|| self.runtime_error("Map keys must be strings or numbers."))
     }
 
-    fn runtime_error(&self, message: &str) {
-        eprintln!("{}", message);
-        let line = self.chunk.as_ref().unwrap().lines[self.ip - 1];
-        eprintln!("[line {}] in script", line);
+    fn call_native(&mut self, native: &Native, arg_count: usize) -> Result<(), RuntimeError> {
+        if let Some(arity) = native.arity
+            && arg_count != arity
+        {
+            return Err(self.runtime_error(&format!(
+                "Expected {} arguments but got {}.",
+                arity, arg_count
+            )));
+        }
+
+        let args = self.pop_values(arg_count);
+        match (native.function)(self, &args) {
+            Ok(result) => {
+                // Natives like `split` create lists
+                self.heap.track(&result);
+                self.pop(); // Callee
+                self.push(result);
+                Ok(())
+            }
+            Err(message) => Err(self.runtime_error(&message)),
+        }
     }
 
-    pub fn intern_string(&mut self, string: String) -> String {
-        let hash = crate::table::hash_string(&string);
+    fn invoke(&mut self, name: &Rc<LoxString>, arg_count: usize) -> Result<(), RuntimeError> {
+        let Some(instance) = self.peek(arg_count).to_value().as_instance().cloned() else {
+            return Err(self.runtime_error("Only instances have methods."));
+        };
 
-        if let Some(interned) = self.strings.find_string(&string, hash) {
-            return interned.to_string();
+        // A field holding a callable shadows a method of the same name
+        let field = instance.borrow().fields.get(name).cloned();
+        if let Some(value) = field {
+            let slot = self.stack.len() - arg_count - 1;
+            self.stack[slot] = value.clone().into_slot();
+            return self.call_value(value, arg_count);
         }
 
-        self.strings.set(string.clone(), Value::nil());
-        string
+        let class = Rc::clone(&instance.borrow().class);
+        self.invoke_from_class(&class, name, arg_count)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    fn invoke_from_class(
+        &mut self,
+        class: &Class,
+        name: &Rc<LoxString>,
+        arg_count: usize,
+    ) -> Result<(), RuntimeError> {
+        let method = class.methods.borrow().get(name).cloned();
+        match &method {
+            Some(Value::Function(method)) => self.call(Rc::clone(method), arg_count),
+            _ => Err(self.runtime_error(&format!("Undefined property '{}'.", name))),
+        }
+    }
+
+    fn bind_method(&mut self, class: &Class, name: &Rc<LoxString>) -> Result<(), RuntimeError> {
+        let method = match class.methods.borrow().get(name) {
+            Some(Value::Function(method)) => Rc::clone(method),
+            _ => return Err(self.runtime_error(&format!("Undefined property '{}'.", name))),
+        };
+
+        let receiver = self.pop();
+        let bound = Value::bound_method(receiver, method);
+        self.record_allocation(&bound);
+        self.push(bound);
+        Ok(())
+    }
+
+    fn call(&mut self, function: Rc<Function>, arg_count: usize) -> Result<(), RuntimeError> {
+        if arg_count != function.arity {
+            return Err(self.runtime_error(&format!(
+                "Expected {} arguments but got {}.",
+                function.arity, arg_count
+            )));
+        }
+
+        if self.frames.len() == self.options.max_call_depth {
+            return Err(self.limit_error(Limit::CallDepth, "Stack overflow."));
+        }
+
+        self.frames.push(CallFrame {
+            function,
+            ip: 0,
+            slots: self.stack.len() - arg_count - 1,
+        });
+        Ok(())
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().unwrap()
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let [byte] = self.read_bytes();
+        byte
+    }
+
+    /// Reads an instruction's next `N` bytes, looking up the frame and
+    /// checking the bounds of its code once rather than for every byte.
+    fn read_bytes<const N: usize>(&mut self) -> [u8; N] {
+        let frame = self.frame_mut();
+        let bytes = frame.function.chunk.code[frame.ip..frame.ip + N]
+            .try_into()
+            .unwrap();
+        frame.ip += N;
+        bytes
+    }
+
+    fn read_constant(&mut self) -> Value {
+        Value::from_slot(self.read_constant_slot())
+    }
+
+    fn read_constant_slot(&mut self) -> Slot {
+        let frame = self.frame_mut();
+        let index = frame.function.chunk.code[frame.ip] as usize;
+        frame.ip += 1;
+        frame.function.chunk.get_constant(index).clone()
+    }
+
+    /// Reads a constant that verification guarantees is a name.
+    fn read_string(&mut self) -> Rc<LoxString> {
+        match &self.read_constant() {
+            Value::String(string) => Rc::clone(string),
+            _ => unreachable!("Name constants are strings"),
+        }
+    }
+
+    /// Reads the operand of a global instruction and returns the global's
+    /// slot.
+    fn read_global(&mut self) -> usize {
+        let index = self.read_short() as usize;
+        self.frame().function.chunk.get_global(index).slot
+    }
+
+    /// Reports that the global the current instruction uses isn't defined,
+    /// or that it's an I/O native that was taken away.
+    fn undefined_global(&mut self) -> RuntimeError {
+        let frame = self.frame();
+        let chunk = &frame.function.chunk;
+        let index = u16::from_be_bytes([chunk.code[frame.ip - 2], chunk.code[frame.ip - 1]]);
+        let name = chunk.get_global(index as usize).name.as_str();
+        let message = if !self.io_natives_enabled() && crate::natives::IO_NATIVES.contains(&name) {
+            feature_disabled(name)
+        } else {
+            format!("Undefined variable '{}'.", name)
+        };
+        self.runtime_error(&message)
+    }
+
+    fn read_short(&mut self) -> u16 {
+        u16::from_be_bytes(self.read_bytes())
+    }
+
+    /// Reads a jump's offset, which long jumps have four bytes for.
+    fn read_jump(&mut self, instruction: OpCode) -> usize {
+        if instruction.is_long_jump() {
+            u32::from_be_bytes(self.read_bytes()) as usize
+        } else {
+            self.read_short() as usize
+        }
+    }
+
+    fn push(&mut self, value: Value) {
+        self.push_slot(value.into_slot());
+    }
+
+    fn push_slot(&mut self, slot: Slot) {
+        self.stack.push(slot);
+        if let Some(stats) = self.stats.as_mut() {
+            stats.peak_stack_depth = stats.peak_stack_depth.max(self.stack.len());
+        }
+    }
+
+    fn record_allocation(&mut self, value: &Value) {
+        if let Some(stats) = self.stats.as_mut() {
+            stats.allocations += 1;
+        }
+        if self.heap.track(value) {
+            self.collect_garbage();
+        }
+    }
+
+    fn pop(&mut self) -> Value {
+        let slot = self
+            .stack
+            .pop()
+            .expect("verified code doesn't underflow the stack");
+        Value::from_slot(slot)
+    }
+
+    /// Pops the top `count` values, in the order they were pushed.
+    fn pop_values(&mut self, count: usize) -> Vec<Value> {
+        let slots = self.stack.split_off(self.stack.len() - count);
+        slots.into_iter().map(Value::from_slot).collect()
+    }
+
+    fn peek(&self, distance: usize) -> &Slot {
+        &self.stack[self.stack.len() - 1 - distance]
+    }
+
+    /// Builds the error for the current instruction and unwinds the VM so
+    /// it can run another script.
+    fn runtime_error(&mut self, message: &str) -> RuntimeError {
+        let trace = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let span = frame.function.chunk.spans[frame.ip - 1];
+                TraceFrame {
+                    line: span.line,
+                    column: span.column,
+                    function: frame.function.name.clone(),
+                    module: frame.function.module.as_deref().map(str::to_string),
+                }
+            })
+            .collect();
+
+        RuntimeError {
+            message: message.to_string(),
+            trace,
+            limit: None,
+        }
+    }
+
+    fn check_limits(&mut self) -> Result<(), RuntimeError> {
+        if self.stack.len() > self.options.max_stack_depth {
+            return Err(self.limit_error(Limit::StackDepth, "Stack overflow."));
+        }
+
+        if self.instructions_left == 0 {
+            return Err(self.limit_error(Limit::Instructions, "Instruction budget exhausted."));
+        }
+        self.instructions_left -= 1;
+
+        if self.instructions_left.is_multiple_of(TIMEOUT_INTERVAL)
+            && self
+                .deadline
+                .is_some_and(|deadline| self.clock.now() >= deadline)
+        {
+            return Err(self.limit_error(Limit::Time, "Timed out."));
+        }
+        Ok(())
+    }
+
+    fn limit_error(&mut self, limit: Limit, message: &str) -> RuntimeError {
+        let mut error = self.runtime_error(message);
+        error.limit = Some(limit);
+        error
+    }
+
+    /// Loads bytecode produced by [`Chunk::serialize`], interning its
+    /// strings and resolving its globals in this VM so it can be passed to
+    /// [`VM::interpret`].
+    pub fn load_chunk(&mut self, bytes: &[u8]) -> Result<Chunk, LoadError> {
+        Chunk::deserialize(bytes, self)
+    }
+
+    /// Returns the VM's shared copy of `string`, so that equal interned
+    /// strings are the same object.
+    pub fn intern_string(&mut self, string: String) -> Rc<LoxString> {
+        let hash = crate::table::hash_string(&string);
+
+        if let Some(interned) = self.strings.find_string(&string, hash) {
+            return Rc::clone(interned);
+        }
+
+        let interned = Rc::new(LoxString::new(string));
+        self.strings.set(Rc::clone(&interned), Value::nil());
+        interned
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::CompileOptions;
+    use crate::error::CompileError;
+    use crate::program_gen;
+    use proptest::prelude::*;
+
+    fn run(source: &str) -> (VM, Result<(), RuntimeError>) {
+        let mut vm = VM::with_output(io::sink());
+        let chunk = crate::compiler::compile(source, &mut vm).expect("Failed to compile");
+        let result = vm.interpret(chunk);
+        (vm, result)
+    }
+
+    /// Collects everything written to it, while letting the test read it.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn run_printing(source: &str) -> String {
+        let buffer = SharedBuffer::default();
+        let mut vm = VM::with_output(buffer.clone());
+        let chunk = crate::compiler::compile(source, &mut vm).expect("Failed to compile");
+        vm.interpret(chunk).expect("Failed to run");
+        String::from_utf8(buffer.0.take()).unwrap()
+    }
+
+    fn compile_repl(source: &str, vm: &mut VM) -> Result<Chunk, Vec<CompileError>> {
+        let options = CompileOptions {
+            repl: true,
+            ..Default::default()
+        };
+        crate::compiler::compile_with(source, vm, options)
+    }
+
+    #[test]
+    fn test_repl_prints_expressions() {
+        let buffer = SharedBuffer::default();
+        let mut vm = VM::with_output(buffer.clone());
+        for line in [
+            "var a = 1;",
+            "a + 2",
+            "a = 5; a;",
+            "{ a; }",
+            "fun f() { a; } f();",
+        ] {
+            let chunk = compile_repl(line, &mut vm).expect("Failed to compile");
+            vm.interpret(chunk).expect("Failed to run");
+        }
+
+        assert_eq!(
+            String::from_utf8(buffer.0.take()).unwrap(),
+            "3\n5\n5\nnil\n"
+        );
+        assert!(compile_repl("a + 2 a", &mut vm).is_err());
+    }
+
+    fn global(vm: &VM, name: &str) -> Value {
+        vm.get_global(name).expect("Undefined global")
+    }
 
     #[test]
     fn test_string_interning() {
@@ -256,10 +1508,55 @@ mod tests {
         let str2 = vm.intern_string("hello".to_string());
         let str3 = vm.intern_string("world".to_string());
 
-        assert_eq!(str1, str2);
-        assert_eq!(str1, "hello");
+        assert!(Rc::ptr_eq(&str1, &str2));
+        assert_eq!(str1.as_str(), "hello");
         assert_ne!(str1, str3);
-        assert_eq!(str3, "world");
+        assert_eq!(str3.as_str(), "world");
+    }
+
+    #[test]
+    fn test_global_slots() {
+        let output = run_printing(
+            "fun f() { return later; }
+             var later = \"bound late\";
+             print f();
+             later = 2; print f();
+             var later = 3; print later;",
+        );
+        assert_eq!(output, "bound late\n2\n3\n");
+
+        let (_, result) = run("unset = 1;");
+        assert_eq!(result.unwrap_err().message, "Undefined variable 'unset'.");
+
+        // Host globals share the slots compiled code resolves to
+        let mut vm = VM::with_output(io::sink());
+        let chunk = crate::compiler::compile("var y = x + 1;", &mut vm).unwrap();
+        vm.set_global("x", Value::number(1.0));
+        vm.interpret(chunk).unwrap();
+        assert_eq!(vm.get_global("y"), Some(Value::number(2.0)));
+        assert_eq!(vm.get_global("z"), None);
+        let mut names: Vec<_> = vm.globals().map(|(name, _)| name).collect();
+        names.retain(|name| ["x", "y"].contains(name));
+        names.sort();
+        assert_eq!(names, ["x", "y"]);
+    }
+
+    #[test]
+    fn test_constants_are_shared() {
+        let (vm, result) = run("var a = \"abc\"; var b = a; var c = \"abc\";
+             var d; for (var i = 0; i < 3; i = i + 1) d = \"abc\";");
+        assert!(result.is_ok());
+
+        // Every read of the constant shares the chunk's copy of the string
+        let Value::String(a) = &global(&vm, "a") else {
+            panic!("Expected a string");
+        };
+        for name in ["b", "c", "d"] {
+            let Value::String(string) = &global(&vm, name) else {
+                panic!("Expected a string");
+            };
+            assert!(Rc::ptr_eq(a, string));
+        }
     }
 
     #[test]
@@ -271,6 +1568,1191 @@ mod tests {
         }
 
         let result = vm.intern_string("test".to_string());
-        assert_eq!(result, "test");
+        assert_eq!(result.as_str(), "test");
+        assert_eq!(
+            vm.strings
+                .iter()
+                .filter(|(s, _)| s.as_str() == "test")
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_execution_stats() {
+        let mut vm = VM::new();
+        let chunk = crate::compiler::compile("var a = \"x\" + \"y\"; a = 1 + 2;", &mut vm).unwrap();
+
+        let (result, stats) = vm.interpret_with_stats(chunk);
+
+        assert!(result.is_ok());
+        assert_eq!(stats.opcode_counts[OpCode::OpConstant as usize], 4);
+        assert_eq!(stats.opcode_counts[OpCode::OpAdd as usize], 2);
+        assert_eq!(stats.instructions_executed(), 11);
+        assert_eq!(stats.peak_stack_depth, 3);
+        assert_eq!(stats.allocations, 1);
+        assert_eq!(stats.line_counts.values().sum::<u64>(), 11);
+    }
+
+    #[test]
+    fn test_hottest_lines() {
+        let mut vm = VM::with_output(io::sink());
+        let chunk = crate::compiler::compile(
+            "var i = 0;\nwhile (i < 10)\n  i = i + 1;\nprint i;",
+            &mut vm,
+        )
+        .unwrap();
+
+        let (_, stats) = vm.interpret_with_stats(chunk);
+        let lines = stats.hottest_lines();
+        // Each increment is 5 instructions plus the jump back, and the
+        // condition is popped on the body's line when the loop exits
+        assert_eq!(lines[0], (None, 3, 61));
+        assert_eq!(lines[1].1, 2);
+        assert_eq!(
+            lines.iter().map(|(_, _, count)| count).sum::<u64>(),
+            stats.instructions_executed()
+        );
+    }
+
+    #[test]
+    fn test_deterministic_clock() {
+        let mut vm = VM::new();
+        vm.set_deterministic(Some(0));
+        let chunk = crate::compiler::compile("var a = 1; a = a + 2;", &mut vm).unwrap();
+
+        let (_, stats) = vm.interpret_with_stats(chunk);
+
+        assert_eq!(
+            stats.elapsed,
+            Duration::from_micros(stats.instructions_executed())
+        );
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let mut vm = VM::new();
+        let chunk =
+            crate::compiler::compile("var a = 1; while (true) a = a + 1;", &mut vm).unwrap();
+        let interrupt = vm.interrupt_handle();
+        let interrupter = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            interrupt.store(true, Ordering::Relaxed);
+        });
+
+        assert!(vm.interpret(chunk).is_err());
+        interrupter.join().unwrap();
+        assert!(vm.stack.is_empty());
+        assert!(vm.frames.is_empty());
+    }
+
+    fn run_limited(options: VmOptions, source: &str) -> RuntimeError {
+        let mut vm = options.build();
+        vm.set_output(io::sink());
+        let chunk = crate::compiler::compile(source, &mut vm).unwrap();
+        let error = vm.interpret(chunk).unwrap_err();
+        assert!(vm.stack.is_empty());
+        assert!(vm.frames.is_empty());
+        error
+    }
+
+    #[test]
+    fn test_instruction_budget() {
+        let error = run_limited(
+            VmOptions::new().instruction_budget(1000),
+            "var a = 0; while (true) a = a + 1;",
+        );
+        assert_eq!(error.limit, Some(Limit::Instructions));
+        assert_eq!(error.message, "Instruction budget exhausted.");
+
+        // The budget is per script, not per VM
+        let mut vm = VmOptions::new().instruction_budget(10).build();
+        for _ in 0..3 {
+            let chunk = crate::compiler::compile("var a = 1;", &mut vm).unwrap();
+            assert!(vm.interpret(chunk).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_timeout() {
+        let error = run_limited(
+            VmOptions::new().timeout(Duration::from_millis(20)),
+            "while (true) {}",
+        );
+        assert_eq!(error.limit, Some(Limit::Time));
+    }
+
+    #[test]
+    fn test_call_depth() {
+        let error = run_limited(
+            VmOptions::new().max_call_depth(10),
+            "fun f(n) { return f(n + 1); } f(0);",
+        );
+        assert_eq!(error.limit, Some(Limit::CallDepth));
+        assert_eq!(error.message, "Stack overflow.");
+        assert_eq!(error.trace.len(), 10);
+    }
+
+    #[test]
+    fn test_stack_depth() {
+        let error = run_limited(
+            VmOptions::new().max_stack_depth(8),
+            "print [1, 2, 3, 4, 5, 6, 7, 8, 9, 10];",
+        );
+        assert_eq!(error.limit, Some(Limit::StackDepth));
+
+        let (_, result) = run("print [1, 2, 3, 4, 5, 6, 7, 8, 9, 10];");
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_limits_cant_be_caught() {
+        let error = run_limited(
+            VmOptions::new().instruction_budget(1000),
+            "while (true) { try { while (true) {} } catch (e) {} }",
+        );
+        assert_eq!(error.limit, Some(Limit::Instructions));
+
+        let error = run_limited(
+            VmOptions::new().max_call_depth(10),
+            "fun f() { try { f(); } catch (e) { print e; } } f();",
+        );
+        assert_eq!(error.limit, Some(Limit::CallDepth));
+    }
+
+    #[test]
+    fn test_disabled_features() {
+        let messages = |options: VmOptions, source: &str| {
+            let mut vm = options.build();
+            match crate::compiler::compile(source, &mut vm) {
+                Ok(_) => Vec::new(),
+                Err(errors) => errors.into_iter().map(|error| error.message).collect(),
+            }
+        };
+
+        let options = VmOptions::new()
+            .disable(Feature::Print)
+            .disable(Feature::Io)
+            .disable(Feature::Classes);
+        assert!(!options.is_enabled(Feature::Print));
+        assert_eq!(
+            messages(options, "print 1;\nclass A {}\nimport \"a.lox\";"),
+            vec![
+                "Feature disabled: 'print'.",
+                "Feature disabled: 'class'.",
+                "Feature disabled: 'import'.",
+            ]
+        );
+        assert!(messages(VmOptions::new(), "print 1; class A {}").is_empty());
+
+        // Without the I/O natives, scripts are free to define their names
+        let mut vm = VmOptions::new().disable(Feature::Io).build();
+        let chunk = crate::compiler::compile(
+            "fun readFile(path) { return path; } var s = readFile(\"x\");
+             var readLine = 1; var n = readLine;",
+            &mut vm,
+        )
+        .unwrap();
+        vm.interpret(chunk).unwrap();
+        assert_eq!(vm.get_global("s"), Some(Value::string("x".to_string())));
+        assert_eq!(vm.get_global("n"), Some(Value::number(1.0)));
+        let chunk = crate::compiler::compile("writeFile(\"x\", \"y\");", &mut vm).unwrap();
+        let error = vm.interpret(chunk).unwrap_err();
+        assert_eq!(error.message, "Feature disabled: 'writeFile'.");
+
+        // The natives are gone too, and bytecode compiled elsewhere still
+        // can't print
+        let mut vm = options.build();
+        assert_eq!(vm.get_global("readFile"), None);
+        assert!(vm.get_global("len").is_some());
+        let chunk = crate::compiler::compile("print 1;", &mut VM::new()).unwrap();
+        let chunk = vm.load_chunk(&chunk.serialize()).unwrap();
+        let error = vm.interpret(chunk).unwrap_err();
+        assert_eq!(error.message, "Feature disabled: 'print'.");
+    }
+
+    #[test]
+    fn test_if_else() {
+        let (vm, result) = run("var a; var b; var c;
+             if (1 < 2) a = \"then\";
+             if (nil) b = \"then\"; else b = \"else\";
+             if (false) c = 1; else if (true) c = 2; else c = 3;");
+
+        assert!(result.is_ok());
+        assert_eq!(global(&vm, "a"), Value::string("then".to_string()));
+        assert_eq!(global(&vm, "b"), Value::string("else".to_string()));
+        assert_eq!(global(&vm, "c"), Value::number(2.0));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn test_if_without_else_skips_body() {
+        let (vm, result) = run("var a = 1; if (a > 1) { var b = 2; a = b; }");
+
+        assert!(result.is_ok());
+        assert_eq!(global(&vm, "a"), Value::number(1.0));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn test_for_loop() {
+        let (vm, result) = run("var sum = 0;
+             for (var i = 0; i < 5; i = i + 1) sum = sum + i;
+             var count = 0;
+             for (; count < 3;) count = count + 1;");
+
+        assert!(result.is_ok());
+        assert_eq!(global(&vm, "sum"), Value::number(10.0));
+        assert_eq!(global(&vm, "count"), Value::number(3.0));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn test_for_loop_variable_is_scoped() {
+        let (vm, result) = run("var i = \"outer\"; for (var i = 0; i < 2; i = i + 1) {}");
+
+        assert!(result.is_ok());
+        assert_eq!(global(&vm, "i"), Value::string("outer".to_string()));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn test_while_loop() {
+        let (vm, result) = run("var n = 1; while (n < 100) n = n * 2;");
+
+        assert!(result.is_ok());
+        assert_eq!(global(&vm, "n"), Value::number(128.0));
+    }
+
+    #[test]
+    fn test_modulo_and_power() {
+        let output = run_printing(
+            "print 7 % 3; print -7 % 3; print 7.5 % 2; print 1 + 5 % 3 * 2;
+             print 2 ** 10; print 2 ** 3 ** 2; print -2 ** 2; print 2 ** -1; print 2 * 3 ** 2;",
+        );
+        assert_eq!(output, "1\n-1\n1.5\n5\n1024\n512\n-4\n0.5\n18\n");
+
+        let (_, result) = run("print 1 % \"a\";");
+        assert_eq!(result.unwrap_err().message, "Operands must be numbers.");
+        let (_, result) = run("print nil ** 2;");
+        assert_eq!(result.unwrap_err().message, "Operands must be numbers.");
+    }
+
+    #[test]
+    fn test_lists() {
+        let output = run_printing(
+            "var a = [1, \"two\", [3]];
+             print a; print a[1]; print a[2][0];
+             a[0] = a[0] + 10; print a[0];
+             push(a, nil); print len(a); print pop(a); print len(a);
+             var b = a; b[1] = 2; print a[1];
+             print [] == []; print a == b;
+             push(a, a); print a;",
+        );
+        assert_eq!(
+            output,
+            "[1, two, [3]]\ntwo\n3\n11\n4\nnil\n3\n2\nfalse\ntrue\n[11, 2, [3], [...]]\n"
+        );
+    }
+
+    #[test]
+    fn test_list_errors() {
+        let errors = [
+            ("var a = 1; a[0];", "Only lists and maps can be indexed."),
+            ("var a = [1]; a[\"x\"];", "List index must be a number."),
+            ("var a = [1]; a[1];", "List index out of range."),
+            ("var a = [1]; a[-1] = 2;", "List index out of range."),
+            ("var a = [1]; a[0.5];", "List index out of range."),
+            ("pop([]);", "Can't pop from an empty list."),
+            (
+                "len(1);",
+                "Argument to 'len' must be a list, map or string.",
+            ),
+        ];
+        for (source, message) in errors {
+            let (vm, result) = run(source);
+            assert_eq!(result.unwrap_err().message, message, "{}", source);
+            assert!(vm.stack.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_maps() {
+        let output = run_printing(
+            "var m = {\"a\": 1, 2: \"two\"};
+             print m[\"a\"]; print m[2]; print len(m);
+             m[\"a\"] = m[\"a\"] + 10; m[3] = nil; print m[\"a\"]; print len(m);
+             print has(m, 3); print remove(m, 3); print has(m, 3); print remove(m, 3);
+             print len(keys(m)); print {} == {}; print m == m;
+             var z = {-0: \"zero\"}; print z[0]; print z;
+             z[\"z\"] = z; print z[\"z\"];",
+        );
+        assert_eq!(
+            output,
+            "1\ntwo\n2\n11\n3\ntrue\nnil\nfalse\nnil\n2\nfalse\ntrue\nzero\n{0: zero}\n{0: zero, z: {...}}\n"
+        );
+    }
+
+    #[test]
+    fn test_map_errors() {
+        let errors = [
+            ("var m = {\"a\": 1}; m[\"b\"];", "Undefined key 'b'."),
+            (
+                "var m = {}; m[nil] = 1;",
+                "Map keys must be strings or numbers.",
+            ),
+            ("var m = {[]: 1};", "Map keys must be strings or numbers."),
+            ("has([], 1);", "First argument to 'has' must be a map."),
+            ("keys(1);", "Argument to 'keys' must be a map."),
+        ];
+        for (source, message) in errors {
+            let (vm, result) = run(source);
+            assert_eq!(result.unwrap_err().message, message, "{}", source);
+            assert!(vm.stack.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_string_natives() {
+        let output = run_printing(
+            "var s = \"Hello, world\";
+             print len(s); print len(\"\");
+             print substr(s, 7, 5); print substr(s, 0, 0); print substr(s, 12, 0);
+             print indexOf(s, \"wo\"); print indexOf(s, \"rld\"); print indexOf(s, \"x\");
+             print split(\"a,b,,c\", \",\"); print split(\"abc\", \"-\");
+             print upper(s); print lower(\"ABC\");",
+        );
+        assert_eq!(
+            output,
+            "12\n0\nworld\n\n\n7\n9\n-1\n[a, b, , c]\n[abc]\nHELLO, WORLD\nabc\n"
+        );
+    }
+
+    #[test]
+    fn test_unicode_source() {
+        let output = run_printing(
+            "var café = \"naïve → ok\";
+             var π = 3;
+             print café; print π; print len(café); print upper(café);
+             print café + \" 日本\";",
+        );
+        assert_eq!(output, "naïve → ok\n3\n10\nNAÏVE → OK\nnaïve → ok 日本\n");
+    }
+
+    #[test]
+    fn test_string_native_errors() {
+        let errors = [
+            ("substr(\"abc\", 2, 2);", "Substring out of range."),
+            (
+                "substr(\"abc\", -1, 1);",
+                "Second argument to 'substr' must be a non-negative integer.",
+            ),
+            (
+                "substr(1, 0, 0);",
+                "First argument to 'substr' must be a string.",
+            ),
+            (
+                "indexOf(\"abc\", nil);",
+                "Second argument to 'indexOf' must be a string.",
+            ),
+            (
+                "split(\"abc\", \"\");",
+                "Separator passed to 'split' can't be empty.",
+            ),
+            ("upper(1);", "Argument to 'upper' must be a string."),
+        ];
+        for (source, message) in errors {
+            let (vm, result) = run(source);
+            assert_eq!(result.unwrap_err().message, message, "{}", source);
+            assert!(vm.stack.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_read_line() {
+        let buffer = SharedBuffer::default();
+        let mut vm = VM::with_output(buffer.clone());
+        vm.set_input(io::Cursor::new("first\r\nsecond\n\nlast"));
+        let chunk = crate::compiler::compile(
+            "var line = readLine();
+             while (line != nil) { print \"<\" + line + \">\"; line = readLine(); }",
+            &mut vm,
+        )
+        .unwrap();
+        vm.interpret(chunk).unwrap();
+        assert_eq!(
+            String::from_utf8(buffer.0.take()).unwrap(),
+            "<first>\n<second>\n<>\n<last>\n"
+        );
+    }
+
+    #[test]
+    fn test_files() {
+        let path = std::env::temp_dir().join(format!("rlox-test-files-{}", std::process::id()));
+        let path = path.to_str().unwrap().replace('\\', "/");
+        let source = format!(
+            "writeFile(\"{path}\", \"one\ntwo\"); print split(readFile(\"{path}\"), \"\n\");"
+        );
+
+        assert_eq!(run_printing(&source), "[one, two]\n");
+        std::fs::remove_file(&path).unwrap();
+
+        let (_, result) = run(&format!("readFile(\"{path}\");"));
+        let message = result.unwrap_err().message;
+        assert!(message.starts_with("Could not read file"), "{}", message);
+
+        let mut vm = VM::with_output(io::sink());
+        let read_file = vm.get_global("readFile").unwrap();
+        vm.set_sandboxed(true);
+        let chunk = crate::compiler::compile(&source, &mut vm).unwrap();
+        assert_eq!(
+            vm.interpret(chunk).unwrap_err().message,
+            "Feature disabled: 'writeFile'."
+        );
+        assert!(!std::path::Path::new(&path).exists());
+
+        // A native the script got hold of before still refuses to run
+        vm.set_global("f", read_file);
+        let chunk = crate::compiler::compile("f(\"x\");", &mut vm).unwrap();
+        assert_eq!(
+            vm.interpret(chunk).unwrap_err().message,
+            "Feature disabled: 'readFile'."
+        );
+        vm.set_sandboxed(false);
+        assert!(vm.get_global("readLine").is_some());
+
+        // Leaving the sandbox doesn't overwrite globals that took the
+        // natives' names
+        let mut vm = VM::with_output(io::sink());
+        vm.set_global("readFile", Value::nil());
+        vm.set_sandboxed(true);
+        let chunk = crate::compiler::compile("fun readLine() { return 1; }", &mut vm).unwrap();
+        vm.interpret(chunk).unwrap();
+        vm.set_sandboxed(false);
+        assert_eq!(vm.get_global("readFile"), Some(Value::nil()));
+        assert!(matches!(
+            vm.get_global("readLine"),
+            Some(Value::Function(_))
+        ));
+        assert!(matches!(vm.get_global("writeFile"), Some(Value::Native(_))));
+    }
+
+    #[test]
+    fn test_conversion_natives() {
+        let output = run_printing(
+            "class C { m() {} }
+             fun f() {}
+             var values = [nil, true, 1, \"s\", f, clock, C().m, C, C(), [], {}];
+             for (var i = 0; i < len(values); i = i + 1) print type(values[i]);
+             print num(\" 3.14 \") + 1; print num(\"-2e3\"); print num(7);
+             print num(\"abc\"); print num(\"\"); print num(\"inf\"); print num(\"1.2.3\");
+             print str(1.5) + str(nil) + str([1, \"a\"]) + str(C);
+             print type(str(1)) == \"string\";",
+        );
+        assert_eq!(
+            output,
+            "nil\nbool\nnumber\nstring\nfunction\nfunction\nfunction\nclass\ninstance\nlist\nmap\n\
+             4.14\n-2000\n7\nnil\nnil\nnil\nnil\n1.5nil[1, a]C\ntrue\n"
+        );
+
+        assert_eq!(run_printing("print str(1000000 * 1000000);"), "1e+12\n");
+
+        let (_, result) = run("num(nil);");
+        assert_eq!(
+            result.unwrap_err().message,
+            "Argument to 'num' must be a string or number."
+        );
+    }
+
+    #[test]
+    fn test_exceptions() {
+        let output = run_printing(
+            "try { print 1; throw \"boom\"; print 2; } catch (e) { print \"caught \" + e; }
+             fun f(n) { if (n == 0) throw {\"code\": 42}; return f(n - 1); }
+             try { var local = 1; f(5); } catch (e) { print e[\"code\"]; }
+             try { nil(); } catch (e) { print e; }
+             for (var i = 0; i < 4; i = i + 1) {
+               try { if (i == 1) continue; if (i == 3) break; print i; } catch (e) {}
+             }
+             try { throw \"after loop\"; } catch (e) { print e; }
+             fun g() { try { return \"returned\"; } catch (e) {} }
+             print g();
+             try { throw \"after return\"; } catch (e) { print e; }
+             try { try { throw 1; } catch (e) { throw e + 1; } } catch (e) { print e; }
+             var after = \"done\"; print after;",
+        );
+        assert_eq!(
+            output,
+            "1\ncaught boom\n42\nCan only call functions and classes.\n0\n2\nafter loop\n\
+             returned\nafter return\n2\ndone\n"
+        );
+    }
+
+    #[test]
+    fn test_uncaught_exception() {
+        let (vm, result) = run("fun f() { throw [1]; }\ntry {} catch (e) {}\nf();");
+        let error = result.unwrap_err();
+        assert_eq!(error.message, "Uncaught exception: [1]");
+        assert_eq!(error.line(), Some(1));
+        assert_eq!(error.trace.len(), 2);
+        assert!(vm.stack.is_empty() && vm.frames.is_empty() && vm.handlers.is_empty());
+    }
+
+    #[test]
+    fn test_assert() {
+        assert_eq!(
+            run_printing("assert true; assert 1 < 2, \"math\"; print \"ok\";"),
+            "ok\n"
+        );
+
+        let (vm, result) = run("var x = 1;\nassert x == 1;\nassert x > 1;");
+        let error = result.unwrap_err();
+        assert_eq!(error.message, "Assertion failed.");
+        assert_eq!(error.line(), Some(3));
+        assert!(vm.stack.is_empty());
+
+        let (_, result) = run("assert nil, \"expected \" + str(1);");
+        assert_eq!(result.unwrap_err().message, "Assertion failed: expected 1");
+
+        assert_eq!(
+            run_printing("try { assert false; } catch (e) { print e; }"),
+            "Assertion failed.\n"
+        );
+    }
+
+    /// Creates an empty directory for a test to write scripts into.
+    fn scratch_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("rlox-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_import() {
+        let dir = scratch_dir("import");
+        let write = |name: &str, source: &str| std::fs::write(dir.join(name), source).unwrap();
+        write(
+            "lib/utils.lox",
+            "import \"helper.lox\"; print \"loading\"; fun double(x) { return x * 2; }",
+        );
+        write("lib/helper.lox", "var helper = \"helper\";");
+
+        let buffer = SharedBuffer::default();
+        let mut vm = VM::with_output(buffer.clone());
+        vm.set_script_path(dir.join("main.lox"));
+        let chunk = crate::compiler::compile(
+            "import \"lib/utils.lox\"; import \"lib/utils.lox\";
+             print double(21); print helper;",
+            &mut vm,
+        )
+        .unwrap();
+        vm.interpret(chunk).unwrap();
+        assert_eq!(
+            String::from_utf8(buffer.0.take()).unwrap(),
+            "loading\n42\nhelper\n"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_import_errors() {
+        let dir = scratch_dir("import-errors");
+        let write = |name: &str, source: &str| std::fs::write(dir.join(name), source).unwrap();
+        write("main.lox", "import \"lib/a.lox\";");
+        write("lib/a.lox", "import \"b.lox\";");
+        write("lib/b.lox", "import \"a.lox\";");
+        write("lib/loop.lox", "import \"../main.lox\";");
+        write("lib/bad.lox", "var = 1;");
+        write("lib/fails.lox", "fun f() {\n  return nil + 1;\n}\nf();");
+
+        let run_main = |source: &str| {
+            let mut vm = VM::with_output(io::sink());
+            vm.set_script_path(dir.join("main.lox"));
+            let chunk = crate::compiler::compile(source, &mut vm).unwrap();
+            let error = vm.interpret(chunk).unwrap_err();
+            assert!(vm.imports.is_empty());
+            error
+        };
+
+        let error = run_main("import \"lib/a.lox\";");
+        assert_eq!(error.message, "Import cycle detected at 'a.lox'.");
+        assert_eq!(error.trace.len(), 3);
+        let error = run_main("import \"lib/loop.lox\";");
+        assert_eq!(error.message, "Import cycle detected at '../main.lox'.");
+
+        let error = run_main("import \"lib/missing.lox\";");
+        assert!(
+            error
+                .message
+                .starts_with("Could not import 'lib/missing.lox'")
+        );
+        let error = run_main("import \"lib/bad.lox\";");
+        assert!(
+            error
+                .message
+                .ends_with("[line 1] Error at '=': Expect variable name.")
+        );
+
+        let error = run_main("import \"lib/fails.lox\";");
+        let module = dir.join("lib/fails.lox").to_string_lossy().into_owned();
+        assert_eq!(error.trace[0].line, 2);
+        assert_eq!(error.trace[0].module, Some(module.clone()));
+        assert_eq!(error.trace[2].module, None);
+        assert_eq!(
+            error.trace[0].to_string(),
+            format!("[line 2] in f() ({})", module)
+        );
+
+        let error =
+            run_main("try { import \"lib/fails.lox\"; } catch (e) {} import \"lib/a.lox\";");
+        assert_eq!(error.message, "Import cycle detected at 'a.lox'.");
+
+        let mut vm = VM::with_output(io::sink());
+        let chunk = crate::compiler::compile("import \"x.lox\";", &mut vm).unwrap();
+        vm.set_sandboxed(true);
+        assert_eq!(
+            vm.interpret(chunk).unwrap_err().message,
+            "Feature disabled: 'import'."
+        );
+        let errors = crate::compiler::compile("import \"x.lox\";", &mut vm).unwrap_err();
+        assert_eq!(errors[0].message, "Feature disabled: 'import'.");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_gc_collects_cycles() {
+        let mut vm = VM::with_output(io::sink());
+        let collections = Rc::new(std::cell::Cell::new(0));
+        let counter = Rc::clone(&collections);
+        vm.on_collect(move |stats| counter.set(stats.collections));
+
+        let chunk = crate::compiler::compile(
+            "class Node { init() { this.next = nil; } }
+             var kept = [];
+             for (var i = 0; i < 100; i = i + 1) {
+               var list = [i]; push(list, list);
+               var map = {\"self\": nil}; map[\"self\"] = map;
+               var a = Node(); var b = Node(); a.next = b; b.next = a;
+               var bound = Node(); bound.method = bound.init;
+               if (i == 99) push(kept, a);
+             }
+             var cycle = [];
+             push(cycle, cycle);",
+            &mut vm,
+        )
+        .unwrap();
+        vm.interpret(chunk).unwrap();
+        assert!(vm.heap_stats().live_objects > 400);
+        let cycle = Rc::downgrade(vm.get_global("cycle").unwrap().as_list().unwrap());
+        vm.set_global("cycle", Value::nil());
+
+        vm.collect_garbage();
+        assert!(cycle.upgrade().is_none());
+        let stats = vm.heap_stats();
+        // `kept` and the two nodes in it
+        assert_eq!(stats.live_objects, 3);
+        assert_eq!(stats.bytes_since_gc, 0);
+        assert_eq!(collections.get(), stats.collections);
+        let kept = vm.get_global("kept").unwrap();
+        let node = kept.as_list().unwrap().borrow()[0].clone();
+        let next = node
+            .as_instance()
+            .unwrap()
+            .borrow()
+            .fields
+            .get(&LoxString::new("next"))
+            .cloned();
+        assert!(next.unwrap().as_instance().is_some());
+    }
+
+    #[test]
+    fn test_deep_data_is_dropped_without_recursing() {
+        // Freeing these a level at a time would overflow the stack
+        let (vm, result) = run("class Node { init(n) { this.next = n; } }
+             var nodes = nil; var list = nil; var map = nil;
+             for (var i = 0; i < 100000; i = i + 1) {
+               nodes = Node(nodes); list = [list]; map = {\"next\": map};
+             }
+             var mixed = nil;
+             for (var i = 0; i < 50000; i = i + 1) mixed = [Node({\"next\": mixed})];
+             nodes = nil;");
+        assert!(result.is_ok());
+        drop(vm);
+    }
+
+    #[test]
+    fn test_gc_stress() {
+        let source = "class Pair { init(a, b) { this.a = a; this.b = b; } sum() { return this.a + this.b; } }
+             var pairs = [];
+             for (var i = 0; i < 20; i = i + 1) {
+               var pair = Pair(i, [i, {\"i\": i}]);
+               var sum = pair.sum;
+               pair.b = pair.b[1][\"i\"];
+               push(pairs, sum);
+             }
+             var total = 0;
+             for (var i = 0; i < len(pairs); i = i + 1) total = total + pairs[i]();
+             print total;
+             print split(\"a,b\", \",\");";
+
+        let buffer = SharedBuffer::default();
+        let mut vm = VM::with_output(buffer.clone());
+        vm.set_gc_stress(true);
+        let chunk = crate::compiler::compile(source, &mut vm).unwrap();
+        vm.interpret(chunk).unwrap();
+        assert_eq!(String::from_utf8(buffer.0.take()).unwrap(), "380\n[a, b]\n");
+        assert!(vm.heap_stats().collections > 60);
+    }
+
+    #[test]
+    fn test_break_and_continue() {
+        let output = run_printing(
+            "for (var i = 0; i < 10; i = i + 1) {
+               var skip = i == 1;
+               if (skip) continue;
+               var stop = i == 4;
+               if (stop) break;
+               print i;
+             }
+             var n = 0;
+             while (true) {
+               n = n + 1;
+               var m = n;
+               if (m < 3) continue;
+               if (m > 5) break;
+               for (;;) { var inner = m; print inner; break; }
+             }
+             print n;",
+        );
+        assert_eq!(output, "0\n2\n3\n3\n4\n5\n6\n");
+    }
+
+    #[test]
+    fn test_break_and_continue_outside_loop() {
+        let mut vm = VM::new();
+        let errors = crate::compiler::compile("break; { continue; }", &mut vm).unwrap_err();
+        let messages: Vec<_> = errors.iter().map(|error| error.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "Can't use 'break' outside of a loop.",
+                "Can't use 'continue' outside of a loop."
+            ]
+        );
+
+        let errors =
+            crate::compiler::compile("while (true) { fun f() { break; } }", &mut vm).unwrap_err();
+        assert_eq!(errors[0].message, "Can't use 'break' outside of a loop.");
+    }
+
+    #[test]
+    fn test_logical_operators() {
+        let (vm, result) = run("var a = nil and 1; var b = 2 and \"yes\";
+             var c = false or 3; var d = 4 or 5;
+             var e = 1 < 2 and 2 < 3 or false;");
+
+        assert!(result.is_ok());
+        assert_eq!(global(&vm, "a"), Value::nil());
+        assert_eq!(global(&vm, "b"), Value::string("yes".to_string()));
+        assert_eq!(global(&vm, "c"), Value::number(3.0));
+        assert_eq!(global(&vm, "d"), Value::number(4.0));
+        assert_eq!(global(&vm, "e"), Value::bool(true));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn test_logical_operators_short_circuit() {
+        // The right operands would fail with "Undefined variable" if evaluated
+        let (vm, result) = run("var a = false and missing; var b = true or missing;");
+
+        assert!(result.is_ok());
+        assert_eq!(global(&vm, "a"), Value::bool(false));
+        assert_eq!(global(&vm, "b"), Value::bool(true));
+    }
+
+    #[test]
+    fn test_function_call() {
+        let (vm, result) = run("fun add(a, b) { return a + b; }
+             fun noop() {}
+             var sum = add(1, 2);
+             var nothing = noop();
+             var f = add;
+             var again = f(sum, 4);");
+
+        assert!(result.is_ok());
+        assert_eq!(global(&vm, "sum"), Value::number(3.0));
+        assert_eq!(global(&vm, "nothing"), Value::nil());
+        assert_eq!(global(&vm, "again"), Value::number(7.0));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn test_recursive_function() {
+        let (vm, result) = run(
+            "fun fib(n) { if (n < 2) return n; return fib(n - 2) + fib(n - 1); }
+             var result = fib(10);",
+        );
+
+        assert!(result.is_ok());
+        assert_eq!(global(&vm, "result"), Value::number(55.0));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn test_local_function() {
+        let (vm, result) = run("var result;
+             { var a = 1; fun twice(n) { return n * 2; } result = twice(a + 2); }");
+
+        assert!(result.is_ok());
+        assert_eq!(global(&vm, "result"), Value::number(6.0));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn test_call_errors() {
+        let (vm, result) = run("fun f(a) {} f(1, 2);");
+        assert!(result.is_err());
+        assert!(vm.stack.is_empty());
+        assert!(vm.frames.is_empty());
+
+        let (_, result) = run("var x = 1; x();");
+        assert!(result.is_err());
+
+        let (_, result) = run("fun f() { f(); } f();");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_return_from_top_level() {
+        let mut vm = VM::new();
+        assert!(crate::compiler::compile("return 1;", &mut vm).is_err());
+    }
+
+    #[test]
+    fn test_class_fields() {
+        let (vm, result) = run("class Point {}
+             var p = Point();
+             p.x = 1;
+             p.y = p.x + 1;
+             var sum = p.x + p.y;
+             var other = Point();
+             var same = p == p and p != other;");
+
+        assert!(result.is_ok());
+        assert_eq!(global(&vm, "sum"), Value::number(3.0));
+        assert_eq!(global(&vm, "same"), Value::bool(true));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn test_property_errors() {
+        for source in [
+            "class A {} var a = A(); print a.missing;",
+            "var n = 1; print n.field;",
+            "var n = 1; n.field = 2;",
+            "class A {} A(1);",
+        ] {
+            let (vm, result) = run(source);
+            assert!(result.is_err(), "{}", source);
+            assert!(vm.stack.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_invalid_assignment_target() {
+        let mut vm = VM::new();
+        assert!(crate::compiler::compile("var a; var b; a + b = 1;", &mut vm).is_err());
+        assert!(crate::compiler::compile("class A {} var a = A(); -a.x = 1;", &mut vm).is_err());
+    }
+
+    #[test]
+    fn test_methods_and_this() {
+        let (vm, result) = run("class Counter {
+               init(start) { this.count = start; }
+               add(n) { this.count = this.count + n; return this; }
+               get() { return this.count; }
+             }
+             var c = Counter(1);
+             var chained = c.add(2).add(3).get();
+             var bound = c.get;
+             c.add(4);
+             var later = bound();
+             var reinit = c.init(0) == c;");
+
+        assert!(result.is_ok());
+        assert_eq!(global(&vm, "chained"), Value::number(6.0));
+        assert_eq!(global(&vm, "later"), Value::number(10.0));
+        assert_eq!(global(&vm, "reinit"), Value::bool(true));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn test_field_shadows_method() {
+        let (vm, result) = run("fun twice(n) { return n * 2; }
+             class A { f(n) { return n; } }
+             var a = A();
+             var method = a.f(3);
+             a.f = twice;
+             var field = a.f(3);");
+
+        assert!(result.is_ok());
+        assert_eq!(global(&vm, "method"), Value::number(3.0));
+        assert_eq!(global(&vm, "field"), Value::number(6.0));
+    }
+
+    #[test]
+    fn test_method_errors() {
+        for source in [
+            "class A { init(a) {} } A();",
+            "class A {} A().missing();",
+            "var n = 1; n.method();",
+        ] {
+            let (vm, result) = run(source);
+            assert!(result.is_err(), "{}", source);
+            assert!(vm.stack.is_empty());
+        }
+
+        let mut vm = VM::new();
+        assert!(crate::compiler::compile("print this;", &mut vm).is_err());
+        assert!(crate::compiler::compile("fun f() { return this; }", &mut vm).is_err());
+        assert!(crate::compiler::compile("class A { init() { return 1; } }", &mut vm).is_err());
+        assert!(crate::compiler::compile("class A { init() { return; } }", &mut vm).is_ok());
+    }
+
+    #[test]
+    fn test_clock_native() {
+        let mut vm = VM::new();
+        vm.set_deterministic(Some(0));
+        let chunk = crate::compiler::compile("var a = 1; var t = clock();", &mut vm).unwrap();
+
+        assert!(vm.interpret(chunk).is_ok());
+        // Four instructions have run by the time clock() is called
+        assert_eq!(global(&vm, "t"), Value::number(4e-6));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn test_random_native() {
+        let source = "var a = random(); var b = random();";
+        let run = |seed| {
+            let mut vm = VM::new();
+            vm.set_deterministic(seed);
+            let chunk = crate::compiler::compile(source, &mut vm).unwrap();
+            assert!(vm.interpret(chunk).is_ok());
+            let (Value::Number(a), Value::Number(b)) = (global(&vm, "a"), global(&vm, "b")) else {
+                panic!("random() didn't return numbers");
+            };
+            assert!((0.0..1.0).contains(&a) && (0.0..1.0).contains(&b));
+            assert_ne!(a, b);
+            (a, b)
+        };
+
+        assert_eq!(run(Some(7)), run(Some(7)));
+        assert_ne!(run(Some(7)), run(Some(8)));
+    }
+
+    #[test]
+    fn test_native_errors() {
+        fn fail(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+            Err(format!("Failed with {} argument.", args.len()))
+        }
+
+        let mut vm = VM::new();
+        vm.define_native("fail", 1, fail);
+        let chunk = crate::compiler::compile("var x = 1 + fail(2);", &mut vm).unwrap();
+        assert!(vm.interpret(chunk).is_err());
+        assert!(vm.stack.is_empty());
+        assert!(vm.get_global("x").is_none());
+
+        let (vm, result) = run("clock(1);");
+        assert!(result.is_err());
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn test_embedding() {
+        let log = Rc::new(std::cell::RefCell::new(Vec::new()));
+        let mut vm = VM::new();
+        vm.set_global("limit", Value::number(3.0));
+        let sink = Rc::clone(&log);
+        vm.register_fn("host_log", move |args| {
+            let line: String = args.iter().map(|arg| format!("{:?}", arg)).collect();
+            sink.borrow_mut().push(line);
+            Ok(Value::nil())
+        });
+        vm.register_fn("host_fail", |_| Err(RloxError::new("Host failure.")));
+
+        let chunk = crate::compiler::compile(
+            "for (var i = 0; i < limit; i = i + 1) host_log(i, \"x\"); host_log();",
+            &mut vm,
+        )
+        .unwrap();
+        assert!(vm.interpret(chunk).is_ok());
+        assert_eq!(
+            *log.borrow(),
+            vec![
+                "Number(0.0)String(\"x\")",
+                "Number(1.0)String(\"x\")",
+                "Number(2.0)String(\"x\")",
+                "",
+            ]
+        );
+
+        let chunk = crate::compiler::compile("var a = host_fail();", &mut vm).unwrap();
+        assert!(vm.interpret(chunk).is_err());
+        assert_eq!(vm.get_global("a"), None);
+        assert_eq!(vm.get_global("limit"), Some(Value::number(3.0)));
+    }
+
+    #[test]
+    fn test_runtime_error_trace() {
+        let (_, result) = run("fun inner() { return -nil; }
+             fun outer() { inner(); }
+             outer();");
+
+        let error = result.unwrap_err();
+        assert_eq!(error.message, "Operand must be a number.");
+        assert_eq!(error.line(), Some(1));
+        assert_eq!(
+            error.to_string(),
+            "Operand must be a number.\n\
+             [line 1] in inner()\n\
+             [line 2] in outer()\n\
+             [line 3] in script"
+        );
+    }
+
+    #[test]
+    fn test_long_constants() {
+        let mut source = String::new();
+        for i in 0..300 {
+            source.push_str(&format!("print {};", i));
+        }
+        source.push_str("print 299 + 1;");
+
+        let output = run_printing(&source);
+        let expected: String = (0..=300).map(|i| format!("{}\n", i)).collect();
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn test_wide_locals() {
+        let mut source = String::from("fun f(a) {");
+        for i in 0..300 {
+            source.push_str(&format!("var l{} = a + {};", i, i));
+        }
+        source.push_str("l299 = l299 + l0; return l299 - l260; } print f(10);");
+
+        assert_eq!(run_printing(&source), "49\n");
+    }
+
+    #[test]
+    fn test_trace() {
+        let buffer = SharedBuffer::default();
+        let mut vm = VM::with_output(buffer.clone());
+        vm.set_trace(true);
+        let chunk = crate::compiler::compile(
+            "var a = 1; fun f(x) { return x + a; } a = f(2); print a;",
+            &mut vm,
+        )
+        .expect("Failed to compile");
+        vm.interpret(chunk).expect("Failed to run");
+
+        assert_eq!(global(&vm, "a"), Value::number(3.0));
+        let output = String::from_utf8(buffer.0.take()).unwrap();
+        assert!(output.contains("OP_ADD"));
+        assert!(output.contains("[ <fn f> ][ 2 ]"));
+        assert!(output.contains("OP_PRINT\n3\n"));
+    }
+
+    #[test]
+    fn test_print_output() {
+        let output = run_printing(
+            "print 1; print 2.5; print \"text\"; print true; print nil;
+             fun f() {} print f; print clock;
+             class A { m() {} } print A; print A(); print A().m;",
+        );
+
+        assert_eq!(
+            output,
+            "1\n2.5\ntext\ntrue\nnil\n<fn f>\n<native fn>\nA\nA instance\n<fn m>\n"
+        );
+    }
+
+    #[test]
+    fn test_superinstructions() {
+        let run_optimized = |source: &str, optimize: bool| {
+            let buffer = SharedBuffer::default();
+            let mut vm = VM::with_output(buffer.clone());
+            let options = CompileOptions {
+                optimize,
+                ..Default::default()
+            };
+            let chunk = crate::compiler::compile_with(source, &mut vm, options).unwrap();
+            let result = vm.interpret(chunk).map_err(|error| error.to_string());
+            (result, String::from_utf8(buffer.0.take()).unwrap())
+        };
+
+        for source in [
+            "fun f(a, b) { print a < b; print b < a; print a + 1; print a != b; } f(1, 2);",
+            "fun f(s) { print s + \"!\"; print s != \"a\"; } f(\"a\"); f(\"b\");",
+            "fun f(a, b) { return a < b; } print f(1, \"x\");",
+            "fun f(a) { return a + 1; } print f(\"x\");",
+            "fun f(a) { return a + \"x\"; } print f(nil);",
+        ] {
+            let (result, output) = run_optimized(source, true);
+            assert_eq!(
+                (result, output),
+                run_optimized(source, false),
+                "in: {}",
+                source
+            );
+        }
+
+        let (result, output) = run_optimized(
+            "fun f(a, b) { print a < b; print a + 1; print a != b; } f(1, 2);",
+            true,
+        );
+        assert!(result.is_ok());
+        assert_eq!(output, "true\n2\ntrue\n");
+    }
+
+    #[test]
+    fn test_print_numbers() {
+        let source = "print 3 * 2; print 0.1 + 0.2; print 100000000000 * 10000000000;
+                      print -1 / 100000000; print [2, 0.5];";
+
+        assert_eq!(run_printing(source), "6\n0.3\n1e+21\n-1e-08\n[2, 0.5]\n");
+    }
+
+    proptest! {
+        #[test]
+        fn test_stack_balanced_at_return(source in program_gen::program()) {
+            let mut vm = VM::with_output(io::sink());
+            let chunk = crate::compiler::compile(&source, &mut vm).unwrap();
+
+            let result = vm.interpret(chunk);
+
+            prop_assert!(result.is_ok(), "failed to run:\n{}", source);
+            prop_assert!(vm.stack.is_empty(), "{} values left on the stack by:\n{}", vm.stack.len(), source);
+        }
+
+        #[test]
+        fn test_optimizations_preserve_output(source in program_gen::program()) {
+            let mut outputs = Vec::new();
+            for optimize in [false, true] {
+                let buffer = SharedBuffer::default();
+                let mut vm = VM::with_output(buffer.clone());
+                let options = CompileOptions { optimize, ..Default::default() };
+                let chunk = crate::compiler::compile_with(&source, &mut vm, options).unwrap();
+                let result = vm.interpret(chunk).map_err(|error| error.to_string());
+                outputs.push((result, String::from_utf8(buffer.0.take()).unwrap()));
+            }
+
+            prop_assert_eq!(&outputs[0], &outputs[1], "in:\n{}", source);
+        }
     }
 }
@@ -0,0 +1,176 @@
+//! Exercises rlox through its public library API, the way an embedding
+//! application would.
+
+use rlox::{CompileError, ErrorKind, Limit, LoadError, Location, RloxError, Value, Vm, VmOptions};
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+#[test]
+fn interpret_reports_failures() {
+    assert!(rlox::interpret("var a = 1; a = a + 1;").is_ok());
+    assert!(rlox::interpret("var a = ;").is_err());
+    assert!(rlox::interpret("nil();").is_err());
+}
+
+#[test]
+fn host_functions_and_globals() {
+    let calls = Rc::new(RefCell::new(Vec::new()));
+    let mut vm = Vm::new();
+    vm.set_global("base", Value::number(40.0));
+    let recorded = Rc::clone(&calls);
+    vm.register_fn("record", move |args| match args {
+        [Value::Number(n)] => {
+            recorded.borrow_mut().push(*n);
+            Ok(Value::number(n + 2.0))
+        }
+        _ => Err(RloxError::new("record() takes a number.")),
+    });
+
+    let chunk = rlox::compile("var answer = record(base);", &mut vm).unwrap();
+    assert!(vm.interpret(chunk).is_ok());
+    assert_eq!(vm.get_global("answer"), Some(Value::number(42.0)));
+    assert_eq!(*calls.borrow(), vec![40.0]);
+
+    let chunk = rlox::compile("record(\"not a number\");", &mut vm).unwrap();
+    assert!(vm.interpret(chunk).is_err());
+}
+
+#[test]
+fn errors_are_structured() {
+    let error = rlox::interpret("print 1\nvar = 2;").unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::Compile);
+    let RloxError::Compile(errors) = &error else {
+        panic!("expected compile errors");
+    };
+    assert_eq!(
+        errors,
+        &vec![
+            CompileError {
+                line: 2,
+                end_line: 2,
+                column: 1,
+                location: Location::Token("var".to_string()),
+                message: "Expect ';' after value.".to_string(),
+            },
+            CompileError {
+                line: 2,
+                end_line: 2,
+                column: 5,
+                location: Location::Token("=".to_string()),
+                message: "Expect variable name.".to_string(),
+            },
+        ]
+    );
+    assert_eq!(
+        error.to_string(),
+        "[line 2] Error at 'var': Expect ';' after value.\n\
+         [line 2] Error at '=': Expect variable name."
+    );
+
+    let error = rlox::interpret("var a = 1;\na();").unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::Runtime);
+    assert_eq!(error.line(), Some(2));
+    assert_eq!(error.message(), "Can only call functions and classes.");
+}
+
+#[test]
+fn limits_stop_untrusted_scripts() {
+    let mut vm = VmOptions::new()
+        .max_call_depth(64)
+        .instruction_budget(10_000)
+        .build();
+    let chunk = rlox::compile("while (true) {}", &mut vm).unwrap();
+    let error = RloxError::from(vm.interpret(chunk).unwrap_err());
+    assert_eq!(error.kind(), ErrorKind::Limit);
+    let RloxError::Runtime(error) = error else {
+        unreachable!()
+    };
+    assert_eq!(error.limit, Some(Limit::Instructions));
+
+    // The VM is still usable afterwards
+    let chunk = rlox::compile("var done = true;", &mut vm).unwrap();
+    assert!(vm.interpret(chunk).is_ok());
+    assert_eq!(vm.get_global("done"), Some(Value::bool(true)));
+}
+
+#[test]
+fn errors_render_source_excerpts() {
+    let source = "print 1\nvar = 2;";
+    let error = rlox::interpret(source).unwrap_err();
+    assert_eq!(
+        error.render(source),
+        "[line 2] Error at 'var': Expect ';' after value.\n\
+         2 | var = 2;\n\
+         \x20 | ^^^\n\
+         [line 2] Error at '=': Expect variable name.\n\
+         2 | var = 2;\n\
+         \x20 |     ^"
+    );
+
+    let source = "var a = 1;\nprint a + \"x\";";
+    let error = rlox::interpret(source).unwrap_err();
+    assert_eq!(
+        error.render(source),
+        "Operands must be two numbers or two strings.\n\
+         2 | print a + \"x\";\n\
+         \x20 |         ^\n\
+         [line 2] in script"
+    );
+
+    // Columns count characters, so the caret lines up after non-ASCII text
+    let source = "var s = \"é\" + nïl;";
+    let error = rlox::interpret(source).unwrap_err();
+    assert_eq!(
+        error.render(source),
+        "Undefined variable 'nïl'.\n\
+         1 | var s = \"é\" + nïl;\n\
+         \x20 |               ^\n\
+         [line 1] in script"
+    );
+}
+
+#[test]
+fn output_can_be_captured() {
+    #[derive(Clone, Default)]
+    struct Capture(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let capture = Capture::default();
+    let mut vm = Vm::with_output(capture.clone());
+    let chunk = rlox::compile("for (var i = 0; i < 3; i = i + 1) print i;", &mut vm).unwrap();
+    vm.interpret(chunk).unwrap();
+
+    assert_eq!(*capture.0.borrow(), b"0\n1\n2\n");
+}
+
+#[test]
+fn bytecode_can_be_loaded_into_another_vm() {
+    let mut vm = Vm::new();
+    let chunk = rlox::compile(
+        "fun twice(x) { return x + x; } var s = twice(\"ab\");",
+        &mut vm,
+    )
+    .unwrap();
+    let bytes = chunk.serialize();
+
+    let mut vm = Vm::with_output(io::sink());
+    let chunk = vm.load_chunk(&bytes).unwrap();
+    vm.interpret(chunk).unwrap();
+    assert_eq!(vm.get_global("s"), Some(Value::string("abab".to_string())));
+
+    assert!(matches!(
+        vm.load_chunk(b"#!/usr/bin/env rlox"),
+        Err(LoadError::BadMagic)
+    ));
+}
@@ -0,0 +1,179 @@
+//! Runs the official Crafting Interpreters test corpus against rlox in
+//! `--compat` mode. The corpus is not vendored; point `CLOX_TEST_DIR` at the
+//! `test/` directory of a craftinginterpreters checkout and run:
+//!
+//!     CLOX_TEST_DIR=../craftinginterpreters/test cargo test -- --ignored conformance
+//!
+//! Set `CLOX_TEST_FILTER` to a path fragment (e.g. `for/`) to only run part of
+//! the suite, such as the tests for the chapters implemented so far, and set
+//! `RLOX_GC_STRESS` to collect garbage on every allocation.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// Directories that exercise jlox-only behaviour or are not tests at all.
+const SKIPPED_DIRS: [&str; 3] = ["benchmark", "expressions", "scanning"];
+
+#[derive(Default)]
+struct Expectations {
+    output: Vec<String>,
+    compile_errors: Vec<String>,
+    runtime_error: Option<(String, usize)>,
+}
+
+impl Expectations {
+    fn parse(source: &str) -> Option<Expectations> {
+        let mut expectations = Expectations::default();
+
+        for (index, line) in source.lines().enumerate() {
+            let line_number = index + 1;
+
+            if line.contains("// nontest") {
+                return None;
+            }
+
+            if let Some((_, output)) = line.split_once("// expect: ") {
+                expectations.output.push(output.to_string());
+            } else if let Some((_, message)) = line.split_once("// expect runtime error: ") {
+                expectations.runtime_error = Some((message.to_string(), line_number));
+            } else if let Some((_, error)) = line.split_once("// Error") {
+                expectations
+                    .compile_errors
+                    .push(format!("[line {}] Error{}", line_number, error));
+            } else if let Some((_, error)) = line.split_once("// [")
+                && let Some(error) = error
+                    .strip_prefix("line ")
+                    .or_else(|| error.strip_prefix("c line "))
+            {
+                expectations.compile_errors.push(format!("[line {}", error));
+            }
+        }
+
+        Some(expectations)
+    }
+
+    fn exit_code(&self) -> i32 {
+        if !self.compile_errors.is_empty() {
+            65
+        } else if self.runtime_error.is_some() {
+            70
+        } else {
+            0
+        }
+    }
+}
+
+fn check(path: &Path, expectations: &Expectations) -> Result<(), String> {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_rlox"));
+    command.args(["--compat", "--no-cache"]);
+    if env::var_os("RLOX_GC_STRESS").is_some() {
+        command.arg("--gc-stress");
+    }
+    let output = command
+        .arg(path)
+        .output()
+        .map_err(|error| format!("failed to run rlox: {}", error))?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stderr: Vec<&str> = stderr.lines().collect();
+
+    if let Some((message, line)) = &expectations.runtime_error {
+        let trace = format!("[line {}]", line);
+        if stderr.first() != Some(&message.as_str())
+            || !stderr.get(1).is_some_and(|l| l.starts_with(&trace))
+        {
+            return Err(format!(
+                "expected runtime error '{}' at line {}, got {:?}",
+                message, line, stderr
+            ));
+        }
+    } else if stderr != expectations.compile_errors {
+        return Err(format!(
+            "expected errors {:?}, got {:?}",
+            expectations.compile_errors, stderr
+        ));
+    }
+
+    let actual: Vec<&str> = stdout.lines().collect();
+    if actual != expectations.output {
+        return Err(format!(
+            "expected output {:?}, got {:?}",
+            expectations.output, actual
+        ));
+    }
+
+    let exit_code = output.status.code().unwrap_or(-1);
+    if exit_code != expectations.exit_code() {
+        return Err(format!(
+            "expected exit code {}, got {}",
+            expectations.exit_code(),
+            exit_code
+        ));
+    }
+
+    Ok(())
+}
+
+fn collect_tests(dir: &Path, tests: &mut Vec<PathBuf>) {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)
+        .expect("Failed to read test directory")
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    entries.sort();
+
+    for path in entries {
+        if path.is_dir() {
+            let name = path.file_name().unwrap().to_string_lossy();
+            if !SKIPPED_DIRS.contains(&name.as_ref()) {
+                collect_tests(&path, tests);
+            }
+        } else if path.extension().is_some_and(|ext| ext == "lox") {
+            tests.push(path);
+        }
+    }
+}
+
+#[test]
+#[ignore]
+fn conformance() {
+    let Ok(root) = std::env::var("CLOX_TEST_DIR") else {
+        panic!("Set CLOX_TEST_DIR to the craftinginterpreters test directory.");
+    };
+    let filter = std::env::var("CLOX_TEST_FILTER").unwrap_or_default();
+
+    let mut tests = Vec::new();
+    collect_tests(Path::new(&root), &mut tests);
+
+    let mut passed = 0;
+    let mut failures = Vec::new();
+    for path in tests {
+        let relative = path
+            .strip_prefix(&root)
+            .unwrap()
+            .to_string_lossy()
+            .into_owned();
+        if !relative.contains(&filter) {
+            continue;
+        }
+
+        let source = fs::read_to_string(&path).unwrap();
+        let Some(expectations) = Expectations::parse(&source) else {
+            continue;
+        };
+
+        match check(&path, &expectations) {
+            Ok(()) => passed += 1,
+            Err(message) => failures.push(format!("{}: {}", relative, message)),
+        }
+    }
+
+    for failure in failures.iter() {
+        eprintln!("FAIL {}", failure);
+    }
+    eprintln!("{} passed, {} failed", passed, failures.len());
+
+    assert!(failures.is_empty());
+}
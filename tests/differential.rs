@@ -0,0 +1,64 @@
+//! Differential testing against a reference Lox implementation. Random
+//! programs are run through both rlox and the interpreter named by
+//! `LOX_REFERENCE` (e.g. a jlox launcher script or a clox binary), and their
+//! output, first diagnostic line and exit code are compared:
+//!
+//!     LOX_REFERENCE=../craftinginterpreters/jlox cargo test -- --ignored differential
+//!
+//! `LOX_FUZZ_SEED` and `LOX_FUZZ_ITERATIONS` control the generated programs.
+//! Set `LOX_REFERENCE_COMPAT=1` when the reference is clox so rlox runs in
+//! `--compat` mode. Mismatching programs are written to the temp directory.
+
+#[path = "differential/harness.rs"]
+mod harness;
+
+use harness::{Generator, Rng};
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+#[ignore]
+fn differential() {
+    let Ok(reference) = env::var("LOX_REFERENCE") else {
+        panic!("Set LOX_REFERENCE to the reference interpreter to compare against.");
+    };
+    let compat = env::var("LOX_REFERENCE_COMPAT").is_ok();
+    let seed: u64 = env::var("LOX_FUZZ_SEED").map_or(0x5eed, |s| s.parse().unwrap());
+    let iterations: u64 = env::var("LOX_FUZZ_ITERATIONS").map_or(200, |s| s.parse().unwrap());
+
+    let dir = env::temp_dir().join("rlox-differential");
+    fs::create_dir_all(&dir).unwrap();
+
+    let mut mismatches = 0;
+    for iteration in 0..iterations {
+        let rng = Rng::new(seed ^ (iteration + 1).wrapping_mul(0x9e3779b97f4a7c15));
+        let source = Generator::new(rng).program();
+        let path = dir.join(format!("case-{}.lox", iteration));
+        fs::write(&path, &source).unwrap();
+
+        let mut rlox = harness::rlox_command(Path::new(env!("CARGO_BIN_EXE_rlox")), compat);
+        let actual = harness::run(&mut rlox, &path);
+        let expected = harness::run(&mut Command::new(&reference), &path);
+
+        if actual == expected {
+            fs::remove_file(&path).unwrap();
+            continue;
+        }
+
+        mismatches += 1;
+        eprintln!("MISMATCH {}", path.display());
+        eprintln!(
+            "  rlox:      {:?} {:?}",
+            actual.diagnostic, actual.exit_code
+        );
+        eprintln!(
+            "  reference: {:?} {:?}",
+            expected.diagnostic, expected.exit_code
+        );
+    }
+
+    eprintln!("{} of {} programs differed", mismatches, iterations);
+    assert_eq!(mismatches, 0);
+}
@@ -0,0 +1,166 @@
+//! Program generation and comparison shared by the differential test and the
+//! `differential` fuzz target.
+
+use std::path::Path;
+use std::process::Command;
+
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // xorshift never leaves zero
+        Rng(seed.max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        // xorshift64
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+/// Generates random well-formed programs: globals, blocks, if/else and
+/// mixed-type expressions.
+pub struct Generator {
+    rng: Rng,
+    globals: Vec<String>,
+}
+
+impl Generator {
+    pub fn new(rng: Rng) -> Self {
+        Generator {
+            rng,
+            globals: Vec::new(),
+        }
+    }
+
+    pub fn program(&mut self) -> String {
+        let mut source = String::new();
+        for _ in 0..1 + self.rng.below(12) {
+            self.statement(&mut source, 0);
+        }
+        source
+    }
+
+    fn statement(&mut self, out: &mut String, depth: usize) {
+        let choice = if depth > 2 {
+            self.rng.below(2)
+        } else {
+            self.rng.below(5)
+        };
+
+        match choice {
+            0 => {
+                let expr = self.expression(0);
+                out.push_str(&format!("print {};\n", expr));
+            }
+            1 if depth == 0 => {
+                let name = format!("v{}", self.globals.len());
+                let expr = self.expression(0);
+                out.push_str(&format!("var {} = {};\n", name, expr));
+                self.globals.push(name);
+            }
+            1 if !self.globals.is_empty() => {
+                let name = self.globals[self.rng.below(self.globals.len())].clone();
+                let expr = self.expression(0);
+                out.push_str(&format!("{} = {};\n", name, expr));
+            }
+            2 => {
+                let condition = self.expression(0);
+                out.push_str(&format!("if ({}) ", condition));
+                self.statement(out, depth + 1);
+                if self.rng.below(2) == 0 {
+                    out.push_str("else ");
+                    self.statement(out, depth + 1);
+                }
+            }
+            3 => {
+                out.push_str("{\n");
+                for _ in 0..self.rng.below(4) {
+                    self.statement(out, depth + 1);
+                }
+                out.push_str("}\n");
+            }
+            _ => {
+                let expr = self.expression(0);
+                out.push_str(&format!("{};\n", expr));
+            }
+        }
+    }
+
+    fn expression(&mut self, depth: usize) -> String {
+        if depth > 3 || self.rng.below(3) == 0 {
+            return self.primary();
+        }
+
+        const BINARY: [&str; 13] = [
+            "+", "-", "*", "/", "==", "!=", "<", "<=", ">", ">=", "and", "or", "+",
+        ];
+        match self.rng.below(4) {
+            0 => format!("({})", self.expression(depth + 1)),
+            1 => {
+                let operator = if self.rng.below(2) == 0 { "-" } else { "!" };
+                format!("{}{}", operator, self.expression(depth + 1))
+            }
+            _ => {
+                let operator = BINARY[self.rng.below(BINARY.len())];
+                let left = self.expression(depth + 1);
+                let right = self.expression(depth + 1);
+                format!("{} {} {}", left, operator, right)
+            }
+        }
+    }
+
+    fn primary(&mut self) -> String {
+        match self.rng.below(6) {
+            0 => format!("{}", self.rng.below(100)),
+            1 => format!("{}.{}", self.rng.below(10), self.rng.below(100)),
+            2 => format!("\"s{}\"", self.rng.below(5)),
+            3 => ["true", "false", "nil"][self.rng.below(3)].to_string(),
+            _ if !self.globals.is_empty() => {
+                self.globals[self.rng.below(self.globals.len())].clone()
+            }
+            _ => format!("{}", self.rng.below(10)),
+        }
+    }
+}
+
+/// What running a script printed and how it exited.
+#[derive(Debug, PartialEq)]
+pub struct Outcome {
+    pub stdout: String,
+    /// The first line written to stderr
+    pub diagnostic: String,
+    pub exit_code: Option<i32>,
+}
+
+/// A command running the rlox binary at `rlox`, in `--compat` mode when the
+/// reference is clox.
+pub fn rlox_command(rlox: &Path, compat: bool) -> Command {
+    let mut command = Command::new(rlox);
+    command.arg("--no-cache");
+    if compat {
+        command.arg("--compat");
+    }
+    command
+}
+
+pub fn run(command: &mut Command, path: &Path) -> Outcome {
+    let output = command
+        .arg(path)
+        .output()
+        .expect("Failed to run interpreter");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    Outcome {
+        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        diagnostic: stderr.lines().next().unwrap_or("").to_string(),
+        exit_code: output.status.code(),
+    }
+}
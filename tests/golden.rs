@@ -0,0 +1,201 @@
+//! Runs every script under `tests/lox/` and compares what it prints, and the
+//! errors it reports, with the expectations written in its comments, in the
+//! format of the Crafting Interpreters suite:
+//!
+//! - `// expect: text` for each line the script prints, in order;
+//! - `// expect runtime error: message` on the line the error happens;
+//! - `// Error at 'x': message` on the line of a compile error, or
+//!   `// [line N] Error ...` for one reported on another line.
+//!
+//! Files containing `// nontest` (such as modules the tests import) are
+//! skipped. Set `LOX_GOLDEN_FILTER` to a path fragment to only run some of
+//! the scripts.
+
+use rlox::{RloxError, Vm};
+use std::cell::RefCell;
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+#[derive(Default)]
+struct Expectations {
+    output: Vec<String>,
+    compile_errors: Vec<String>,
+    runtime_error: Option<(String, usize)>,
+}
+
+impl Expectations {
+    fn parse(source: &str) -> Option<Expectations> {
+        let mut expectations = Expectations::default();
+
+        for (index, line) in source.lines().enumerate() {
+            let line_number = index + 1;
+
+            if line.contains("// nontest") {
+                return None;
+            }
+
+            if let Some((_, output)) = line.split_once("// expect: ") {
+                expectations.output.push(output.to_string());
+            } else if let Some((_, message)) = line.split_once("// expect runtime error: ") {
+                expectations.runtime_error = Some((message.to_string(), line_number));
+            } else if let Some((_, error)) = line.split_once("// Error") {
+                expectations
+                    .compile_errors
+                    .push(format!("[line {}] Error{}", line_number, error));
+            } else if let Some((_, error)) = line.split_once("// [line ") {
+                expectations.compile_errors.push(format!("[line {}", error));
+            }
+        }
+
+        Some(expectations)
+    }
+
+    /// What the script should write to stderr: its compile errors, or the
+    /// runtime error's message followed by the innermost frame of its trace.
+    fn errors(&self) -> Vec<String> {
+        match &self.runtime_error {
+            Some((message, line)) => vec![message.clone(), format!("[line {}]", line)],
+            None => self.compile_errors.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+struct Capture(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs the script at `path`, returning what it printed and the lines of the
+/// error it stopped with, if any, in the form [`Expectations::errors`] uses.
+fn run(path: &Path, source: &str) -> (Vec<String>, Vec<String>) {
+    let capture = Capture::default();
+    let mut vm = Vm::with_output(capture.clone());
+    vm.set_script_path(path);
+
+    let errors = match rlox::compile(source, &mut vm) {
+        Ok(chunk) => match vm.interpret(chunk) {
+            Ok(()) => Vec::new(),
+            Err(error) => {
+                let error = RloxError::from(error);
+                vec![
+                    error.message().to_string(),
+                    format!("[line {}]", error.line().unwrap_or(0)),
+                ]
+            }
+        },
+        Err(errors) => errors.iter().map(ToString::to_string).collect(),
+    };
+
+    let output = String::from_utf8_lossy(&capture.0.borrow())
+        .lines()
+        .map(str::to_string)
+        .collect();
+    (output, errors)
+}
+
+/// Lists expected and actual lines side by side, marking the ones that differ
+/// with `-` (expected) and `+` (actual).
+fn diff(expected: &[String], actual: &[String]) -> String {
+    let mut lines = Vec::new();
+    for i in 0..expected.len().max(actual.len()) {
+        match (expected.get(i), actual.get(i)) {
+            (Some(e), Some(a)) if e == a => lines.push(format!("    {}", e)),
+            (e, a) => {
+                if let Some(e) = e {
+                    lines.push(format!("  - {}", e));
+                }
+                if let Some(a) = a {
+                    lines.push(format!("  + {}", a));
+                }
+            }
+        }
+    }
+    lines.join("\n")
+}
+
+fn check(path: &Path, source: &str, expectations: &Expectations) -> Result<(), String> {
+    let (output, errors) = run(path, source);
+
+    let mut report = Vec::new();
+    if output != expectations.output {
+        report.push(format!("output:\n{}", diff(&expectations.output, &output)));
+    }
+    let expected_errors = expectations.errors();
+    if errors != expected_errors {
+        report.push(format!("errors:\n{}", diff(&expected_errors, &errors)));
+    }
+
+    if report.is_empty() {
+        Ok(())
+    } else {
+        Err(report.join("\n"))
+    }
+}
+
+fn collect_tests(dir: &Path, tests: &mut Vec<PathBuf>) {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)
+        .expect("Failed to read test directory")
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    entries.sort();
+
+    for path in entries {
+        if path.is_dir() {
+            collect_tests(&path, tests);
+        } else if path.extension().is_some_and(|ext| ext == "lox") {
+            tests.push(path);
+        }
+    }
+}
+
+#[test]
+fn golden() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lox");
+    let filter = env::var("LOX_GOLDEN_FILTER").unwrap_or_default();
+
+    let mut tests = Vec::new();
+    collect_tests(&root, &mut tests);
+
+    let mut passed = 0;
+    let mut failures = Vec::new();
+    for path in tests {
+        let relative = path
+            .strip_prefix(&root)
+            .unwrap()
+            .to_string_lossy()
+            .into_owned();
+        if !relative.contains(&filter) {
+            continue;
+        }
+
+        let source = fs::read_to_string(&path).unwrap();
+        let Some(expectations) = Expectations::parse(&source) else {
+            continue;
+        };
+
+        match check(&path, &source, &expectations) {
+            Ok(()) => passed += 1,
+            Err(report) => failures.push(format!("{}\n{}", relative, report)),
+        }
+    }
+
+    for failure in failures.iter() {
+        eprintln!("FAIL {}", failure);
+    }
+    eprintln!("{} passed, {} failed", passed, failures.len());
+
+    assert!(passed > 0 || !filter.is_empty(), "no scripts in {:?}", root);
+    assert!(failures.is_empty());
+}
@@ -0,0 +1,58 @@
+//! Saves a REPL session with `--session` and resumes it, the way a user
+//! picking up where they left off would.
+
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+
+fn repl(session: &Path, input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .arg("--session")
+        .arg(session)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to run rlox");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn sessions_resume_where_they_left_off() {
+    let dir = std::env::temp_dir().join(format!("rlox-test-session-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let session = dir.join("session.lox");
+    let module = dir.join("lib.lox");
+    let written = dir.join("written.txt");
+    fs::write(&module, "fun helper() { return \"helped\"; }\n").unwrap();
+
+    let output = repl(
+        &session,
+        &format!(
+            "fun readLine() {{ return \"mine\"; }}\n\
+             import \"{}\";\n\
+             writeFile(\"{}\", \"once\");\n",
+            module.display(),
+            written.display()
+        ),
+    );
+    assert!(output.status.success());
+    fs::remove_file(&written).unwrap();
+
+    // The replay brings back the function and the module, doesn't write the
+    // file again, and leaves the user's readLine in place of the native
+    let output = repl(&session, "print readLine();\nprint helper();\n");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Restored 3 of 3 inputs"), "{}", stderr);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "mine\nhelped\n");
+    assert!(!written.exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
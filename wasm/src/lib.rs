@@ -0,0 +1,64 @@
+//! JavaScript bindings for running Lox in the browser. Build them with
+//! [wasm-pack](https://rustwasm.github.io/wasm-pack/):
+//!
+//!     wasm-pack build --target web
+//!
+//! and call `interpret(source)` from the generated module.
+
+use rlox::{Feature, RloxError, VmOptions};
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+
+// Enough for any tutorial exercise, while an infinite loop still ends
+// instead of freezing the page
+const INSTRUCTION_BUDGET: u64 = 100_000_000;
+
+/// What a script printed, and the errors it stopped with, each with an
+/// excerpt of the source.
+#[wasm_bindgen(getter_with_clone)]
+pub struct Outcome {
+    pub output: String,
+    pub errors: Vec<String>,
+}
+
+#[derive(Clone, Default)]
+struct Capture(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Compiles and runs `source` on a fresh VM without I/O.
+#[wasm_bindgen]
+pub fn interpret(source: &str) -> Outcome {
+    let capture = Capture::default();
+    let mut vm = VmOptions::new()
+        .instruction_budget(INSTRUCTION_BUDGET)
+        .disable(Feature::Io)
+        .build();
+    vm.set_output(capture.clone());
+
+    let result = match rlox::compile(source, &mut vm) {
+        Ok(chunk) => vm.interpret(chunk).map_err(RloxError::from),
+        Err(errors) => Err(RloxError::Compile(errors)),
+    };
+    let errors = match result {
+        Ok(()) => Vec::new(),
+        Err(RloxError::Compile(errors)) => {
+            errors.iter().map(|error| error.render(source)).collect()
+        }
+        Err(error) => vec![error.render(source)],
+    };
+
+    let output = String::from_utf8_lossy(&capture.0.borrow()).into_owned();
+    Outcome { output, errors }
+}